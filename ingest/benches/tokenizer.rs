@@ -0,0 +1,109 @@
+//! Trigram extraction throughput, `cargo bench -p ingest`
+//!
+//! Compares the analyzer path (an allocated `Token` per trigram, lowercased
+//! by a filter) with byte trigrams packed in a `u32`, alone & building the
+//! postings of a block.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use ingest::analyzer::Analyzer;
+use ingest::postings::PostingsBuilder;
+use ingest::tokenizer::{ByteTrigrams, NGramTokenizer, Tokenizer};
+
+/// apache like access log lines, ~1MB
+fn lines() -> Vec<String> {
+    let paths = [
+        "/index.html",
+        "/api/v1/Users",
+        "/login?next=/Admin",
+        "/static/app.js",
+    ];
+    let agents = [
+        "curl/7.68.0",
+        "Mozilla/5.0 (X11; Linux x86_64)",
+        "Go-http-client/1.1",
+    ];
+    (0..8000)
+        .map(|i| {
+            format!(
+                "10.0.{}.{} - - [10/Oct/2020:13:55:{:02} -0700] \"GET {} HTTP/1.1\" {} {} \"{}\"",
+                i % 256,
+                i % 97,
+                i % 60,
+                paths[i % paths.len()],
+                [200, 404, 500][i % 3],
+                i * 31 % 10000,
+                agents[i % agents.len()]
+            )
+        })
+        .collect()
+}
+
+fn trigrams(c: &mut Criterion) {
+    let lines = lines();
+    let bytes = lines.iter().map(|l| l.len() as u64).sum();
+    let mut group = c.benchmark_group("trigrams");
+    group.throughput(Throughput::Bytes(bytes));
+
+    group.bench_function("ngram_tokenizer", |b| {
+        let tokenizer = NGramTokenizer::new(3).unwrap();
+        b.iter(|| {
+            for line in &lines {
+                for token in tokenizer.tokenize(line) {
+                    black_box(token);
+                }
+            }
+        })
+    });
+    group.bench_function("analyzer", |b| {
+        let analyzer = Analyzer::trigram();
+        b.iter(|| {
+            for line in &lines {
+                analyzer.analyze(line, |token| {
+                    black_box(token);
+                });
+            }
+        })
+    });
+    group.bench_function("byte_trigrams", |b| {
+        b.iter(|| {
+            for line in &lines {
+                for gram in ByteTrigrams::new(line.as_bytes()) {
+                    black_box(gram);
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+fn postings(c: &mut Criterion) {
+    let lines = lines();
+    let bytes = lines.iter().map(|l| l.len() as u64).sum();
+    let mut group = c.benchmark_group("postings");
+    group.throughput(Throughput::Bytes(bytes));
+    group.sample_size(20);
+
+    group.bench_function("analyzer", |b| {
+        let analyzer = Analyzer::trigram();
+        b.iter(|| {
+            let mut postings = PostingsBuilder::new();
+            for (row, line) in lines.iter().enumerate() {
+                analyzer.analyze(line, |token| postings.insert(token, row as u32));
+            }
+            postings.build(Vec::new(), Vec::new()).unwrap()
+        })
+    });
+    group.bench_function("ascii_trigrams", |b| {
+        b.iter(|| {
+            let mut postings = PostingsBuilder::new();
+            for (row, line) in lines.iter().enumerate() {
+                postings.insert_ascii_trigrams(line, row as u32);
+            }
+            postings.build(Vec::new(), Vec::new()).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, trigrams, postings);
+criterion_main!(benches);
@@ -0,0 +1,190 @@
+//! Analyzers, a tokenizer & a chain of token filters
+//!
+//! Indexed columns pick an analyzer by name in their schema
+//! (`Schema::index_with`), the same analyzer has to be used to look terms up.
+//! Built in ones:
+//!
+//! - `trigram`: lowercased 3-grams, what regex & substring search need
+//! - `word`: lowercased words
+//! - `edge_ngram`: lowercased word prefixes of 2 to 10 chars
+//! - `path`: url & file path segments
+//! - `number`: ip addresses & numbers
+
+use crate::tokenizer::{
+    EdgeNGramTokenizer, NGramTokenizer, NumberTokenizer, PathTokenizer, Token, Tokenizer,
+    WordTokenizer,
+};
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+
+pub const TRIGRAM: &str = store::schema::DEFAULT_ANALYZER;
+pub const WORD: &str = "word";
+pub const EDGE_NGRAM: &str = "edge_ngram";
+pub const PATH: &str = "path";
+pub const NUMBER: &str = "number";
+
+/// Object safe `Tokenizer`, so analyzers can pick one at runtime
+pub trait TokenStream: Send + Sync {
+    fn for_each_token(&self, data: &str, f: &mut dyn FnMut(Token));
+}
+
+impl<T> TokenStream for T
+where
+    T: for<'a> Tokenizer<'a> + Send + Sync,
+{
+    fn for_each_token(&self, data: &str, f: &mut dyn FnMut(Token)) {
+        for token in self.tokenize(data) {
+            f(token)
+        }
+    }
+}
+
+/// Changes or drops (`None`) a token
+pub trait TokenFilter: Send + Sync {
+    fn filter(&self, token: Token) -> Option<Token>;
+}
+
+pub struct LowerCaser;
+
+impl TokenFilter for LowerCaser {
+    fn filter(&self, token: Token) -> Option<Token> {
+        if token.to_str().chars().any(char::is_uppercase) {
+            Some(Token::from(token.to_str().to_lowercase().as_str()))
+        } else {
+            Some(token)
+        }
+    }
+}
+
+/// Drops tokens in a list of words
+pub struct StopWords {
+    words: HashSet<String>,
+}
+
+impl StopWords {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(words: I) -> Self {
+        Self {
+            words: words.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn english() -> Self {
+        Self::new(vec![
+            "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "in", "is", "it", "of",
+            "on", "or", "the", "to", "was", "with",
+        ])
+    }
+}
+
+impl TokenFilter for StopWords {
+    fn filter(&self, token: Token) -> Option<Token> {
+        if self.words.contains(token.to_str()) {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
+
+/// Drops tokens longer than some chars, e.g. hashes & base64 blobs
+pub struct MaxLength(pub usize);
+
+impl TokenFilter for MaxLength {
+    fn filter(&self, token: Token) -> Option<Token> {
+        if token.to_str().chars().count() > self.0 {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
+
+pub struct Analyzer {
+    tokenizer: Box<dyn TokenStream>,
+    filters: Vec<Box<dyn TokenFilter>>,
+}
+
+impl Analyzer {
+    pub fn new<T: TokenStream + 'static>(tokenizer: T) -> Self {
+        Self {
+            tokenizer: Box::new(tokenizer),
+            filters: vec![],
+        }
+    }
+
+    /// runs `filter` on the tokens after the filters added before it
+    pub fn filter<F: TokenFilter + 'static>(mut self, filter: F) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// One of the built in analyzers
+    pub fn by_name(name: &str) -> Result<Self> {
+        let analyzer = match name {
+            TRIGRAM => Self::new(NGramTokenizer::new(3)?).filter(LowerCaser),
+            WORD => Self::new(WordTokenizer)
+                .filter(MaxLength(64))
+                .filter(LowerCaser),
+            EDGE_NGRAM => Self::new(EdgeNGramTokenizer::new(2, 10)?).filter(LowerCaser),
+            PATH => Self::new(PathTokenizer).filter(MaxLength(128)),
+            NUMBER => Self::new(NumberTokenizer),
+            _ => return Err(anyhow!("Unknown analyzer: {}", name)),
+        };
+        Ok(analyzer)
+    }
+
+    /// lowercased trigrams, as used by regex & substring search
+    pub fn trigram() -> Self {
+        Self::by_name(TRIGRAM).unwrap()
+    }
+
+    /// Calls `f` with every token of `data` the filters keep
+    pub fn analyze<F: FnMut(Token)>(&self, data: &str, mut f: F) {
+        self.tokenizer.for_each_token(data, &mut |token| {
+            let mut token = Some(token);
+            for filter in &self.filters {
+                token = match token {
+                    Some(token) => filter.filter(token),
+                    None => break,
+                };
+            }
+            if let Some(token) = token {
+                f(token)
+            }
+        });
+    }
+
+    pub fn tokens(&self, data: &str) -> Vec<Token> {
+        let mut tokens = vec![];
+        self.analyze(data, |token| tokens.push(token));
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn analyzer_test() {
+        assert_eq!(Analyzer::trigram().tokens("GET /"), ["get", "et ", "t /"]);
+        assert_eq!(
+            Analyzer::by_name(WORD)
+                .unwrap()
+                .tokens("Connection RESET by peer"),
+            ["connection", "reset", "by", "peer"]
+        );
+        assert!(Analyzer::by_name("soundex").is_err());
+
+        // filters run in order
+        let analyzer = Analyzer::new(WordTokenizer)
+            .filter(LowerCaser)
+            .filter(StopWords::english())
+            .filter(MaxLength(8));
+        assert_eq!(
+            analyzer.tokens("The upstream is DOWN for 3c2f8a9b01e4"),
+            ["upstream", "down"]
+        );
+    }
+}
@@ -0,0 +1,929 @@
+//! Write path: wal -> memtable -> blocks
+//!
+//! Every row is logged in the wal and buffered in the active memtable. Once
+//! the memtable grows past `max_memtable_size` it is frozen and handed to a
+//! background flusher, which writes one block per table, commits them to
+//! the table manifests and then checkpoints the wal up to the last row of
+//! the memtable.
+//!
+//! Manifests also record the last flushed lsn, so rows that made it to a
+//! block before a crash aren't replayed twice if the checkpoint was lost.
+//! Replayed rows of tables no longer configured are skipped.
+//! Frozen memtables are flushed oldest first: one that fails stays frozen &
+//! is retried before any later one, so neither manifests nor the wal
+//! checkpoint ever move past rows that aren't in a block.
+//!
+//! Between flushes the flusher also drops the blocks of tables with a
+//! retention once they are expired, every `retention_interval`.
+//!
+//! Readers can `subscribe` to a table: they get its blocks, the rows it has
+//! in memtables, and a channel of the rows written from then on. A reader
+//! more than `tail_buffer` rows behind is dropped, writes never wait.
+
+use crate::memtable::{MemTable, TableBatches};
+use crate::row::Row;
+use anyhow::{anyhow, Result};
+use arrow::record_batch::RecordBatch;
+use log::{error, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError,
+};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use store::compaction::BlockIndexer;
+use store::manifest::{Snapshot, TableEdit};
+use store::schema::Schema;
+use store::table::{Table, TableBlock};
+use wal::{SharedWal, Wal, WriteRecord};
+
+pub const DEFAULT_MEMTABLE_SIZE: usize = 64 * 1024 * 1024;
+pub const DEFAULT_RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_TAIL_BUFFER: usize = 64 * 1024;
+
+/// Tables with the blocks flushed so far, shared with readers
+pub type Tables = Arc<HashMap<String, Table>>;
+
+/// Memtables handed to the flusher, oldest first, readable until flushed
+type Frozen = Arc<Mutex<Vec<Arc<FrozenMemTable>>>>;
+
+struct FrozenMemTable {
+    max_lsn: Option<u64>,
+    tables: Vec<TableBatches>,
+}
+
+impl FrozenMemTable {
+    fn new(memtable: MemTable) -> Result<Self> {
+        Ok(Self {
+            max_lsn: memtable.max_lsn(),
+            tables: memtable.into_batches()?,
+        })
+    }
+
+    fn batches(&self, table: &str) -> &[RecordBatch] {
+        self.tables
+            .iter()
+            .find(|t| t.table == table)
+            .map_or(&[], |t| &t.batches)
+    }
+}
+
+/// Asks the flusher to flush the frozen memtables
+struct FlushTask {
+    done: Option<Sender<Result<()>>>,
+}
+
+struct Subscriber {
+    table: String,
+    rows: SyncSender<(u64, Row)>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// Rows of a table when subscribing, then the rows written to it
+pub struct Subscription {
+    /// flushed rows
+    pub snapshot: Arc<Snapshot>,
+    /// rows in memtables, in lsn order
+    pub unflushed: Vec<RecordBatch>,
+    rows: Receiver<(u64, Row)>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    /// Waits for the next row & its lsn, `None` once the writer is dropped
+    pub fn recv(&self) -> Result<Option<(u64, Row)>> {
+        match self.rows.recv() {
+            Ok(row) => Ok(Some(row)),
+            Err(_) => self.closed(),
+        }
+    }
+
+    /// The next row if there is one already
+    pub fn try_recv(&self) -> Result<Option<(u64, Row)>> {
+        match self.rows.try_recv() {
+            Ok(row) => Ok(Some(row)),
+            Err(_) => self.closed(),
+        }
+    }
+
+    fn closed(&self) -> Result<Option<(u64, Row)>> {
+        if self.lagged.load(Ordering::SeqCst) {
+            return Err(anyhow!("Subscriber fell behind, rows were dropped"));
+        }
+        Ok(None)
+    }
+}
+
+pub struct WriterBuilder<W: wal::Store, S: store::Store> {
+    wal: Wal<W>,
+    store: S,
+    schemas: HashMap<String, Schema>,
+    retentions: HashMap<String, Duration>,
+    indexers: Vec<Arc<dyn BlockIndexer>>,
+    max_memtable_size: usize,
+    retention_interval: Duration,
+    tail_buffer: usize,
+}
+
+impl<W, S> WriterBuilder<W, S>
+where
+    W: wal::Store + Send + 'static,
+    W::Writer: Send,
+    S: store::Store + Send + Sync + 'static,
+{
+    pub fn new(wal: Wal<W>, store: S) -> Self {
+        Self {
+            wal,
+            store,
+            schemas: HashMap::new(),
+            retentions: HashMap::new(),
+            indexers: vec![],
+            max_memtable_size: DEFAULT_MEMTABLE_SIZE,
+            retention_interval: DEFAULT_RETENTION_INTERVAL,
+            tail_buffer: DEFAULT_TAIL_BUFFER,
+        }
+    }
+
+    pub fn table(mut self, name: impl Into<String>, schema: Schema) -> Self {
+        self.schemas.insert(name.into(), schema);
+        self
+    }
+
+    /// keeps rows of `table` for `retention`, forever by default
+    pub fn retention(mut self, table: impl Into<String>, retention: Duration) -> Self {
+        self.retentions.insert(table.into(), retention);
+        self
+    }
+
+    /// how often expired blocks are looked for
+    pub fn retention_interval(mut self, retention_interval: Duration) -> Self {
+        self.retention_interval = retention_interval;
+        self
+    }
+
+    /// builds side indexes of every flushed block
+    pub fn indexer(mut self, indexer: Arc<dyn BlockIndexer>) -> Self {
+        self.indexers.push(indexer);
+        self
+    }
+
+    pub fn max_memtable_size(mut self, max_memtable_size: usize) -> Self {
+        self.max_memtable_size = max_memtable_size;
+        self
+    }
+
+    /// rows a subscriber can be behind before it is dropped
+    pub fn tail_buffer(mut self, tail_buffer: usize) -> Self {
+        self.tail_buffer = tail_buffer;
+        self
+    }
+
+    /// Opens the tables, replays the wal into the memtable & starts the
+    /// flusher
+    pub fn build(self) -> Result<Writer<W>> {
+        let mut tables = HashMap::new();
+        for (name, schema) in &self.schemas {
+            let mut table = Table::open_or_create(&self.store, name, schema.clone())?;
+            if let Some(retention) = self.retentions.get(name) {
+                table = table.with_retention(*retention);
+            }
+            tables.insert(name.clone(), table);
+        }
+        let tables = Arc::new(tables);
+
+        let mut active = MemTable::new();
+        let mut skipped = None;
+        for record in self.wal.replay()? {
+            let record = record?;
+            let row = Row::decode(record.data())?;
+            let table = match tables.get(&row.table) {
+                Some(table) => table,
+                None => {
+                    warn!(
+                        "skipping wal record {} of unknown table {}",
+                        record.lsn(),
+                        row.table
+                    );
+                    skipped = Some(record.lsn());
+                    continue;
+                }
+            };
+            if record.lsn() <= table.snapshot().flushed_lsn() {
+                continue;
+            }
+            active.insert(record.lsn(), table.schema(), &row)?;
+        }
+
+        let wal = self.wal.shared()?;
+        // with nothing left to flush, skipped rows are checkpointed right away,
+        // otherwise the next flush moves past them
+        if let (Some(lsn), true) = (skipped, active.is_empty()) {
+            wal.checkpoint(lsn)?;
+        }
+        let (tx, rx) = channel();
+        let frozen = Frozen::default();
+
+        let flusher = Flusher {
+            store: self.store,
+            wal: wal.clone(),
+            tables: tables.clone(),
+            frozen: frozen.clone(),
+            indexers: self.indexers,
+            retention_interval: self.retention_interval,
+        };
+        let handle = std::thread::Builder::new()
+            .name("flusher".into())
+            .spawn(move || flusher.run(rx))?;
+
+        Ok(Writer {
+            wal,
+            schemas: self.schemas,
+            active: Mutex::new(active),
+            frozen,
+            subscribers: Mutex::new(vec![]),
+            tables,
+            flush_tx: Mutex::new(Some(tx)),
+            handle: Some(handle),
+            max_memtable_size: self.max_memtable_size,
+            tail_buffer: self.tail_buffer,
+        })
+    }
+}
+
+pub struct Writer<W: wal::Store> {
+    wal: SharedWal<W>,
+    schemas: HashMap<String, Schema>,
+    active: Mutex<MemTable>,
+    frozen: Frozen,
+    /// locked after `active`
+    subscribers: Mutex<Vec<Subscriber>>,
+    tables: Tables,
+    flush_tx: Mutex<Option<Sender<FlushTask>>>,
+    handle: Option<JoinHandle<()>>,
+    max_memtable_size: usize,
+    tail_buffer: usize,
+}
+
+impl<W: wal::Store> Writer<W> {
+    /// Logs & buffers the row, returns its lsn once it is durable
+    pub fn write(&self, row: &Row) -> Result<u64> {
+        let schema = self.schema(&row.table)?;
+        MemTable::validate(schema, row)?;
+        let record = WriteRecord::new(row.encode()?)?;
+
+        let lsn = {
+            // memtables must see rows in lsn order, or a checkpoint could
+            // cover a row that is still on its way to the memtable
+            let mut active = self.active.lock().unwrap();
+            let lsn = self.wal.write(&record)?;
+            active.insert(lsn, schema, row)?;
+            self.publish(lsn, row);
+            if active.size() >= self.max_memtable_size {
+                let memtable = std::mem::take(&mut *active);
+                self.send(memtable, None)?;
+            }
+            lsn
+        };
+
+        self.wal.sync_to(lsn)?;
+        Ok(lsn)
+    }
+
+    /// Flushes everything buffered so far & waits for it to land in blocks
+    pub fn flush(&self) -> Result<()> {
+        let (tx, rx) = channel();
+        {
+            let mut active = self.active.lock().unwrap();
+            let memtable = std::mem::take(&mut *active);
+            self.send(memtable, Some(tx))?;
+        }
+        rx.recv()?
+    }
+
+    pub fn tables(&self) -> Tables {
+        self.tables.clone()
+    }
+
+    /// Rows of `table` as of now, & the rows written to it from then on
+    pub fn subscribe(&self, table: &str) -> Result<Subscription> {
+        let flushed = self
+            .tables
+            .get(table)
+            .ok_or_else(|| anyhow!("Unknown table: {}", table))?;
+
+        // no memtable is frozen while `active` is locked, but one can be
+        // flushed: frozen ones are listed before the snapshot is taken, so
+        // one flushed in between is in both and skipped by its lsn
+        let mut active = self.active.lock().unwrap();
+        let frozen = self.frozen.lock().unwrap().clone();
+        let snapshot = flushed.snapshot();
+        let mut unflushed = vec![];
+        for memtable in frozen {
+            if memtable.max_lsn > Some(snapshot.flushed_lsn()) {
+                unflushed.extend_from_slice(memtable.batches(table));
+            }
+        }
+        unflushed.extend(active.batches(table)?);
+
+        let (tx, rx) = sync_channel(self.tail_buffer);
+        let lagged = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            table: table.to_owned(),
+            rows: tx,
+            lagged: lagged.clone(),
+        });
+        Ok(Subscription {
+            snapshot,
+            unflushed,
+            rows: rx,
+            lagged,
+        })
+    }
+
+    /// Sends the row to the subscribers of its table, dropping the ones
+    /// gone or too far behind
+    fn publish(&self, lsn: u64, row: &Row) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| {
+            if s.table != row.table {
+                return true;
+            }
+            match s.rows.try_send((lsn, row.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    s.lagged.store(true, Ordering::SeqCst);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    fn schema(&self, table: &str) -> Result<&Schema> {
+        self.schemas
+            .get(table)
+            .ok_or_else(|| anyhow!("Unknown table: {}", table))
+    }
+
+    /// Freezes the memtable & queues it, with `active` locked
+    fn send(&self, memtable: MemTable, done: Option<Sender<Result<()>>>) -> Result<()> {
+        let memtable = Arc::new(FrozenMemTable::new(memtable)?);
+        self.frozen.lock().unwrap().push(memtable.clone());
+        let sent = self
+            .flush_tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| anyhow!("Flusher is stopped"))?
+            .send(FlushTask { done })
+            .map_err(|_| anyhow!("Flusher is stopped"));
+        if sent.is_err() {
+            unfreeze(&self.frozen, &memtable);
+        }
+        sent
+    }
+}
+
+impl<W: wal::Store> Drop for Writer<W> {
+    /// stops the flusher once queued memtables are flushed, the active one
+    /// stays in the wal & is replayed on the next start
+    fn drop(&mut self) {
+        self.flush_tx.lock().unwrap().take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn unfreeze(frozen: &Frozen, memtable: &Arc<FrozenMemTable>) {
+    frozen.lock().unwrap().retain(|m| !Arc::ptr_eq(m, memtable));
+}
+
+struct Flusher<W: wal::Store, S: store::Store> {
+    store: S,
+    wal: SharedWal<W>,
+    tables: Tables,
+    frozen: Frozen,
+    indexers: Vec<Arc<dyn BlockIndexer>>,
+    retention_interval: Duration,
+}
+
+impl<W: wal::Store, S: store::Store> Flusher<W, S> {
+    fn run(self, rx: Receiver<FlushTask>) {
+        let mut last_expire = Instant::now();
+        loop {
+            match rx.recv_timeout(self.retention_interval) {
+                Ok(task) => self.handle(task),
+                Err(RecvTimeoutError::Timeout) => {
+                    // retries memtables left by a failed flush
+                    if let Err(e) = self.flush_frozen() {
+                        error!("memtable flush failed: {}", e);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if last_expire.elapsed() >= self.retention_interval {
+                if let Err(e) = self.expire() {
+                    error!("retention failed: {}", e);
+                }
+                last_expire = Instant::now();
+            }
+        }
+    }
+
+    fn handle(&self, task: FlushTask) {
+        let result = self.flush_frozen();
+        match task.done {
+            Some(done) => {
+                let _ = done.send(result);
+            }
+            None => {
+                if let Err(e) = result {
+                    // retried on the next flush, its rows are still in the
+                    // wal & come back on restart
+                    error!("memtable flush failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Flushes frozen memtables oldest first, up to the first one failing
+    fn flush_frozen(&self) -> Result<()> {
+        loop {
+            let oldest = self.frozen.lock().unwrap().first().cloned();
+            let memtable = match oldest {
+                Some(memtable) => memtable,
+                None => return Ok(()),
+            };
+            self.flush(&memtable)?;
+            unfreeze(&self.frozen, &memtable);
+        }
+    }
+
+    /// Drops expired blocks of every table, returns how many were dropped.
+    /// Also deletes blocks readers were still using on earlier commits.
+    fn expire(&self) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let now = now.min(i32::MAX as u64) as i32;
+
+        let mut expired = 0;
+        for table in self.tables.values() {
+            expired += table.expire(&self.store, now)?;
+            table.collect_garbage(&self.store)?;
+        }
+        Ok(expired)
+    }
+
+    fn flush(&self, memtable: &FrozenMemTable) -> Result<()> {
+        let max_lsn = match memtable.max_lsn {
+            Some(lsn) => lsn,
+            None => return Ok(()),
+        };
+        self.wal.sync_to(max_lsn)?;
+
+        // blocks are named after the last lsn they hold
+        let name = format!("{:020}", max_lsn);
+        for table in &memtable.tables {
+            let flushed = self
+                .tables
+                .get(&table.table)
+                .ok_or_else(|| anyhow!("Unknown table: {}", table.table))?;
+            // committed before a later table of this memtable failed
+            if flushed.snapshot().flushed_lsn() >= max_lsn {
+                continue;
+            }
+
+            let mut block = TableBlock::write(
+                &self.store,
+                &table.table,
+                &name,
+                table.schema.clone(),
+                &table.batches,
+            )?;
+            for indexer in &self.indexers {
+                for path in indexer.index(&self.store, &table.table, &block, &table.batches)? {
+                    block.add_index(path);
+                }
+            }
+
+            flushed.commit(
+                &self.store,
+                TableEdit::new().add_block(block).flushed_lsn(max_lsn),
+            )?;
+        }
+
+        self.wal.checkpoint(max_lsn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fst::TermIndexer;
+    use crate::row::Value;
+    use pretty_assertions::assert_eq;
+    use store::builder::SchemaBuilder;
+    use store::schema::FieldType;
+    use store::FSBlobStore;
+    use wal::MemStore;
+    use wal::Store as _;
+
+    fn schema() -> Schema {
+        SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("status", FieldType::Str)
+            .build()
+            .unwrap()
+    }
+
+    fn row(i: i32) -> Row {
+        Row::new("apache", vec![Value::Int(i), Value::Str("200".into())])
+    }
+
+    /// Fails the puts of keys starting with `fail`, if set
+    struct FailingStore {
+        inner: FSBlobStore,
+        fail: Arc<Mutex<Option<String>>>,
+    }
+
+    impl store::Store for FailingStore {
+        fn root(&self) -> &std::path::PathBuf {
+            self.inner.root()
+        }
+
+        fn get<'a>(&self, key: &str, buf: &'a mut Vec<u8>) -> Result<&'a mut Vec<u8>> {
+            self.inner.get(key, buf)
+        }
+
+        fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+            if let Some(prefix) = &*self.fail.lock().unwrap() {
+                if key.starts_with(prefix.as_str()) {
+                    return Err(anyhow!("Can't write {}", key));
+                }
+            }
+            self.inner.put(key, data)
+        }
+
+        fn delete(&self, key: &str) -> Result<()> {
+            self.inner.delete(key)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn list(&self) -> Result<Vec<std::fs::File>> {
+            self.inner.list()
+        }
+
+        fn exist(&self, key: &str) -> bool {
+            self.inner.exist(key)
+        }
+
+        fn clean(&self, key: &str) -> Result<()> {
+            self.inner.clean(key)
+        }
+    }
+
+    #[test]
+    fn write_and_flush_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("write_and_flush_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let wal_store = MemStore::new();
+
+        let writer = WriterBuilder::new(
+            Wal::new(wal_store.clone()),
+            FSBlobStore {
+                root: root.clone(),
+                blobs: vec![],
+            },
+        )
+        .table("apache", schema())
+        .indexer(Arc::new(TermIndexer::new().unwrap()))
+        .build()
+        .unwrap();
+
+        for i in 0..100 {
+            writer.write(&row(i)).unwrap();
+        }
+        assert!(writer.write(&Row::new("nginx", vec![])).is_err());
+        writer.flush().unwrap();
+
+        let snapshot = writer.tables()["apache"].snapshot();
+        let blocks = snapshot.blocks();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].rows(), 100);
+        assert_eq!(snapshot.flushed_lsn(), 100);
+        assert!(root.join(blocks[0].path()).exists());
+        assert!(root.join(&blocks[0].indexes()[0]).exists());
+
+        // flushed rows are checkpointed & not replayed again
+        assert_eq!(Wal::new(wal_store).replay().unwrap().count(), 0);
+        drop(writer);
+
+        // blocks are found again through the manifest
+        let store = FSBlobStore {
+            root,
+            blobs: vec![],
+        };
+        assert_eq!(
+            Table::open(&store, "apache").unwrap().snapshot().rows(),
+            100
+        );
+    }
+
+    #[test]
+    fn replay_unflushed_rows_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("replay_unflushed_rows_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let wal_store = MemStore::new();
+        let fs_store = || FSBlobStore {
+            root: root.clone(),
+            blobs: vec![],
+        };
+
+        let writer = WriterBuilder::new(Wal::new(wal_store.clone()), fs_store())
+            .table("apache", schema())
+            .build()
+            .unwrap();
+        for i in 0..10 {
+            writer.write(&row(i)).unwrap();
+        }
+        drop(writer);
+
+        let writer = WriterBuilder::new(Wal::new(wal_store), fs_store())
+            .table("apache", schema())
+            .build()
+            .unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.tables()["apache"].snapshot().rows(), 10);
+    }
+
+    #[test]
+    fn skip_unknown_table_on_replay_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("skip_unknown_table_on_replay_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let wal_store = MemStore::new();
+        let fs_store = || FSBlobStore {
+            root: root.clone(),
+            blobs: vec![],
+        };
+
+        let writer = WriterBuilder::new(Wal::new(wal_store.clone()), fs_store())
+            .table("apache", schema())
+            .table("nginx", schema())
+            .build()
+            .unwrap();
+        for i in 0..5 {
+            writer
+                .write(&Row::new(
+                    "nginx",
+                    vec![Value::Int(i), Value::Str("404".into())],
+                ))
+                .unwrap();
+        }
+        drop(writer);
+
+        // nginx was removed from the config
+        let writer = WriterBuilder::new(Wal::new(wal_store.clone()), fs_store())
+            .table("apache", schema())
+            .build()
+            .unwrap();
+        assert!(writer.tables().get("nginx").is_none());
+        drop(writer);
+        assert_eq!(Wal::new(wal_store).replay().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn skip_flushed_rows_on_replay_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("skip_flushed_rows_on_replay_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let wal_store = MemStore::new();
+        let fs_store = || FSBlobStore {
+            root: root.clone(),
+            blobs: vec![],
+        };
+
+        let writer = WriterBuilder::new(Wal::new(wal_store.clone()), fs_store())
+            .table("apache", schema())
+            .build()
+            .unwrap();
+        for i in 0..10 {
+            writer.write(&row(i)).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        // crash after the manifest commit but before the wal checkpoint
+        wal_store.remove("checkpoint").unwrap();
+        let wal = Wal::new(wal_store);
+        assert_eq!(wal.replay().unwrap().count(), 10);
+
+        let writer = WriterBuilder::new(wal, fs_store())
+            .table("apache", schema())
+            .build()
+            .unwrap();
+        writer.flush().unwrap();
+        let snapshot = writer.tables()["apache"].snapshot();
+        assert_eq!(snapshot.blocks().len(), 1);
+        assert_eq!(snapshot.rows(), 10);
+    }
+
+    #[test]
+    fn failed_flush_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("failed_flush_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let wal_store = MemStore::new();
+        let fail = Arc::new(Mutex::new(Some("nginx".to_owned())));
+        let fs_store = || FailingStore {
+            inner: FSBlobStore {
+                root: root.clone(),
+                blobs: vec![],
+            },
+            fail: fail.clone(),
+        };
+        let nginx = |i: i32| Row::new("nginx", vec![Value::Int(i), Value::Str("404".into())]);
+
+        let writer = WriterBuilder::new(Wal::new(wal_store.clone()), fs_store())
+            .table("apache", schema())
+            .table("nginx", schema())
+            .build()
+            .unwrap();
+        for i in 0..5 {
+            writer.write(&row(i)).unwrap();
+            writer.write(&nginx(i)).unwrap();
+        }
+        // apache is committed, nginx fails
+        assert!(writer.flush().is_err());
+        assert_eq!(writer.tables()["apache"].snapshot().rows(), 5);
+
+        // the failed memtable goes first, later rows wait for it
+        for i in 5..10 {
+            writer.write(&row(i)).unwrap();
+        }
+        assert!(writer.flush().is_err());
+        let tables = writer.tables();
+        assert_eq!(tables["apache"].snapshot().rows(), 5);
+        assert_eq!(tables["apache"].snapshot().flushed_lsn(), 10);
+        assert_eq!(tables["nginx"].snapshot().rows(), 0);
+        drop(writer);
+
+        // nothing was checkpointed
+        assert_eq!(Wal::new(wal_store.clone()).replay().unwrap().count(), 15);
+
+        fail.lock().unwrap().take();
+        let writer = WriterBuilder::new(Wal::new(wal_store), fs_store())
+            .table("apache", schema())
+            .table("nginx", schema())
+            .build()
+            .unwrap();
+        writer.flush().unwrap();
+        let tables = writer.tables();
+        assert_eq!(tables["apache"].snapshot().blocks().len(), 2);
+        assert_eq!(tables["apache"].snapshot().rows(), 10);
+        assert_eq!(tables["nginx"].snapshot().rows(), 5);
+    }
+
+    #[test]
+    fn retry_failed_flush_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("retry_failed_flush_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let wal_store = MemStore::new();
+        let fail = Arc::new(Mutex::new(Some("apache".to_owned())));
+
+        let writer = WriterBuilder::new(
+            Wal::new(wal_store.clone()),
+            FailingStore {
+                inner: FSBlobStore {
+                    root,
+                    blobs: vec![],
+                },
+                fail: fail.clone(),
+            },
+        )
+        .table("apache", schema())
+        .max_memtable_size(5 * 8)
+        .build()
+        .unwrap();
+        // background flushes of 5 rows each fail
+        for i in 0..10 {
+            writer.write(&row(i)).unwrap();
+        }
+        fail.lock().unwrap().take();
+        for i in 10..12 {
+            writer.write(&row(i)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let snapshot = writer.tables()["apache"].snapshot();
+        assert_eq!(snapshot.rows(), 12);
+        assert_eq!(snapshot.flushed_lsn(), 12);
+        assert_eq!(Wal::new(wal_store).replay().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn retention_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("retention_test");
+        let _ = std::fs::remove_dir_all(&root);
+
+        let writer = WriterBuilder::new(
+            Wal::new(MemStore::new()),
+            FSBlobStore {
+                root: root.clone(),
+                blobs: vec![],
+            },
+        )
+        .table("apache", schema())
+        .retention("apache", Duration::from_secs(30 * 24 * 3600))
+        .retention_interval(Duration::from_millis(10))
+        .indexer(Arc::new(TermIndexer::new().unwrap()))
+        .build()
+        .unwrap();
+
+        // rows from 1970 are long expired
+        for i in 0..10 {
+            writer.write(&row(i)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let tables = writer.tables();
+        let block = root.join("apache").join(format!("{:020}.arrow", 10));
+        let terms = root.join("apache").join(format!("{:020}.status.term", 10));
+        for _ in 0..200 {
+            if tables["apache"].snapshot().blocks().is_empty() && !block.exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(tables["apache"].snapshot().blocks().len(), 0);
+        assert!(!block.exists());
+        assert!(!terms.exists());
+    }
+
+    #[test]
+    fn subscribe_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-ingest")
+            .join("subscribe_test");
+        let _ = std::fs::remove_dir_all(&root);
+
+        let writer = WriterBuilder::new(
+            Wal::new(MemStore::new()),
+            FSBlobStore {
+                root,
+                blobs: vec![],
+            },
+        )
+        .table("apache", schema())
+        .tail_buffer(5)
+        .build()
+        .unwrap();
+        assert!(writer.subscribe("nginx").is_err());
+
+        for i in 0..10 {
+            writer.write(&row(i)).unwrap();
+        }
+        writer.flush().unwrap();
+        for i in 10..15 {
+            writer.write(&row(i)).unwrap();
+        }
+
+        let sub = writer.subscribe("apache").unwrap();
+        assert_eq!(sub.snapshot.rows(), 10);
+        assert_eq!(sub.unflushed.iter().map(|b| b.num_rows()).sum::<usize>(), 5);
+        assert!(sub.try_recv().unwrap().is_none());
+
+        writer.write(&row(15)).unwrap();
+        assert_eq!(sub.recv().unwrap(), Some((16, row(15))));
+
+        // a subscriber that can't keep up is dropped
+        for i in 16..22 {
+            writer.write(&row(i)).unwrap();
+        }
+        for i in 16..21 {
+            assert_eq!(sub.recv().unwrap(), Some((i as u64 + 1, row(i))));
+        }
+        assert!(sub.recv().is_err());
+
+        let sub = writer.subscribe("apache").unwrap();
+        drop(writer);
+        assert_eq!(sub.recv().unwrap(), None);
+    }
+}
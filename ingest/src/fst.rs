@@ -4,8 +4,28 @@
 //! But i am too lazy to create a crate for this, So lets keep this here
 //! for now
 
-use anyhow::Result;
+use crate::analyzer::{Analyzer, EDGE_NGRAM, NUMBER, PATH, TRIGRAM, WORD};
+use crate::postings::{
+    merge_moved, mmap, postings_path, term_path, PostingsBuilder, PostingsReader,
+};
+use anyhow::{anyhow, Result};
+use arrow::array::{Array, StringArray};
+use arrow::record_batch::RecordBatch;
+use fst::automaton::{Automaton, Levenshtein, StartsWith, Str};
+use fst::map::{IndexedValue, OpBuilder, Stream};
+use fst::{IntoStreamer, Streamer};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::io::Write;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use store::compaction::BlockIndexer;
+use store::table::TableBlock;
+use store::Store;
+
+pub const TERM_EXT: &str = "term";
 
 // based on tantivy's fst & burntsushi's transducer blog
 
@@ -31,14 +51,276 @@ where
         Ok(())
     }
 
-    pub fn build(mut self) -> Result<W> {
-        println!("BYTES WRITTEN:: {}", self.map_builder.bytes_written());
+    /// inserts `key` with its own value instead of the next term id
+    pub fn insert_value<K: AsRef<[u8]>>(&mut self, key: K, value: u64) -> Result<()> {
+        self.map_builder.insert(key, value)?;
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<W> {
         Ok(self.map_builder.into_inner()?)
     }
 
-    pub fn merge(&mut self) {
-        unimplemented!()
+    /// Inserts the union of the terms of `dicts`, each with the next term id
+    pub fn merge<'a, D, I>(&mut self, dicts: I) -> Result<()>
+    where
+        D: AsRef<[u8]> + 'a,
+        I: IntoIterator<Item = &'a TermDict<D>>,
+    {
+        self.merge_with(dicts, |_, _| Ok(None))
     }
+
+    /// Inserts the union of the terms of `dicts`, in order. `value` gets each
+    /// term with the values it has in the dicts holding it (`index` being the
+    /// position in `dicts`) & returns its merged value, `None` for the next
+    /// term id.
+    pub fn merge_with<'a, D, I, F>(&mut self, dicts: I, mut value: F) -> Result<()>
+    where
+        D: AsRef<[u8]> + 'a,
+        I: IntoIterator<Item = &'a TermDict<D>>,
+        F: FnMut(&[u8], &[IndexedValue]) -> Result<Option<u64>>,
+    {
+        let mut op = OpBuilder::new();
+        for dict in dicts {
+            op = op.add(dict.as_fst());
+        }
+        let mut union = op.union();
+        while let Some((term, values)) = union.next() {
+            match value(term, values)? {
+                Some(v) => self.insert_value(term, v)?,
+                None => self.insert(term)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read side of `TermDictBuilder`, over the bytes of a `.term` file either
+/// loaded in memory or mmapped
+pub struct TermDict<D = Vec<u8>> {
+    map: fst::Map<D>,
+}
+
+impl<D: AsRef<[u8]>> TermDict<D> {
+    pub fn new(data: D) -> Result<Self> {
+        Ok(Self {
+            map: fst::Map::new(data)?,
+        })
+    }
+
+    /// number of terms
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// value of `term`, if the dictionary has it
+    pub fn get<K: AsRef<[u8]>>(&self, term: K) -> Option<u64> {
+        self.map.get(term)
+    }
+
+    pub fn contains<K: AsRef<[u8]>>(&self, term: K) -> bool {
+        self.map.contains_key(term)
+    }
+
+    /// all terms, in order
+    pub fn stream(&self) -> Stream<'_> {
+        self.map.stream()
+    }
+
+    /// terms starting with `prefix`, in order
+    pub fn prefix<'a>(&'a self, prefix: &'a str) -> Stream<'a, StartsWith<Str<'a>>> {
+        self.map
+            .search(Str::new(prefix).starts_with())
+            .into_stream()
+    }
+
+    /// terms within `range`, in order
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Stream<'_> {
+        let mut builder = self.map.range();
+        builder = match range.start_bound() {
+            Bound::Included(k) => builder.ge(k),
+            Bound::Excluded(k) => builder.gt(k),
+            Bound::Unbounded => builder,
+        };
+        builder = match range.end_bound() {
+            Bound::Included(k) => builder.le(k),
+            Bound::Excluded(k) => builder.lt(k),
+            Bound::Unbounded => builder,
+        };
+        builder.into_stream()
+    }
+
+    /// terms within `distance` edits of `term`, in order
+    pub fn fuzzy(&self, term: &str, distance: u32) -> Result<Stream<'_, Levenshtein>> {
+        let automaton = Levenshtein::new(term, distance)?;
+        Ok(self.map.search(automaton).into_stream())
+    }
+
+    /// terms accepted by any other `fst` automaton, in order
+    pub fn search<A: Automaton>(&self, automaton: A) -> Stream<'_, A> {
+        self.map.search(automaton).into_stream()
+    }
+
+    pub fn as_fst(&self) -> &fst::Map<D> {
+        &self.map
+    }
+}
+
+impl TermDict {
+    /// Loads the term dictionary of a block column
+    pub fn open(store: &dyn Store, table: &str, block: &str, column: &str) -> Result<Self> {
+        let mut data = vec![];
+        store.get(term_path(table, block, column).to_str().unwrap(), &mut data)?;
+        Self::new(data)
+    }
+}
+
+impl TermDict<Mmap> {
+    /// Maps the `.term` file at `path` in `store`, which must not be
+    /// rewritten while mapped
+    pub fn mmap(store: &dyn Store, path: &Path) -> Result<Self> {
+        Self::new(mmap(store, path)?)
+    }
+}
+
+/// Postings of each indexed column of a block, kept next to it as
+/// `<table>/<block>.<column>.term` & `<table>/<block>.<column>.post`. Terms
+/// come from the analyzer the schema picked for the column.
+pub struct TermIndexer {
+    analyzers: HashMap<String, Analyzer>,
+    /// ascii values of `trigram` columns skip the analyzer, unless replaced
+    ascii_trigrams: bool,
+}
+
+impl TermIndexer {
+    /// Indexer knowing the built in analyzers
+    pub fn new() -> Result<Self> {
+        let mut analyzers = HashMap::new();
+        for name in &[TRIGRAM, WORD, EDGE_NGRAM, PATH, NUMBER] {
+            analyzers.insert(name.to_string(), Analyzer::by_name(name)?);
+        }
+        Ok(Self {
+            analyzers,
+            ascii_trigrams: true,
+        })
+    }
+
+    /// adds or replaces the analyzer schemas refer to as `name`
+    pub fn analyzer(mut self, name: &str, analyzer: Analyzer) -> Self {
+        self.ascii_trigrams &= name != TRIGRAM;
+        self.analyzers.insert(name.to_owned(), analyzer);
+        self
+    }
+}
+
+impl BlockIndexer for TermIndexer {
+    fn index(
+        &self,
+        store: &dyn Store,
+        table: &str,
+        block: &TableBlock,
+        batches: &[RecordBatch],
+    ) -> Result<Vec<PathBuf>> {
+        // fails early on blocks too big for u32 row ids
+        u32::try_from(block.rows())?;
+
+        let mut paths = vec![];
+        for column in block.schema().indexed() {
+            let index = block.schema().projection(&[column])?[0];
+            let name = block.schema().analyzer(column).unwrap();
+            let analyzer = self
+                .analyzers
+                .get(name)
+                .ok_or_else(|| anyhow!("Unknown analyzer {} for column {}", name, column))?;
+            let ascii_trigrams = self.ascii_trigrams && name == TRIGRAM;
+            let mut postings = PostingsBuilder::new();
+            let mut row = 0;
+            for batch in batches {
+                let values = batch
+                    .column(index)
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .ok_or_else(|| anyhow!("Indexed column {} isn't a string", column))?;
+                for i in 0..batch.num_rows() {
+                    if values.is_valid(i) {
+                        let value = values.value(i);
+                        if !(ascii_trigrams && postings.insert_ascii_trigrams(value, row)) {
+                            analyzer.analyze(value, |token| postings.insert(token, row));
+                        }
+                    }
+                    row += 1;
+                }
+            }
+
+            let (terms, posts) = postings.build(Vec::new(), Vec::new())?;
+            paths.extend(put_postings(store, table, block, column, terms, posts)?);
+        }
+        Ok(paths)
+    }
+
+    /// Merges the postings of the inputs, unless one of them misses some
+    fn merge(
+        &self,
+        store: &dyn Store,
+        table: &str,
+        block: &TableBlock,
+        inputs: &[Arc<TableBlock>],
+        moved: &[usize],
+        batches: &[RecordBatch],
+    ) -> Result<Vec<PathBuf>> {
+        u32::try_from(block.rows())?;
+        let indexed = |input: &Arc<TableBlock>| {
+            block.schema().indexed().all(|column| {
+                input
+                    .indexes()
+                    .contains(&postings_path(table, input.name(), column))
+            })
+        };
+        if !inputs.iter().all(indexed) {
+            return self.index(store, table, block, batches);
+        }
+
+        let mut paths = vec![];
+        for column in block.schema().indexed() {
+            let readers = inputs
+                .iter()
+                .map(|input| PostingsReader::open(store, table, input.name(), column))
+                .collect::<Result<Vec<_>>>()?;
+            let mut rows = 0;
+            let parts = readers
+                .iter()
+                .zip(inputs)
+                .map(|(reader, input)| {
+                    let part = (reader, rows);
+                    rows += input.rows() as u32;
+                    part
+                })
+                .collect::<Vec<_>>();
+
+            let (terms, posts) = merge_moved(&parts, moved, Vec::new(), Vec::new())?;
+            paths.extend(put_postings(store, table, block, column, terms, posts)?);
+        }
+        Ok(paths)
+    }
+}
+
+fn put_postings(
+    store: &dyn Store,
+    table: &str,
+    block: &TableBlock,
+    column: &str,
+    terms: Vec<u8>,
+    postings: Vec<u8>,
+) -> Result<Vec<PathBuf>> {
+    let terms_path = term_path(table, block.name(), column);
+    store.put(terms_path.to_str().unwrap(), terms)?;
+    let postings_path = postings_path(table, block.name(), column);
+    store.put(postings_path.to_str().unwrap(), postings)?;
+    Ok(vec![terms_path, postings_path])
 }
 
 #[cfg(test)]
@@ -93,4 +375,278 @@ mod tests {
     //     tokens,
     //     vec!["123", "234", "345", "456", "567", "678", "789"]
     // );
+
+    #[test]
+    fn term_dict_lookup_test() {
+        let mut builder = TermDictBuilder::new(vec![]).unwrap();
+        for term in &["db-01", "db-02", "web-01", "web-02", "worker"] {
+            builder.insert(term).unwrap();
+        }
+        let dict = TermDict::new(builder.build().unwrap()).unwrap();
+        fn terms<A: Automaton>(stream: Stream<'_, A>) -> Vec<String> {
+            stream.into_str_keys().unwrap()
+        }
+
+        assert_eq!(dict.len(), 5);
+        assert_eq!(dict.get("web-01"), Some(2));
+        assert_eq!(dict.get("web"), None);
+        assert!(dict.contains("worker"));
+
+        assert_eq!(terms(dict.prefix("web")), vec!["web-01", "web-02"]);
+        assert_eq!(terms(dict.prefix("x")), Vec::<String>::new());
+        assert_eq!(
+            terms(dict.range("db-02".."web-02")),
+            vec!["db-02", "web-01"]
+        );
+        assert_eq!(terms(dict.range::<&str, _>(.."db-02")), vec!["db-01"]);
+        assert_eq!(terms(dict.range("web-02"..)), vec!["web-02", "worker"]);
+
+        // typo tolerant hostname lookup
+        assert_eq!(terms(dict.fuzzy("web-1", 1).unwrap()), vec!["web-01"]);
+        assert_eq!(terms(dict.fuzzy("wrker", 1).unwrap()), vec!["worker"]);
+        // swapped letters are two edits
+        assert_eq!(
+            terms(dict.fuzzy("wbe-01", 1).unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            terms(dict.fuzzy("wbe-01", 2).unwrap()),
+            vec!["db-01", "web-01"]
+        );
+    }
+
+    #[test]
+    fn term_dict_merge_test() {
+        let dict = |terms: &[&str]| {
+            let mut builder = TermDictBuilder::new(vec![]).unwrap();
+            for term in terms {
+                builder.insert(term).unwrap();
+            }
+            TermDict::new(builder.build().unwrap()).unwrap()
+        };
+        let dicts = vec![dict(&["get", "put"]), dict(&["del", "get", "pos"])];
+
+        let mut builder = TermDictBuilder::new(vec![]).unwrap();
+        builder.merge(&dicts).unwrap();
+        let merged = TermDict::new(builder.build().unwrap()).unwrap();
+        let terms = merged.stream().into_str_vec().unwrap();
+        assert_eq!(
+            terms,
+            vec![
+                ("del".to_owned(), 0),
+                ("get".to_owned(), 1),
+                ("pos".to_owned(), 2),
+                ("put".to_owned(), 3)
+            ]
+        );
+
+        // values can be derived from the merged ones
+        let mut builder = TermDictBuilder::new(vec![]).unwrap();
+        builder
+            .merge_with(&dicts, |_, values| {
+                Ok(Some(values.iter().map(|v| 1 << v.index).sum()))
+            })
+            .unwrap();
+        let merged = TermDict::new(builder.build().unwrap()).unwrap();
+        assert_eq!(merged.get("get"), Some(3));
+        assert_eq!(merged.get("del"), Some(2));
+        assert_eq!(merged.get("put"), Some(1));
+    }
+
+    #[test]
+    fn term_indexer_test() {
+        use arrow::array::Int32Array;
+        use std::sync::Arc;
+        use store::builder::SchemaBuilder;
+        use store::schema::FieldType;
+
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("path", FieldType::Str)
+            .field("useragent", FieldType::Str)
+            .analyzed_field("message", FieldType::Str, "words")
+            .build()
+            .unwrap();
+        let batch = RecordBatch::try_new(
+            schema.arrow_schema(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2])),
+                Arc::new(StringArray::from(vec!["/index", "/login"])),
+                Arc::new(StringArray::from(vec!["curl/7.68", "Mozilla/5.0"])),
+                Arc::new(StringArray::from(vec!["Page served", "The login failed"])),
+            ],
+        )
+        .unwrap();
+        let block = TableBlock::write(
+            &store,
+            "term_table",
+            "block",
+            schema,
+            std::slice::from_ref(&batch),
+        )
+        .unwrap();
+
+        // unknown analyzers fail the indexing
+        assert!(TermIndexer::new()
+            .unwrap()
+            .index(&store, "term_table", &block, std::slice::from_ref(&batch))
+            .is_err());
+
+        let words = Analyzer::new(crate::tokenizer::WordTokenizer)
+            .filter(crate::analyzer::LowerCaser)
+            .filter(crate::analyzer::StopWords::english());
+        let paths = TermIndexer::new()
+            .unwrap()
+            .analyzer("words", words)
+            .index(&store, "term_table", &block, &[batch])
+            .unwrap();
+        // only the indexed columns get postings
+        assert_eq!(
+            paths,
+            vec![
+                term_path("term_table", "block", "path"),
+                postings_path("term_table", "block", "path"),
+                term_path("term_table", "block", "message"),
+                postings_path("term_table", "block", "message")
+            ]
+        );
+        let open = |column: &str| {
+            crate::postings::PostingsReader::open(&store, "term_table", "block", column).unwrap()
+        };
+        let rows = |reader: &crate::postings::PostingsReader, term: &str| {
+            reader
+                .get(term)
+                .unwrap()
+                .map(|b| b.iter().collect::<Vec<_>>())
+        };
+        let path = open("path");
+        assert_eq!(rows(&path, "ind"), Some(vec![0]));
+        assert_eq!(rows(&path, "gin"), Some(vec![1]));
+        assert_eq!(rows(&path, "/in"), Some(vec![0]));
+        assert_eq!(rows(&path, "xyz"), None);
+        assert_eq!(rows(&path, "cur"), None);
+
+        let message = open("message");
+        assert_eq!(rows(&message, "login"), Some(vec![1]));
+        assert_eq!(rows(&message, "page"), Some(vec![0]));
+        assert_eq!(rows(&message, "the"), None);
+        assert_eq!(message.terms().len(), 4);
+
+        store.clean("term_table").unwrap();
+    }
+
+    #[test]
+    fn term_indexer_merge_test() {
+        use arrow::array::Int32Array;
+        use store::builder::SchemaBuilder;
+        use store::compaction::{CompactionPolicy, Compactor};
+        use store::manifest::TableEdit;
+        use store::schema::FieldType;
+        use store::table::Table;
+
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        store.clean("merge_table").unwrap();
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("path", FieldType::Str)
+            .build()
+            .unwrap();
+        let table = Table::new("merge_table", schema.clone());
+        let indexer = Arc::new(TermIndexer::new().unwrap());
+
+        let inputs = [
+            ("a", vec![5, 1], vec!["/index", "/login"]),
+            ("b", vec![3, 0], vec!["/api/login", "/index"]),
+        ];
+        for (name, times, paths) in &inputs {
+            let batch = RecordBatch::try_new(
+                schema.arrow_schema(),
+                vec![
+                    Arc::new(Int32Array::from(times.clone())),
+                    Arc::new(StringArray::from(paths.clone())),
+                ],
+            )
+            .unwrap();
+            let mut block = TableBlock::write(
+                &store,
+                table.name(),
+                name,
+                schema.clone(),
+                std::slice::from_ref(&batch),
+            )
+            .unwrap();
+            for path in indexer
+                .index(&store, table.name(), &block, &[batch])
+                .unwrap()
+            {
+                block.add_index(path);
+            }
+            table
+                .commit(&store, TableEdit::new().add_block(block))
+                .unwrap();
+        }
+
+        let compactor =
+            Compactor::new(CompactionPolicy::Leveled { target_rows: 100 }).indexer(indexer);
+        assert_eq!(compactor.compact(&store, &table).unwrap(), 1);
+        let merged = table.snapshot().blocks()[0].clone();
+
+        // rows sorted by time: /index(0) /login(1) /api/login(3) /index(5)
+        let postings =
+            crate::postings::PostingsReader::open(&store, table.name(), merged.name(), "path")
+                .unwrap();
+        let rows = |term: &str| {
+            postings
+                .get(term)
+                .unwrap()
+                .map(|b| b.iter().collect::<Vec<_>>())
+        };
+        assert_eq!(rows("ind"), Some(vec![0, 3]));
+        assert_eq!(rows("log"), Some(vec![1, 2]));
+        assert_eq!(rows("api"), Some(vec![2]));
+
+        // same as indexing the merged rows
+        let batches = merged.read(&store, None).unwrap();
+        let (terms, posts) = {
+            let reindexed = Table::new("reindex_table", schema);
+            let mut block = TableBlock::write(
+                &store,
+                reindexed.name(),
+                "block",
+                merged.schema().clone(),
+                &batches,
+            )
+            .unwrap();
+            for path in TermIndexer::new()
+                .unwrap()
+                .index(&store, reindexed.name(), &block, &batches)
+                .unwrap()
+            {
+                block.add_index(path);
+            }
+            let reader =
+                crate::postings::PostingsReader::open(&store, reindexed.name(), "block", "path")
+                    .unwrap();
+            let terms = reader.terms().stream().into_str_keys().unwrap();
+            let posts = terms
+                .iter()
+                .map(|t| reader.get(t).unwrap().unwrap())
+                .collect::<Vec<_>>();
+            (terms, posts)
+        };
+        assert_eq!(postings.terms().stream().into_str_keys().unwrap(), terms);
+        for (term, posting) in terms.iter().zip(posts) {
+            assert_eq!(postings.get(term).unwrap(), Some(posting));
+        }
+
+        store.clean("merge_table").unwrap();
+        store.clean("reindex_table").unwrap();
+    }
 }
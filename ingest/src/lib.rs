@@ -1,4 +1,10 @@
+pub mod analyzer;
+pub mod flush;
 pub mod fst;
+pub mod memtable;
+pub mod postings;
+pub mod row;
+pub mod spill;
 pub mod tokenizer;
 use anyhow::Result;
 use std::path::PathBuf;
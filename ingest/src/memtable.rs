@@ -0,0 +1,277 @@
+//! In memory buffer for rows that are in the wal but not yet in a block
+//!
+//! Rows are appended to arrow builders per table and cut into record
+//! batches of `BATCH_SIZE` rows, so flushing is just writing batches out.
+
+use crate::row::{Row, Value};
+use anyhow::{anyhow, Result};
+use arrow::array::{ArrayRef, Int32Builder, StringBuilder};
+use arrow::datatypes::{DataType as ArrowDataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use store::schema::Schema;
+
+pub const BATCH_SIZE: usize = 8 * 1024;
+
+enum ColumnBuilder {
+    Int(Int32Builder),
+    Str(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(data_type: &ArrowDataType) -> Result<Self> {
+        match data_type {
+            ArrowDataType::Int32 => Ok(ColumnBuilder::Int(Int32Builder::new(BATCH_SIZE))),
+            ArrowDataType::Utf8 => Ok(ColumnBuilder::Str(StringBuilder::new(BATCH_SIZE))),
+            _ => Err(anyhow!("Unsupported data type({})", data_type)),
+        }
+    }
+
+    fn append(&mut self, value: &Value) -> Result<()> {
+        match (self, value) {
+            (ColumnBuilder::Int(b), Value::Int(v)) => b.append_value(*v)?,
+            (ColumnBuilder::Int(b), Value::Null) => b.append_null()?,
+            (ColumnBuilder::Str(b), Value::Str(v)) => b.append_value(v)?,
+            (ColumnBuilder::Str(b), Value::Null) => b.append_null()?,
+            (_, value) => return Err(anyhow!("Type mismatch for value {:?}", value)),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Int(b) => Arc::new(b.finish()),
+            ColumnBuilder::Str(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+struct TableBuffer {
+    schema: Schema,
+    arrow_schema: SchemaRef,
+    builders: Vec<ColumnBuilder>,
+    rows: usize,
+    batches: Vec<RecordBatch>,
+}
+
+impl TableBuffer {
+    fn new(schema: &Schema) -> Result<Self> {
+        let builders = schema
+            .iter()
+            .map(|field| ColumnBuilder::new(field.data_type()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            schema: schema.clone(),
+            arrow_schema: schema.arrow_schema(),
+            builders,
+            rows: 0,
+            batches: vec![],
+        })
+    }
+
+    fn push(&mut self, row: &Row) -> Result<()> {
+        for (builder, value) in self.builders.iter_mut().zip(row.values.iter()) {
+            builder.append(value)?;
+        }
+        self.rows += 1;
+        if self.rows == BATCH_SIZE {
+            self.finish_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish_batch(&mut self) -> Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let columns = self.builders.iter_mut().map(|b| b.finish()).collect();
+        self.batches
+            .push(RecordBatch::try_new(self.arrow_schema.clone(), columns)?);
+        self.rows = 0;
+        Ok(())
+    }
+}
+
+/// Rows of a memtable, ready to be written as blocks
+pub struct TableBatches {
+    pub table: String,
+    pub schema: Schema,
+    pub batches: Vec<RecordBatch>,
+}
+
+#[derive(Default)]
+pub struct MemTable {
+    tables: BTreeMap<String, TableBuffer>,
+    rows: usize,
+    size: usize,
+    min_lsn: Option<u64>,
+    max_lsn: Option<u64>,
+}
+
+impl MemTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks that `row` fits `schema`, so bad rows can be rejected before
+    /// they are logged
+    pub fn validate(schema: &Schema, row: &Row) -> Result<()> {
+        let fields = schema.iter().collect::<Vec<_>>();
+        if fields.len() != row.values.len() {
+            return Err(anyhow!(
+                "Table {} has {} columns, row has {} values",
+                row.table,
+                fields.len(),
+                row.values.len()
+            ));
+        }
+
+        for (field, value) in fields.iter().zip(row.values.iter()) {
+            let ok = match (field.data_type(), value) {
+                (_, Value::Null) => field.is_nullable(),
+                (ArrowDataType::Int32, Value::Int(_)) => true,
+                (ArrowDataType::Utf8, Value::Str(_)) => true,
+                _ => false,
+            };
+            if !ok {
+                return Err(anyhow!(
+                    "Invalid value {:?} for column: {}",
+                    value,
+                    field.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, lsn: u64, schema: &Schema, row: &Row) -> Result<()> {
+        Self::validate(schema, row)?;
+
+        if !self.tables.contains_key(&row.table) {
+            self.tables
+                .insert(row.table.clone(), TableBuffer::new(schema)?);
+        }
+        self.tables.get_mut(&row.table).unwrap().push(row)?;
+
+        self.rows += 1;
+        self.size += row_size(row);
+        self.min_lsn = Some(self.min_lsn.map_or(lsn, |min| min.min(lsn)));
+        self.max_lsn = Some(self.max_lsn.map_or(lsn, |max| max.max(lsn)));
+        Ok(())
+    }
+
+    /// number of buffered rows
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// rough number of bytes buffered
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn min_lsn(&self) -> Option<u64> {
+        self.min_lsn
+    }
+
+    pub fn max_lsn(&self) -> Option<u64> {
+        self.max_lsn
+    }
+
+    /// Rows of `table` so far, the pending ones are cut into a batch
+    pub fn batches(&mut self, table: &str) -> Result<Vec<RecordBatch>> {
+        match self.tables.get_mut(table) {
+            Some(buffer) => {
+                buffer.finish_batch()?;
+                Ok(buffer.batches.clone())
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn into_batches(self) -> Result<Vec<TableBatches>> {
+        let mut tables = vec![];
+        for (table, mut buffer) in self.tables {
+            buffer.finish_batch()?;
+            tables.push(TableBatches {
+                table,
+                schema: buffer.schema,
+                batches: buffer.batches,
+            });
+        }
+        Ok(tables)
+    }
+}
+
+fn row_size(row: &Row) -> usize {
+    row.values
+        .iter()
+        .map(|v| match v {
+            Value::Null => 1,
+            Value::Int(_) => 4,
+            Value::Str(s) => s.len() + 4,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int32Array, StringArray};
+    use pretty_assertions::assert_eq;
+    use store::builder::SchemaBuilder;
+    use store::schema::FieldType;
+
+    fn schema() -> Schema {
+        SchemaBuilder::new()
+            .timestamp()
+            .field("status", FieldType::Str)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn memtable_insert_test() {
+        let schema = schema();
+        let mut mem = MemTable::new();
+        for i in 0..(BATCH_SIZE + 10) as i32 {
+            let row = Row::new("apache", vec![Value::Int(i), Value::Str("200".into())]);
+            mem.insert(i as u64 + 1, &schema, &row).unwrap();
+        }
+
+        let bad = Row::new("apache", vec![Value::Str("oops".into()), Value::Null]);
+        assert!(mem.insert(0, &schema, &bad).is_err());
+
+        assert_eq!(mem.len(), BATCH_SIZE + 10);
+        assert_eq!(mem.min_lsn(), Some(1));
+        assert_eq!(mem.max_lsn(), Some(BATCH_SIZE as u64 + 10));
+
+        let tables = mem.into_batches().unwrap();
+        assert_eq!(tables.len(), 1);
+        let batches = &tables[0].batches;
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+            vec![BATCH_SIZE, 10]
+        );
+
+        let time = batches[1]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert_eq!(time.value(0), BATCH_SIZE as i32);
+        let status = batches[1]
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(status.value(9), "200");
+    }
+}
@@ -0,0 +1,362 @@
+//! Posting lists, which rows of a block hold a term
+//!
+//! Kept as two files next to the block for every indexed column: the term
+//! dictionary (`.term`), an fst mapping every term to the offset of its
+//! posting list, and the posting lists (`.post`), serialized `RoaringBitmap`s
+//! of row ids laid out back to back in term order. Row ids are row numbers
+//! within the block.
+//!
+//! Readers either load both files or mmap them, to merge more of them than
+//! fits in memory.
+
+use crate::fst::{TermDict, TermDictBuilder, TERM_EXT};
+use crate::tokenizer::{trigram_bytes, ByteTrigrams};
+use anyhow::{anyhow, Result};
+use memmap2::Mmap;
+use roaring::RoaringBitmap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Write;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use store::Store;
+
+pub const POSTINGS_EXT: &str = "post";
+
+/// `<table>/<block>.<column>.term`
+pub fn term_path(table: &str, block: &str, column: &str) -> PathBuf {
+    PathBuf::from(table).join(format!("{}.{}.{}", block, column, TERM_EXT))
+}
+
+/// `<table>/<block>.<column>.post`
+pub fn postings_path(table: &str, block: &str, column: &str) -> PathBuf {
+    PathBuf::from(table).join(format!("{}.{}.{}", block, column, POSTINGS_EXT))
+}
+
+#[derive(Default)]
+pub struct PostingsBuilder {
+    postings: BTreeMap<Vec<u8>, RoaringBitmap>,
+    /// rows of the ascii trigrams packed by `ByteTrigrams`, appending to a
+    /// vec is much cheaper than inserting in a bitmap. Moved to `postings` on
+    /// build.
+    trigrams: HashMap<u32, Vec<u32>>,
+}
+
+impl PostingsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<K: AsRef<[u8]>>(&mut self, term: K, row: u32) {
+        match self.postings.get_mut(term.as_ref()) {
+            Some(bitmap) => {
+                bitmap.insert(row);
+            }
+            None => {
+                let mut bitmap = RoaringBitmap::new();
+                bitmap.insert(row);
+                self.postings.insert(term.as_ref().to_vec(), bitmap);
+            }
+        }
+    }
+
+    /// Inserts the lowercased trigrams of `value` like the `trigram` analyzer
+    /// would, without allocating. Returns `false`, inserting nothing, for
+    /// values that aren't ascii or are too short to have a trigram.
+    pub fn insert_ascii_trigrams(&mut self, value: &str, row: u32) -> bool {
+        if value.len() < 3 || !value.is_ascii() {
+            return false;
+        }
+        for gram in ByteTrigrams::new(value.as_bytes()) {
+            let rows = self.trigrams.entry(gram).or_default();
+            if rows.last() != Some(&row) {
+                rows.push(row);
+            }
+        }
+        true
+    }
+
+    /// number of distinct terms
+    pub fn len(&self) -> usize {
+        let postings = &self.postings;
+        let trigrams = self
+            .trigrams
+            .keys()
+            .filter(|gram| !postings.contains_key(&trigram_bytes(**gram)[..]))
+            .count();
+        postings.len() + trigrams
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.postings.is_empty() && self.trigrams.is_empty()
+    }
+
+    /// Rough number of bytes held, to spill before running out of memory
+    pub fn memory_size(&self) -> usize {
+        let postings: usize = self
+            .postings
+            .iter()
+            .map(|(term, bitmap)| {
+                size_of::<(Vec<u8>, RoaringBitmap)>() + term.capacity() + bitmap.serialized_size()
+            })
+            .sum();
+        let trigrams: usize = self
+            .trigrams
+            .values()
+            .map(|rows| size_of::<(u32, Vec<u32>)>() + rows.capacity() * size_of::<u32>())
+            .sum();
+        postings + trigrams
+    }
+
+    fn into_postings(mut self) -> BTreeMap<Vec<u8>, RoaringBitmap> {
+        for (gram, rows) in self.trigrams {
+            let bitmap = self
+                .postings
+                .entry(trigram_bytes(gram).to_vec())
+                .or_default();
+            bitmap.extend(rows);
+        }
+        self.postings
+    }
+
+    /// Writes the term dictionary to `terms` & the posting lists to `postings`
+    pub fn build<T: Write, P: Write>(self, terms: T, mut postings: P) -> Result<(T, P)> {
+        let mut dict = TermDictBuilder::new(terms)?;
+        let mut offset = 0;
+        for (term, bitmap) in self.into_postings() {
+            dict.insert_value(&term, offset)?;
+            bitmap.serialize_into(&mut postings)?;
+            offset += bitmap.serialized_size() as u64;
+        }
+        Ok((dict.build()?, postings))
+    }
+}
+
+/// Merges the postings of several blocks into one, the rows of each shifted
+/// by its offset: the row count of the blocks before it to concatenate them,
+/// 0 to union postings of the same rows.
+pub fn merge<D: AsRef<[u8]>, T: Write, P: Write>(
+    parts: &[(&PostingsReader<D>, u32)],
+    terms: T,
+    postings: P,
+) -> Result<(T, P)> {
+    merge_by(parts, terms, postings, |bitmap, posting, rows| {
+        if rows == 0 {
+            *bitmap |= posting;
+        } else {
+            bitmap.extend(posting.iter().map(|row| row + rows));
+        }
+        Ok(())
+    })
+}
+
+/// Merges the postings of blocks compacted into one whose rows were
+/// reordered: row `r` of a part moves to `moved[offset + r]`, `offset` being
+/// the row count of the parts before it, as in `merge`.
+pub fn merge_moved<D: AsRef<[u8]>, T: Write, P: Write>(
+    parts: &[(&PostingsReader<D>, u32)],
+    moved: &[usize],
+    terms: T,
+    postings: P,
+) -> Result<(T, P)> {
+    merge_by(parts, terms, postings, |bitmap, posting, rows| {
+        for row in posting {
+            let to = moved
+                .get((rows + row) as usize)
+                .ok_or_else(|| anyhow!("Row {} isn't in the merged block", rows + row))?;
+            bitmap.insert(u32::try_from(*to)?);
+        }
+        Ok(())
+    })
+}
+
+/// Merges the term dictionaries of `parts`, `add` adding the posting of a
+/// part & its offset to the merged one
+fn merge_by<D, T, P, F>(
+    parts: &[(&PostingsReader<D>, u32)],
+    terms: T,
+    mut postings: P,
+    mut add: F,
+) -> Result<(T, P)>
+where
+    D: AsRef<[u8]>,
+    T: Write,
+    P: Write,
+    F: FnMut(&mut RoaringBitmap, RoaringBitmap, u32) -> Result<()>,
+{
+    let mut dict = TermDictBuilder::new(terms)?;
+    let mut offset = 0;
+    dict.merge_with(parts.iter().map(|(r, _)| r.terms()), |_, values| {
+        let mut bitmap = RoaringBitmap::new();
+        for value in values {
+            let (reader, rows) = parts[value.index];
+            add(&mut bitmap, reader.posting(value.value)?, rows)?;
+        }
+        bitmap.serialize_into(&mut postings)?;
+        let value = offset;
+        offset += bitmap.serialized_size() as u64;
+        Ok(Some(value))
+    })?;
+    Ok((dict.build()?, postings))
+}
+
+pub struct PostingsReader<D = Vec<u8>> {
+    terms: TermDict<D>,
+    postings: D,
+}
+
+impl PostingsReader {
+    pub fn new(terms: Vec<u8>, postings: Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            terms: TermDict::new(terms)?,
+            postings,
+        })
+    }
+
+    /// Loads the term dictionary & posting lists of a block column
+    pub fn open(store: &dyn Store, table: &str, block: &str, column: &str) -> Result<Self> {
+        let terms = TermDict::open(store, table, block, column)?;
+        let mut postings = vec![];
+        let path = postings_path(table, block, column);
+        store.get(path.to_str().unwrap(), &mut postings)?;
+        Ok(Self { terms, postings })
+    }
+}
+
+impl PostingsReader<Mmap> {
+    /// Maps the term dictionary & posting lists of a block column, only the
+    /// pages read are loaded. Neither file may be rewritten while the reader
+    /// lives, as with runs being merged.
+    pub fn mmap(store: &dyn Store, table: &str, block: &str, column: &str) -> Result<Self> {
+        let terms = mmap(store, &term_path(table, block, column))?;
+        let postings = mmap(store, &postings_path(table, block, column))?;
+        Ok(Self {
+            terms: TermDict::new(terms)?,
+            postings,
+        })
+    }
+}
+
+impl<D: AsRef<[u8]>> PostingsReader<D> {
+    /// term -> posting offset
+    pub fn terms(&self) -> &TermDict<D> {
+        &self.terms
+    }
+
+    /// Rows holding `term`, `None` if no row does
+    pub fn get<K: AsRef<[u8]>>(&self, term: K) -> Result<Option<RoaringBitmap>> {
+        match self.terms.get(term) {
+            Some(offset) => Ok(Some(self.posting(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// posting list at `offset`, as found in the term dictionary
+    pub fn posting(&self, offset: u64) -> Result<RoaringBitmap> {
+        let data = self
+            .postings
+            .as_ref()
+            .get(offset as usize..)
+            .ok_or_else(|| anyhow!("Posting offset {} is out of bounds", offset))?;
+        Ok(RoaringBitmap::deserialize_from(data)?)
+    }
+}
+
+/// Maps the file at `path` in `store`
+pub(crate) fn mmap(store: &dyn Store, path: &Path) -> Result<Mmap> {
+    let file = File::open(store.root().join(path))?;
+    // Safety: callers don't rewrite what they map, see `PostingsReader::mmap`
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn postings_build_and_read_test() {
+        let mut builder = PostingsBuilder::new();
+        builder.insert("get", 0);
+        builder.insert("pos", 1);
+        builder.insert("get", 2);
+        builder.insert("get", 2);
+        assert_eq!(builder.len(), 2);
+
+        let (terms, postings) = builder.build(vec![], vec![]).unwrap();
+        let reader = PostingsReader::new(terms, postings).unwrap();
+
+        let get = reader.get("get").unwrap().unwrap();
+        assert_eq!(get.iter().collect::<Vec<_>>(), vec![0, 2]);
+        let pos = reader.get("pos").unwrap().unwrap();
+        assert_eq!(pos.iter().collect::<Vec<_>>(), vec![1]);
+        assert!(reader.get("put").unwrap().is_none());
+
+        // offsets point right after the previous posting list
+        assert_eq!(reader.terms().get("get"), Some(0));
+        assert_eq!(
+            reader.terms().get("pos"),
+            Some(get.serialized_size() as u64)
+        );
+        assert!(reader.posting(1 << 20).is_err());
+    }
+
+    #[test]
+    fn ascii_trigrams_test() {
+        let analyzer = crate::analyzer::Analyzer::trigram();
+        let mut fast = PostingsBuilder::new();
+        let mut slow = PostingsBuilder::new();
+        for (row, value) in ["GET /Index", "Ünïcode", "ab"].iter().enumerate() {
+            if !fast.insert_ascii_trigrams(value, row as u32) {
+                analyzer.analyze(value, |token| fast.insert(token, row as u32));
+            }
+            analyzer.analyze(value, |token| slow.insert(token, row as u32));
+        }
+        assert!(!fast.insert_ascii_trigrams("Ünïcode", 0));
+        assert_eq!(fast.len(), slow.len());
+        assert_eq!(fast.into_postings(), slow.into_postings());
+    }
+
+    #[test]
+    fn postings_merge_test() {
+        let reader = |rows: &[(&str, u32)]| {
+            let mut builder = PostingsBuilder::new();
+            for (term, row) in rows {
+                builder.insert(term, *row);
+            }
+            let (terms, postings) = builder.build(vec![], vec![]).unwrap();
+            PostingsReader::new(terms, postings).unwrap()
+        };
+        let a = reader(&[("get", 0), ("put", 1)]);
+        let b = reader(&[("del", 0), ("get", 1)]);
+        let rows = |reader: &PostingsReader, term: &str| {
+            reader
+                .get(term)
+                .unwrap()
+                .map(|b| b.iter().collect::<Vec<_>>())
+        };
+
+        // b's rows come after a's two
+        let (terms, postings) = merge(&[(&a, 0), (&b, 2)], vec![], vec![]).unwrap();
+        let merged = PostingsReader::new(terms, postings).unwrap();
+        assert_eq!(merged.terms().len(), 3);
+        assert_eq!(rows(&merged, "get"), Some(vec![0, 3]));
+        assert_eq!(rows(&merged, "put"), Some(vec![1]));
+        assert_eq!(rows(&merged, "del"), Some(vec![2]));
+
+        // same rows, postings are unioned
+        let (terms, postings) = merge(&[(&a, 0), (&b, 0)], vec![], vec![]).unwrap();
+        let merged = PostingsReader::new(terms, postings).unwrap();
+        assert_eq!(rows(&merged, "get"), Some(vec![0, 1]));
+
+        // rows of a & b sorted back to front
+        let moved = [3, 2, 1, 0];
+        let (terms, postings) = merge_moved(&[(&a, 0), (&b, 2)], &moved, vec![], vec![]).unwrap();
+        let merged = PostingsReader::new(terms, postings).unwrap();
+        assert_eq!(rows(&merged, "get"), Some(vec![0, 3]));
+        assert_eq!(rows(&merged, "put"), Some(vec![2]));
+        assert_eq!(rows(&merged, "del"), Some(vec![1]));
+        assert!(merge_moved(&[(&a, 0), (&b, 2)], &moved[..3], vec![], vec![]).is_err());
+    }
+}
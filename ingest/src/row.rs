@@ -0,0 +1,116 @@
+//! A row as it is logged in the wal
+//!
+//! Encoded as `table_len (u16) | table | value_count (u16) | values...`,
+//! each value being a tag byte followed by its payload.
+
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryInto;
+use std::io::Read;
+
+const NULL: u8 = 0;
+const INT: u8 = 1;
+const STR: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Int(i32),
+    Str(String),
+}
+
+/// Values of one row of `table`, in the column order of its schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub table: String,
+    pub values: Vec<Value>,
+}
+
+impl Row {
+    pub fn new(table: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            table: table.into(),
+            values,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        write_str_u16(&mut buf, &self.table)?;
+        buf.write_u16::<LittleEndian>(self.values.len().try_into()?)?;
+        for value in &self.values {
+            match value {
+                Value::Null => buf.push(NULL),
+                Value::Int(v) => {
+                    buf.push(INT);
+                    buf.write_i32::<LittleEndian>(*v)?;
+                }
+                Value::Str(v) => {
+                    buf.push(STR);
+                    buf.write_u32::<LittleEndian>(v.len().try_into()?)?;
+                    buf.extend_from_slice(v.as_bytes());
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        let table = read_str_u16(&mut buf)?;
+        let count = buf.read_u16::<LittleEndian>()?;
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let value = match buf.read_u8()? {
+                NULL => Value::Null,
+                INT => Value::Int(buf.read_i32::<LittleEndian>()?),
+                STR => {
+                    let len = buf.read_u32::<LittleEndian>()? as usize;
+                    Value::Str(read_string(&mut buf, len)?)
+                }
+                tag => return Err(anyhow!("Unknown value tag: {}", tag)),
+            };
+            values.push(value);
+        }
+        Ok(Self { table, values })
+    }
+}
+
+fn write_str_u16(buf: &mut Vec<u8>, s: &str) -> Result<()> {
+    buf.write_u16::<LittleEndian>(s.len().try_into()?)?;
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn read_str_u16(buf: &mut &[u8]) -> Result<String> {
+    let len = buf.read_u16::<LittleEndian>()? as usize;
+    read_string(buf, len)
+}
+
+fn read_string(buf: &mut &[u8], len: usize) -> Result<String> {
+    let mut bytes = vec![0; len];
+    buf.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn row_encode_decode_test() {
+        let row = Row::new(
+            "apache",
+            vec![
+                Value::Int(1615559160),
+                Value::Str("68.99.50.249".to_owned()),
+                Value::Null,
+                Value::Str("你好，世界！".to_owned()),
+            ],
+        );
+
+        let buf = row.encode().unwrap();
+        assert_eq!(Row::decode(&buf).unwrap(), row);
+        assert!(Row::decode(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Building postings within a memory budget
+//!
+//! Postings are buffered until they take more than the budget, then written
+//! to the store as a sorted run: a term dictionary & posting lists per column,
+//! laid out like a block's (`<dir>/run-<n>.<column>.term`). Runs of a column
+//! are k-way merged into the final term dictionary once everything is read.
+//! Row ids aren't shifted when merging, several builders can spill runs of
+//! the same rows, e.g. one per indexing thread.
+
+use crate::postings::{self, postings_path, term_path, PostingsBuilder, PostingsReader};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use store::Store;
+
+pub struct SpillingBuilder<'a, S> {
+    store: &'a S,
+    dir: &'a str,
+    budget: usize,
+    /// run counter shared by the builders spilling to `dir`
+    next_run: &'a AtomicUsize,
+    postings: BTreeMap<String, PostingsBuilder>,
+    /// column -> runs spilled
+    runs: BTreeMap<String, Vec<String>>,
+}
+
+impl<'a, S: Store> SpillingBuilder<'a, S> {
+    pub fn new(store: &'a S, dir: &'a str, budget: usize, next_run: &'a AtomicUsize) -> Self {
+        Self {
+            store,
+            dir,
+            budget,
+            next_run,
+            postings: BTreeMap::new(),
+            runs: BTreeMap::new(),
+        }
+    }
+
+    /// Postings of `column`
+    pub fn column(&mut self, column: &str) -> &mut PostingsBuilder {
+        if !self.postings.contains_key(column) {
+            self.postings
+                .insert(column.to_owned(), PostingsBuilder::new());
+        }
+        self.postings.get_mut(column).unwrap()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.postings
+            .values()
+            .map(PostingsBuilder::memory_size)
+            .sum()
+    }
+
+    /// Spills if over budget
+    pub fn maybe_spill(&mut self) -> Result<()> {
+        if self.memory_size() > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered postings as a new run
+    pub fn spill(&mut self) -> Result<()> {
+        let postings = mem::take(&mut self.postings);
+        if postings.values().all(PostingsBuilder::is_empty) {
+            return Ok(());
+        }
+        let run = format!("run-{}", self.next_run.fetch_add(1, Ordering::SeqCst));
+        for (column, postings) in postings {
+            if postings.is_empty() {
+                continue;
+            }
+            let (terms, posts) = postings.build(Vec::new(), Vec::new())?;
+            let path = term_path(self.dir, &run, &column);
+            self.store.put(path.to_str().unwrap(), terms)?;
+            let path = postings_path(self.dir, &run, &column);
+            self.store.put(path.to_str().unwrap(), posts)?;
+            self.runs.entry(column).or_default().push(run.clone());
+        }
+        Ok(())
+    }
+
+    /// Spills what's left, returns the runs of every column
+    pub fn finish(mut self) -> Result<BTreeMap<String, Vec<String>>> {
+        self.spill()?;
+        Ok(self.runs)
+    }
+}
+
+/// Merges the runs of `column` into one term dictionary & posting lists,
+/// deleting them. Runs are mmapped & merged a term at a time, so this takes
+/// as little memory as `terms` & `postings` do, e.g. when writing to files.
+pub fn merge_runs<S: Store, T: Write, P: Write>(
+    store: &S,
+    dir: &str,
+    column: &str,
+    runs: &[String],
+    terms: T,
+    postings: P,
+) -> Result<(T, P)> {
+    let readers = runs
+        .iter()
+        .map(|run| PostingsReader::mmap(store, dir, run, column))
+        .collect::<Result<Vec<_>>>()?;
+    let parts = readers.iter().map(|r| (r, 0)).collect::<Vec<_>>();
+    let merged = postings::merge(&parts, terms, postings)?;
+    // unmapped before deleting
+    drop(readers);
+    for run in runs {
+        store.delete(term_path(dir, run, column).to_str().unwrap())?;
+        store.delete(postings_path(dir, run, column).to_str().unwrap())?;
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use store::FSBlobStore;
+
+    #[test]
+    fn spill_and_merge_runs_test() {
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        store.clean("spill_runs").unwrap();
+        let next_run = AtomicUsize::new(0);
+
+        // nothing fits, every maybe_spill writes a run
+        let mut a = SpillingBuilder::new(&store, "spill_runs", 0, &next_run);
+        a.column("message").insert("get", 0);
+        a.column("method").insert("get", 0);
+        a.maybe_spill().unwrap();
+        a.column("message").insert("put", 2);
+        a.maybe_spill().unwrap();
+        a.maybe_spill().unwrap();
+        assert_eq!(a.memory_size(), 0);
+
+        let mut b = SpillingBuilder::new(&store, "spill_runs", 1 << 20, &next_run);
+        b.column("message").insert("get", 1);
+        b.maybe_spill().unwrap();
+        assert!(b.memory_size() > 0);
+
+        let mut runs = a.finish().unwrap();
+        assert_eq!(runs["message"], vec!["run-0", "run-1"]);
+        assert_eq!(runs["method"], vec!["run-0"]);
+        for (column, more) in b.finish().unwrap() {
+            runs.entry(column).or_default().extend(more);
+        }
+        assert_eq!(runs["message"], vec!["run-0", "run-1", "run-2"]);
+
+        let rows = |reader: &PostingsReader, term: &str| {
+            reader
+                .get(term)
+                .unwrap()
+                .map(|b| b.iter().collect::<Vec<_>>())
+        };
+        let (terms, postings) = merge_runs(
+            &store,
+            "spill_runs",
+            "message",
+            &runs["message"],
+            vec![],
+            vec![],
+        )
+        .unwrap();
+        let merged = PostingsReader::new(terms, postings).unwrap();
+        assert_eq!(merged.terms().len(), 2);
+        assert_eq!(rows(&merged, "get"), Some(vec![0, 1]));
+        assert_eq!(rows(&merged, "put"), Some(vec![2]));
+
+        // merged runs are deleted
+        assert!(!store.exist(
+            term_path("spill_runs", "run-1", "message")
+                .to_str()
+                .unwrap()
+        ));
+        assert!(store.exist(term_path("spill_runs", "run-0", "method").to_str().unwrap()));
+
+        store.clean("spill_runs").unwrap();
+    }
+}
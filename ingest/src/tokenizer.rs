@@ -7,7 +7,7 @@ use std::convert::TryFrom;
 
 const MAX_LEN: usize = 15;
 
-#[derive(Debug, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
 pub enum Token {
     Stack(ArrayString<MAX_LEN>),
     Heap(String),
@@ -24,7 +24,7 @@ impl Token {
     }
 
     #[inline]
-    fn to_str(&self) -> &str {
+    pub fn to_str(&self) -> &str {
         match self {
             Token::Stack(ref s) => s.as_ref(),
             Token::Heap(ref s) => s.as_ref(),
@@ -32,6 +32,12 @@ impl Token {
     }
 }
 
+impl From<&str> for Token {
+    fn from(data: &str) -> Self {
+        Token::from_str(data)
+    }
+}
+
 impl PartialEq<&str> for Token {
     fn eq(&self, other: &&str) -> bool {
         &self.to_str() == other
@@ -94,7 +100,7 @@ impl<'a> Iterator for NGramIter<'a> {
 
     //I dont know how this works now!
     fn next(&mut self) -> Option<Self::Item> {
-        if self.offset + self.last_term_len > self.data.len() {
+        if self.offset >= self.data.len() || self.offset + self.last_term_len > self.data.len() {
             return None;
         }
 
@@ -115,12 +121,247 @@ impl<'a> Iterator for NGramIter<'a> {
 
         self.last_term_len = term_len;
 
-        Some(Token::from_str(
-            &self.data[offset..offset + term_len].to_lowercase(),
-        ))
+        Some(Token::from_str(&self.data[offset..offset + term_len]))
+    }
+}
+
+/// Byte n-grams of `data`, borrowed from it, `data` itself when shorter than
+/// `n`. Fold case beforehand with `make_ascii_lowercase` on the buffer.
+pub fn byte_ngrams(data: &[u8], n: usize) -> impl Iterator<Item = &[u8]> {
+    let short = if data.len() < n && !data.is_empty() {
+        Some(data)
+    } else {
+        None
+    };
+    data.windows(n.max(1)).chain(short)
+}
+
+/// Byte trigrams of `data` ascii lowercased on the fly, packed big endian in
+/// the low 24 bits of a `u32` so they sort like their bytes. For ascii text
+/// they're the same terms as the `trigram` analyzer's, without allocating.
+#[derive(Debug)]
+pub struct ByteTrigrams<'a> {
+    data: &'a [u8],
+    pos: usize,
+    gram: u32,
+}
+
+impl<'a> ByteTrigrams<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ByteTrigrams {
+            data,
+            pos: 0,
+            gram: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for ByteTrigrams<'a> {
+    type Item = u32;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.data.len() {
+            let byte = self.data[self.pos].to_ascii_lowercase();
+            self.gram = ((self.gram << 8) | byte as u32) & 0x00ff_ffff;
+            self.pos += 1;
+            if self.pos >= 3 {
+                return Some(self.gram);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // a trigram ends at each of the remaining bytes past the second
+        let n = self.data.len().saturating_sub(self.pos.max(2));
+        (n, Some(n))
+    }
+}
+
+/// bytes of a trigram packed by `ByteTrigrams`
+#[inline]
+pub fn trigram_bytes(gram: u32) -> [u8; 3] {
+    let [_, a, b, c] = gram.to_be_bytes();
+    [a, b, c]
+}
+
+/// Splits on every char `is_sep` holds for, skipping empty pieces
+#[derive(Debug)]
+pub struct SplitIter<'a> {
+    rest: &'a str,
+    is_sep: fn(char) -> bool,
+}
+
+impl<'a> SplitIter<'a> {
+    fn new(data: &'a str, is_sep: fn(char) -> bool) -> Self {
+        SplitIter { rest: data, is_sep }
+    }
+
+    fn next_str(&mut self) -> Option<&'a str> {
+        let start = self.rest.find(|c| !(self.is_sep)(c))?;
+        let rest = &self.rest[start..];
+        let end = rest.find(self.is_sep).unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+impl<'a> Iterator for SplitIter<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_str().map(Token::from_str)
+    }
+}
+
+fn is_word_sep(c: char) -> bool {
+    !c.is_alphanumeric() && c != '_'
+}
+
+fn is_path_sep(c: char) -> bool {
+    c.is_whitespace() || "/?&=#:;,".contains(c)
+}
+
+/// Words, split on whitespace & punctuation
+pub struct WordTokenizer;
+
+impl<'a> Tokenizer<'a> for WordTokenizer {
+    type TokenIterator = SplitIter<'a>;
+    fn tokenize(&self, data: &'a str) -> Self::TokenIterator {
+        SplitIter::new(data, is_word_sep)
+    }
+}
+
+/// Segments of urls & file paths, `/api/v1?id=3` is `api`, `v1`, `id`, `3`
+pub struct PathTokenizer;
+
+impl<'a> Tokenizer<'a> for PathTokenizer {
+    type TokenIterator = SplitIter<'a>;
+    fn tokenize(&self, data: &'a str) -> Self::TokenIterator {
+        SplitIter::new(data, is_path_sep)
+    }
+}
+
+/// Prefixes of `min` to `max` chars of every word, for search as you type
+pub struct EdgeNGramTokenizer {
+    min: usize,
+    max: usize,
+}
+
+impl EdgeNGramTokenizer {
+    pub fn new(min: usize, max: usize) -> Result<Self> {
+        if min < 1 || max < min {
+            return Err(anyhow::anyhow!(
+                "Invalid edge ngram range {}..={}",
+                min,
+                max
+            ));
+        }
+
+        Ok(EdgeNGramTokenizer { min, max })
+    }
+}
+
+impl<'a> Tokenizer<'a> for EdgeNGramTokenizer {
+    type TokenIterator = EdgeNGramIter<'a>;
+    fn tokenize(&self, data: &'a str) -> Self::TokenIterator {
+        EdgeNGramIter {
+            words: SplitIter::new(data, is_word_sep),
+            word: "",
+            len: 0,
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EdgeNGramIter<'a> {
+    words: SplitIter<'a>,
+    word: &'a str,
+    /// chars of the last prefix of `word`
+    len: usize,
+    min: usize,
+    max: usize,
+}
+
+impl<'a> Iterator for EdgeNGramIter<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.len >= self.min && self.len < self.max {
+            if let Some((end, ch)) = self.word.char_indices().nth(self.len) {
+                self.len += 1;
+                return Some(Token::from_str(&self.word[..end + ch.len_utf8()]));
+            }
+        }
+
+        self.word = self.words.next_str()?;
+        match self.word.char_indices().nth(self.min - 1) {
+            Some((end, ch)) => {
+                self.len = self.min;
+                Some(Token::from_str(&self.word[..end + ch.len_utf8()]))
+            }
+            // words shorter than `min` are kept whole
+            None => {
+                self.len = self.max;
+                Some(Token::from_str(self.word))
+            }
+        }
     }
 }
 
+/// Ip addresses & numbers, everything else is dropped
+pub struct NumberTokenizer;
+
+impl<'a> Tokenizer<'a> for NumberTokenizer {
+    type TokenIterator = NumberIter<'a>;
+    fn tokenize(&self, data: &'a str) -> Self::TokenIterator {
+        NumberIter {
+            runs: SplitIter::new(data, |c| !c.is_ascii_digit() && c != '.'),
+            parts: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NumberIter<'a> {
+    runs: SplitIter<'a>,
+    /// pieces of a dotted run that isn't an ip or a decimal, e.g. `1.2.3`
+    parts: Option<std::str::Split<'a, char>>,
+}
+
+impl<'a> Iterator for NumberIter<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(parts) = &mut self.parts {
+                match parts.find(|p| !p.is_empty()) {
+                    Some(part) => return Some(Token::from_str(part)),
+                    None => self.parts = None,
+                }
+            }
+
+            let run = self.runs.next_str()?.trim_matches('.');
+            if run.is_empty() {
+                continue;
+            }
+            let dots = run.matches('.').count();
+            if dots <= 1 || (dots == 3 && is_ipv4(run)) {
+                return Some(Token::from_str(run));
+            }
+            self.parts = Some(run.split('.'));
+        }
+    }
+}
+
+fn is_ipv4(data: &str) -> bool {
+    data.split('.')
+        .all(|octet| !octet.is_empty() && octet.len() <= 3 && octet.parse::<u8>().is_ok())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -136,7 +377,7 @@ mod tests {
                 .tokenize("Abcdef ghij klmno")
                 .collect::<Vec<Token>>(),
             vec![
-                "abc", "bcd", "cde", "def", "ef ", "f g", " gh", "ghi", "hij", "ij ", "j k", " kl",
+                "Abc", "bcd", "cde", "def", "ef ", "f g", " gh", "ghi", "hij", "ij ", "j k", " kl",
                 "klm", "lmn", "mno"
             ]
         );
@@ -146,10 +387,90 @@ mod tests {
             vec!["123", "234", "345", "456", "567", "678", "789"]
         );
 
+        // short values are a single token, empty ones none
+        assert_eq!(tokenizer.tokenize("ab").collect::<Vec<Token>>(), ["ab"]);
+        assert_eq!(tokenizer.tokenize("").count(), 0);
+
         //works on chinese characters too
         assert_eq!(
             tokenizer.tokenize("你好，世界！").collect::<Vec<Token>>(),
             ["你好，", "好，世", "，世界", "世界！"]
         );
     }
+
+    #[test]
+    fn word_and_path_tokenizer_test() {
+        assert_eq!(
+            WordTokenizer
+                .tokenize("GET /index.html failed: conn_reset (peer)")
+                .collect::<Vec<Token>>(),
+            ["GET", "index", "html", "failed", "conn_reset", "peer"]
+        );
+        assert_eq!(WordTokenizer.tokenize(" -- ").count(), 0);
+
+        assert_eq!(
+            PathTokenizer
+                .tokenize("/api/v1/users.json?id=3&sort=name")
+                .collect::<Vec<Token>>(),
+            ["api", "v1", "users.json", "id", "3", "sort", "name"]
+        );
+    }
+
+    #[test]
+    fn edge_ngram_tokenizer_test() {
+        let tokenizer = EdgeNGramTokenizer::new(2, 4).unwrap();
+        assert_eq!(
+            tokenizer.tokenize("nginx a db-01").collect::<Vec<Token>>(),
+            ["ng", "ngi", "ngin", "a", "db", "01"]
+        );
+        assert_eq!(
+            tokenizer.tokenize("héllo").collect::<Vec<Token>>(),
+            ["hé", "hél", "héll"]
+        );
+        assert!(EdgeNGramTokenizer::new(3, 2).is_err());
+    }
+
+    #[test]
+    fn number_tokenizer_test() {
+        assert_eq!(
+            NumberTokenizer
+                .tokenize("from 10.0.0.1:8080 took 1.5ms, v1.2.3. 300.1.1.1")
+                .collect::<Vec<Token>>(),
+            ["10.0.0.1", "8080", "1.5", "1", "2", "3", "300", "1", "1", "1"]
+        );
+        assert_eq!(NumberTokenizer.tokenize("no numbers.").count(), 0);
+    }
+
+    #[test]
+    fn byte_trigrams_test() {
+        let grams = ByteTrigrams::new(b"GET /Index").collect::<Vec<_>>();
+        assert_eq!(grams.len(), 8);
+        assert_eq!(ByteTrigrams::new(b"GET /Index").size_hint(), (8, Some(8)));
+        assert_eq!(&trigram_bytes(grams[0]), b"get");
+        assert_eq!(&trigram_bytes(grams[7]), b"dex");
+
+        // same terms as the ngram tokenizer lowercased, for ascii text
+        let line = "10.0.0.1 - - [GET /api/v1/Users HTTP/1.1] 200";
+        let tokens = NGramTokenizer::new(3)
+            .unwrap()
+            .tokenize(line)
+            .map(|t| t.to_str().to_lowercase().into_bytes())
+            .collect::<Vec<_>>();
+        let bytes = ByteTrigrams::new(line.as_bytes())
+            .map(|g| trigram_bytes(g).to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(bytes, tokens);
+
+        assert_eq!(ByteTrigrams::new(b"ab").count(), 0);
+        assert_eq!(ByteTrigrams::new(b"ab").size_hint(), (0, Some(0)));
+
+        let mut buf = b"ABcd".to_vec();
+        buf.make_ascii_lowercase();
+        assert_eq!(
+            byte_ngrams(&buf, 3).collect::<Vec<_>>(),
+            vec![&b"abc"[..], &b"bcd"[..]]
+        );
+        assert_eq!(byte_ngrams(b"ab", 3).collect::<Vec<_>>(), vec![&b"ab"[..]]);
+        assert_eq!(byte_ngrams(b"", 3).count(), 0);
+    }
 }
@@ -0,0 +1,488 @@
+//! Aggregations of the rows matching a query
+//!
+//! Rows are aggregated into partials, one per time bucket & group, as the
+//! batches scanned out of a block go by. Every block has its own partials,
+//! merged into the result once all of them are searched: counts add up and
+//! quantiles come out of sketches, so no row is kept around.
+
+use crate::ast::{AggFunc, Aggregation};
+use anyhow::{anyhow, Result};
+use arrow::array::{Array, ArrayRef, Float64Array, Int32Array, StringArray, UInt64Array};
+use arrow::datatypes::{
+    DataType as ArrowDataType, Field as ArrowField, Schema as ArrowSchema, SchemaRef,
+};
+use arrow::record_batch::RecordBatch;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use store::schema::TIME_COL_NAME;
+
+/// Relative error of quantiles
+const ACCURACY: f64 = 0.01;
+
+/// Columns an aggregation reads
+pub fn columns(aggregation: &Aggregation) -> Vec<String> {
+    let mut columns = vec![];
+    if aggregation.step.is_some() {
+        columns.push(TIME_COL_NAME.to_owned());
+    }
+    columns.extend(aggregation.by.iter().cloned());
+    if let AggFunc::Quantile(_, field) = &aggregation.func {
+        columns.push(field.clone());
+    }
+    columns
+}
+
+/// Bucket start time if there's a step, the `by` fields, then the value
+pub fn output_schema(aggregation: &Aggregation, schema: &ArrowSchema) -> Result<SchemaRef> {
+    let data_type = |name: &str| {
+        schema
+            .field_with_name(name)
+            .map(|f| f.data_type().clone())
+            .map_err(|_| anyhow!("Unknown field: {}", name))
+    };
+
+    let mut fields = vec![];
+    if aggregation.step.is_some() {
+        data_type(TIME_COL_NAME)?;
+        fields.push(ArrowField::new(TIME_COL_NAME, ArrowDataType::Int32, false));
+    }
+    for name in &aggregation.by {
+        fields.push(ArrowField::new(name, data_type(name)?, true));
+    }
+    let value = match &aggregation.func {
+        AggFunc::Count | AggFunc::TopK(_) => ArrowField::new("count", ArrowDataType::UInt64, false),
+        AggFunc::Rate => ArrowField::new("rate", ArrowDataType::Float64, false),
+        AggFunc::Quantile(_, field) => {
+            if data_type(field)? != ArrowDataType::Int32 {
+                return Err(anyhow!("Field {} isn't a number", field));
+            }
+            ArrowField::new("quantile", ArrowDataType::Float64, true)
+        }
+    };
+    fields.push(value);
+    Ok(Arc::new(ArrowSchema::new(fields)))
+}
+
+/// Value of a `by` field
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Null,
+    Int(i32),
+    Str(String),
+}
+
+/// Aggregate of the rows of a time bucket & group seen so far
+#[derive(Debug, Clone, Default)]
+struct Partial {
+    rows: u64,
+    /// values of the quantile field
+    sketch: Sketch,
+}
+
+impl Partial {
+    fn merge(&mut self, other: Partial) {
+        self.rows += other.rows;
+        self.sketch.merge(other.sketch);
+    }
+}
+
+pub struct Aggregator {
+    aggregation: Aggregation,
+    /// `[start, end)` of the query, rates without a step are over it
+    range: Option<(i32, i32)>,
+    output: SchemaRef,
+    groups: BTreeMap<(Option<i32>, Vec<Key>), Partial>,
+}
+
+impl Aggregator {
+    /// `output` being the `output_schema` of `aggregation`
+    pub fn new(aggregation: &Aggregation, range: Option<(i32, i32)>, output: SchemaRef) -> Self {
+        Self {
+            aggregation: aggregation.clone(),
+            range,
+            output,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `rows` of `batch`, which has the `columns` of the aggregation
+    pub fn update(&mut self, batch: &RecordBatch, rows: &[u32]) -> Result<()> {
+        let column = |name: &str| -> Result<ArrayRef> {
+            let i = batch.schema().index_of(name)?;
+            Ok(batch.column(i).clone())
+        };
+        let step = self
+            .aggregation
+            .step
+            .map(|step| step.as_secs().min(i32::MAX as u64) as i64);
+        let times = match step {
+            Some(_) => Some(column(TIME_COL_NAME)?),
+            None => None,
+        };
+        let times = times.as_ref().map(|c| ints(c, TIME_COL_NAME)).transpose()?;
+        let by = self
+            .aggregation
+            .by
+            .iter()
+            .map(|name| column(name))
+            .collect::<Result<Vec<_>>>()?;
+        let values = match &self.aggregation.func {
+            AggFunc::Quantile(_, field) => Some((column(field)?, field)),
+            _ => None,
+        };
+        let values = values.as_ref().map(|(c, name)| ints(c, name)).transpose()?;
+
+        for &row in rows {
+            let row = row as usize;
+            let bucket = match (times, step) {
+                (Some(times), Some(step)) => {
+                    if times.is_null(row) {
+                        continue;
+                    }
+                    let time = times.value(row) as i64;
+                    Some(i32::try_from(time - time.rem_euclid(step)).unwrap_or(i32::MIN))
+                }
+                _ => None,
+            };
+            let key = by.iter().map(|c| key(c, row)).collect();
+            let partial = self.groups.entry((bucket, key)).or_default();
+            partial.rows += 1;
+            if let Some(values) = values {
+                if values.is_valid(row) {
+                    partial.sketch.insert(values.value(row) as f64);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the partials of `other`, aggregating the same query
+    pub fn merge(&mut self, other: Aggregator) {
+        for (group, partial) in other.groups {
+            self.groups.entry(group).or_default().merge(partial);
+        }
+    }
+
+    /// A row per time bucket & group, in their order, or from the most rows
+    /// to the least for `topk`
+    pub fn finish(self, limit: Option<usize>) -> Result<RecordBatch> {
+        let mut groups = self.groups.into_iter().collect::<Vec<_>>();
+        if let AggFunc::TopK(k) = self.aggregation.func {
+            // stable, so ties stay in group order
+            groups.sort_by(|((a, _), x), ((b, _), y)| a.cmp(b).then(y.rows.cmp(&x.rows)));
+            let mut kept = 0;
+            let mut bucket = None;
+            groups.retain(|((b, _), _)| {
+                if bucket != Some(*b) {
+                    bucket = Some(*b);
+                    kept = 0;
+                }
+                kept += 1;
+                kept <= k
+            });
+        }
+        groups.truncate(limit.unwrap_or(usize::MAX));
+
+        let mut columns: Vec<ArrayRef> = vec![];
+        if self.aggregation.step.is_some() {
+            let times = groups.iter().map(|((t, _), _)| t.unwrap_or_default());
+            columns.push(Arc::new(Int32Array::from(times.collect::<Vec<_>>())));
+        }
+        for (i, name) in self.aggregation.by.iter().enumerate() {
+            let keys = groups.iter().map(|((_, key), _)| &key[i]);
+            let column: ArrayRef = match self.output.field_with_name(name)?.data_type() {
+                ArrowDataType::Int32 => Arc::new(Int32Array::from(
+                    keys.map(|key| match key {
+                        Key::Int(n) => Some(*n),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+                )),
+                _ => Arc::new(StringArray::from(
+                    keys.map(|key| match key {
+                        Key::Str(s) => Some(s.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+                )),
+            };
+            columns.push(column);
+        }
+        let partials = groups.iter().map(|(_, partial)| partial);
+        let value: ArrayRef = match &self.aggregation.func {
+            AggFunc::Count | AggFunc::TopK(_) => Arc::new(UInt64Array::from(
+                partials.map(|p| p.rows).collect::<Vec<_>>(),
+            )),
+            AggFunc::Rate => {
+                let secs = match (self.aggregation.step, self.range) {
+                    (Some(step), _) => step.as_secs() as f64,
+                    (None, Some((start, end))) => (end as f64 - start as f64).max(1.0),
+                    (None, None) => return Err(anyhow!("rate needs a step or a time range")),
+                };
+                Arc::new(Float64Array::from(
+                    partials.map(|p| p.rows as f64 / secs).collect::<Vec<_>>(),
+                ))
+            }
+            AggFunc::Quantile(q, _) => Arc::new(Float64Array::from(
+                partials.map(|p| p.sketch.quantile(*q)).collect::<Vec<_>>(),
+            )),
+        };
+        columns.push(value);
+        Ok(RecordBatch::try_new(self.output, columns)?)
+    }
+}
+
+fn ints<'a>(column: &'a ArrayRef, name: &str) -> Result<&'a Int32Array> {
+    column
+        .as_any()
+        .downcast_ref::<Int32Array>()
+        .ok_or_else(|| anyhow!("Field {} isn't a number", name))
+}
+
+fn key(column: &ArrayRef, row: usize) -> Key {
+    if column.is_null(row) {
+        return Key::Null;
+    }
+    let any = column.as_any();
+    if let Some(strings) = any.downcast_ref::<StringArray>() {
+        Key::Str(strings.value(row).to_owned())
+    } else if let Some(ints) = any.downcast_ref::<Int32Array>() {
+        Key::Int(ints.value(row))
+    } else {
+        Key::Null
+    }
+}
+
+/// Mergeable quantile sketch, counting values in buckets growing
+/// exponentially so that any value of a bucket is within `ACCURACY` of its
+/// middle
+#[derive(Debug, Clone, Default)]
+struct Sketch {
+    /// by `i`, counts of positive values in `(gamma^(i-1), gamma^i]`
+    positive: BTreeMap<i32, u64>,
+    /// same for the absolute negative values
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+}
+
+impl Sketch {
+    fn gamma() -> f64 {
+        (1.0 + ACCURACY) / (1.0 - ACCURACY)
+    }
+
+    fn index(value: f64) -> i32 {
+        (value.ln() / Self::gamma().ln()).ceil() as i32
+    }
+
+    fn value(index: i32) -> f64 {
+        2.0 * Self::gamma().powi(index) / (Self::gamma() + 1.0)
+    }
+
+    fn insert(&mut self, value: f64) {
+        self.count += 1;
+        if value > 0.0 {
+            *self.positive.entry(Self::index(value)).or_default() += 1;
+        } else if value < 0.0 {
+            *self.negative.entry(Self::index(-value)).or_default() += 1;
+        } else {
+            self.zeros += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Sketch) {
+        for (i, n) in other.positive {
+            *self.positive.entry(i).or_default() += n;
+        }
+        for (i, n) in other.negative {
+            *self.negative.entry(i).or_default() += n;
+        }
+        self.zeros += other.zeros;
+        self.count += other.count;
+    }
+
+    /// `None` without values
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q * (self.count - 1) as f64).round() as u64;
+        let mut seen = 0;
+        for (&i, &n) in self.negative.iter().rev() {
+            seen += n;
+            if seen > rank {
+                return Some(-Self::value(i));
+            }
+        }
+        seen += self.zeros;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (&i, &n) in &self.positive {
+            seen += n;
+            if seen > rank {
+                return Some(Self::value(i));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Stage;
+    use crate::parser::parse;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn aggregation(query: &str) -> Aggregation {
+        match parse(query).unwrap().pipeline.pop() {
+            Some(Stage::Aggregate(aggregation)) => aggregation,
+            stage => panic!("not an aggregation {:?}", stage),
+        }
+    }
+
+    #[test]
+    fn aggregate_test() {
+        let schema = ArrowSchema::new(vec![
+            ArrowField::new("time", ArrowDataType::Int32, false),
+            ArrowField::new("ip", ArrowDataType::Utf8, true),
+            ArrowField::new("size", ArrowDataType::Int32, true),
+        ]);
+        let batch = |times: Vec<i32>, ips: Vec<Option<&str>>, sizes: Vec<Option<i32>>| {
+            RecordBatch::try_new(
+                Arc::new(schema.clone()),
+                vec![
+                    Arc::new(Int32Array::from(times)),
+                    Arc::new(StringArray::from(ips)),
+                    Arc::new(Int32Array::from(sizes)),
+                ],
+            )
+            .unwrap()
+        };
+        // two blocks
+        let blocks = vec![
+            batch(
+                vec![0, 30, 59, 60],
+                vec![Some("a"), Some("b"), Some("b"), None],
+                vec![Some(100), Some(200), None, Some(400)],
+            ),
+            batch(
+                vec![61, 62, 130, 131],
+                vec![Some("c"), Some("b"), Some("a"), Some("c")],
+                vec![Some(10), Some(20), Some(30), Some(40)],
+            ),
+        ];
+        let run = |query: &str, range, limit| {
+            let aggregation = aggregation(query);
+            let output = output_schema(&aggregation, &schema).unwrap();
+            let mut result = Aggregator::new(&aggregation, range, output.clone());
+            for batch in &blocks {
+                let mut partial = Aggregator::new(&aggregation, range, output.clone());
+                // the first row of a block doesn't match
+                let rows = (1..batch.num_rows() as u32).collect::<Vec<_>>();
+                partial.update(batch, &rows).unwrap();
+                result.merge(partial);
+            }
+            result.finish(limit).unwrap()
+        };
+        let column = |batch: &RecordBatch, i: usize| -> Vec<String> {
+            let column = batch.column(i);
+            let any = column.as_any();
+            (0..column.len())
+                .map(|row| {
+                    if column.is_null(row) {
+                        "null".to_owned()
+                    } else if let Some(a) = any.downcast_ref::<StringArray>() {
+                        a.value(row).to_owned()
+                    } else if let Some(a) = any.downcast_ref::<Int32Array>() {
+                        a.value(row).to_string()
+                    } else if let Some(a) = any.downcast_ref::<UInt64Array>() {
+                        a.value(row).to_string()
+                    } else {
+                        let a = any.downcast_ref::<Float64Array>().unwrap();
+                        format!("{:.2}", a.value(row))
+                    }
+                })
+                .collect()
+        };
+
+        let counts = run("t | count_over_time(1m) by (ip)", None, None);
+        assert_eq!(column(&counts, 0), ["0", "60", "60", "120", "120"]);
+        assert_eq!(column(&counts, 1), ["b", "null", "b", "a", "c"]);
+        assert_eq!(column(&counts, 2), ["2", "1", "1", "1", "1"]);
+
+        let rates = run("t | rate()", Some((0, 200)), None);
+        assert_eq!(column(&rates, 0), ["0.03"]);
+        let rates = run("t | rate(1m)", None, Some(2));
+        assert_eq!(column(&rates, 0), ["0", "60"]);
+        assert_eq!(column(&rates, 1), ["0.03", "0.03"]);
+
+        let top = run("t | topk(2) by (ip)", None, None);
+        // ties in group order, nulls first
+        assert_eq!(column(&top, 0), ["b", "null"]);
+        assert_eq!(column(&top, 1), ["3", "1"]);
+        let top = run("t | topk(1, 1m) by (ip)", None, None);
+        assert_eq!(column(&top, 0), ["0", "60", "120"]);
+        assert_eq!(column(&top, 1), ["b", "null", "a"]);
+
+        // sizes 200, 400, 20, 30, 40, the null one isn't counted
+        let quantiles = run("t | quantile(0.5, size) by (ip)", None, None);
+        assert_eq!(column(&quantiles, 0), ["null", "a", "b", "c"]);
+        assert_eq!(
+            column(&quantiles, 1),
+            ["399.47", "30.27", "198.37", "40.05"]
+        );
+        let median = run("t | quantile(0.5, size)", None, None);
+        let median = median
+            .column(0)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .value(0);
+        assert!((median - 40.0).abs() / 40.0 <= ACCURACY);
+
+        assert_eq!(
+            columns(&aggregation("t | quantile(0.9, size, 5m) by (ip)")),
+            ["time", "ip", "size"]
+        );
+        assert_eq!(
+            output_schema(&aggregation("t | quantile(0.9, ip)"), &schema)
+                .unwrap_err()
+                .to_string(),
+            "Field ip isn't a number"
+        );
+        assert_eq!(
+            aggregation("t | rate(5m)").step,
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn sketch_test() {
+        let mut a = Sketch::default();
+        let mut b = Sketch::default();
+        for v in -50..=1000 {
+            if v % 2 == 0 {
+                a.insert(v as f64);
+            } else {
+                b.insert(v as f64);
+            }
+        }
+        a.merge(b);
+        assert_eq!(a.count, 1051);
+        for &(q, exact) in &[(0.0, -50.0), (0.5, 475.0), (0.99, 990.0), (1.0, 1000.0)] {
+            let value = a.quantile(q).unwrap();
+            assert!(
+                (value - exact).abs() <= exact.abs() * ACCURACY,
+                "q{} = {}, not {}",
+                q,
+                value,
+                exact
+            );
+        }
+        assert_eq!(Sketch::default().quantile(0.5), None);
+    }
+}
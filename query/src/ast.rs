@@ -0,0 +1,307 @@
+//! Syntax tree of a log query
+//!
+//! ```text
+//! nginx{status="500", useragent=~"curl.*"} |= "timeout" != "health" | latency > 200 | fields message [1h]
+//! ```
+//!
+//! reads: rows of table `nginx` matching every field matcher, whose line
+//! contains `timeout` but not `health`, with a latency above 200, keeping
+//! only `message`, from the last hour. Prefixed with `explain`, the plan of
+//! the query is shown instead of its rows. Queries print back in this syntax.
+//!
+//! ```text
+//! apache |= "GET" | count_over_time(5m) by (status) [1d]
+//! ```
+//!
+//! counts the `GET` rows of each status in 5 minute buckets over the last day.
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    /// `explain`, show the plan instead of running it
+    pub explain: bool,
+    pub table: String,
+    pub matchers: Vec<Matcher>,
+    pub pipeline: Vec<Stage>,
+    pub range: Option<TimeRange>,
+}
+
+/// `field op "value"` in the selector, on string columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matcher {
+    pub field: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp {
+    /// `=`
+    Eq,
+    /// `!=`
+    Neq,
+    /// `=~`, the regex has to match the whole value
+    Re,
+    /// `!~`
+    NotRe,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// `|= "text"`, `!= "text"`, `|~ "regex"`, `!~ "regex"` on every string
+    /// column of a row
+    Line(LineFilter),
+    /// `| field op value`
+    Filter(FieldFilter),
+    /// `| fields a, b`, the columns to return
+    Fields(Vec<String>),
+    /// `| limit n`
+    Limit(usize),
+    /// `| count_over_time(5m) by (status)`, only followed by `limit`
+    Aggregate(Aggregation),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineFilter {
+    pub op: LineOp,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineOp {
+    /// `|=`
+    Contains,
+    /// `!=`
+    NotContains,
+    /// `|~`, the regex can match anywhere in the line
+    Match,
+    /// `!~`
+    NotMatch,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldFilter {
+    pub field: String,
+    pub op: CmpOp,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Neq,
+    Re,
+    NotRe,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i32),
+}
+
+/// Aggregates rows into one per time bucket & group
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregation {
+    pub func: AggFunc,
+    /// width of the time buckets, a single bucket when `None`
+    pub step: Option<Duration>,
+    /// `by (a, b)`, fields whose values make the groups
+    pub by: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AggFunc {
+    /// `count_over_time(step?)`, rows
+    Count,
+    /// `rate(step?)`, rows per second
+    Rate,
+    /// `topk(k, step?)`, the `k` groups with the most rows
+    TopK(usize),
+    /// `quantile(q, field, step?)`, with `q` in `[0, 1]`, of a number field
+    Quantile(f64, String),
+}
+
+/// Rows to search by their time column, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeRange {
+    /// `[5m]`, up to now
+    Last(Duration),
+    /// `[start, end]`, end excluded
+    Between(i32, i32),
+}
+
+impl TimeRange {
+    /// `[start, end)` of the range, `now` being the current time
+    pub fn bounds(&self, now: i32) -> (i32, i32) {
+        match *self {
+            TimeRange::Last(d) => {
+                let secs = d.as_secs().min(i32::MAX as u64) as i32;
+                (now.saturating_sub(secs), now.saturating_add(1))
+            }
+            TimeRange::Between(start, end) => (start, end),
+        }
+    }
+}
+
+impl MatchOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchOp::Eq => "=",
+            MatchOp::Neq => "!=",
+            MatchOp::Re => "=~",
+            MatchOp::NotRe => "!~",
+        }
+    }
+}
+
+impl LineOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineOp::Contains => "|=",
+            LineOp::NotContains => "!=",
+            LineOp::Match => "|~",
+            LineOp::NotMatch => "!~",
+        }
+    }
+}
+
+impl CmpOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CmpOp::Eq => "=",
+            CmpOp::Neq => "!=",
+            CmpOp::Re => "=~",
+            CmpOp::NotRe => "!~",
+            CmpOp::Gt => ">",
+            CmpOp::Gte => ">=",
+            CmpOp::Lt => "<",
+            CmpOp::Lte => "<=",
+        }
+    }
+}
+
+impl AggFunc {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggFunc::Count => "count_over_time",
+            AggFunc::Rate => "rate",
+            AggFunc::TopK(_) => "topk",
+            AggFunc::Quantile(..) => "quantile",
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.explain {
+            write!(f, "explain ")?;
+        }
+        write!(f, "{}", self.table)?;
+        if !self.matchers.is_empty() {
+            write!(f, "{{")?;
+            for (i, m) in self.matchers.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", m)?;
+            }
+            write!(f, "}}")?;
+        }
+        for stage in &self.pipeline {
+            write!(f, " {}", stage)?;
+        }
+        if let Some(range) = &self.range {
+            write!(f, " {}", range)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{:?}", self.field, self.op.as_str(), self.value)
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Line(filter) => write!(f, "{} {:?}", filter.op.as_str(), filter.value),
+            Stage::Filter(filter) => write!(
+                f,
+                "| {} {} {}",
+                filter.field,
+                filter.op.as_str(),
+                filter.value
+            ),
+            Stage::Fields(fields) => write!(f, "| fields {}", fields.join(", ")),
+            Stage::Limit(n) => write!(f, "| limit {}", n),
+            Stage::Aggregate(aggregation) => write!(f, "| {}", aggregation),
+        }
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args = match &self.func {
+            AggFunc::Count | AggFunc::Rate => vec![],
+            AggFunc::TopK(k) => vec![k.to_string()],
+            AggFunc::Quantile(q, field) => vec![format!("{:?}", q), field.clone()],
+        };
+        if let Some(step) = self.step {
+            args.push(format_duration(step));
+        }
+        write!(f, "{}({})", self.func.name(), args.join(", "))?;
+        if !self.by.is_empty() {
+            write!(f, " by ({})", self.by.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Int(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeRange::Last(d) => write!(f, "[{}]", format_duration(*d)),
+            TimeRange::Between(start, end) => write!(f, "[{}, {}]", start, end),
+        }
+    }
+}
+
+/// `1h30m`, in the largest units that fit
+fn format_duration(d: Duration) -> String {
+    let mut secs = d.as_secs();
+    if secs == 0 {
+        return "0s".to_owned();
+    }
+    let mut out = String::new();
+    for (unit, len) in &[
+        ("w", 604_800),
+        ("d", 86_400),
+        ("h", 3_600),
+        ("m", 60),
+        ("s", 1),
+    ] {
+        if secs >= *len {
+            out.push_str(&format!("{}{}", secs / len, unit));
+            secs %= len;
+        }
+    }
+    out
+}
@@ -0,0 +1,414 @@
+//! Runs plans over the blocks of a table
+//!
+//! Blocks are searched in snapshot order. The candidates of a block are
+//! checked batch by batch against the filters, reading only the scanned
+//! columns, and the rows passing all of them are taken out of the projected
+//! columns into output batches, until the limit is reached. Aggregations
+//! aggregate each block on its own, merging the partials at the end.
+//!
+//! Rows not in blocks yet are filtered with `filter_batch`, see `tail`.
+
+use crate::agg::Aggregator;
+use crate::ast::{Aggregation, CmpOp, LineOp, Value};
+use crate::parser::parse;
+use crate::plan::{Filter, Plan, Planner};
+use anyhow::{anyhow, Result};
+use arrow::array::{Array, ArrayRef, Int32Array, StringArray, UInt32Array};
+use arrow::compute::take;
+use arrow::record_batch::RecordBatch;
+use store::schema::TIME_COL_NAME;
+use store::table::{Table, TableBlock};
+use store::Store;
+
+pub enum Output {
+    Batches(Vec<RecordBatch>),
+    /// plan of an `explain` query
+    Explain(String),
+}
+
+/// Parses, plans & runs `query` on the current version of its table
+pub fn run<S: Store>(store: &S, query: &str) -> Result<Output> {
+    let query = parse(query)?;
+    let table = Table::open(store, &query.table)?;
+    let plan = Planner::new().plan(&table, &query)?;
+    if query.explain {
+        return Ok(Output::Explain(plan.explain(store)?));
+    }
+    Ok(Output::Batches(execute(store, &plan)?))
+}
+
+/// Rows matching `plan`, or their aggregates, with its output schema
+pub fn execute<S: Store>(store: &S, plan: &Plan) -> Result<Vec<RecordBatch>> {
+    if let Some(aggregation) = plan.aggregation() {
+        return Ok(vec![aggregate(store, plan, aggregation)?]);
+    }
+    let mut batches = vec![];
+    let mut left = plan.limit().unwrap_or(usize::MAX);
+    for block in plan.blocks() {
+        if left == 0 {
+            break;
+        }
+        for batch in execute_block(store, plan, block, left)? {
+            left -= batch.num_rows();
+            batches.push(batch);
+        }
+    }
+    Ok(batches)
+}
+
+/// Rows of `block` matching `plan`, up to `limit` of them
+pub fn execute_block<S: Store>(
+    store: &S,
+    plan: &Plan,
+    block: &TableBlock,
+    limit: usize,
+) -> Result<Vec<RecordBatch>> {
+    let mut batches = vec![];
+    search_block(store, plan, block, limit, |batch, rows| {
+        let columns = Columns::new(plan.scan(), batch);
+        batches.push(project(plan, &columns, rows)?);
+        Ok(())
+    })?;
+    Ok(batches)
+}
+
+/// Rows of `batch`, with every column of the table, matching the filters of
+/// `plan` & projected, up to `limit` of them. `None` if none match.
+pub fn filter_batch(plan: &Plan, batch: &RecordBatch, limit: usize) -> Result<Option<RecordBatch>> {
+    let names = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.name().clone())
+        .collect::<Vec<_>>();
+    let columns = Columns::new(&names, batch);
+    let rows = (0..batch.num_rows())
+        .filter(|&i| plan.filters().iter().all(|f| columns.matches(f, i)))
+        .take(limit)
+        .map(|i| i as u32)
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(project(plan, &columns, rows)?))
+}
+
+fn project(plan: &Plan, columns: &Columns, rows: Vec<u32>) -> Result<RecordBatch> {
+    let indices = UInt32Array::from(rows);
+    let arrays = plan
+        .projection()
+        .iter()
+        .map(|name| Ok(take(columns.array(name)?.as_ref(), &indices, None)?))
+        .collect::<Result<Vec<ArrayRef>>>()?;
+    Ok(RecordBatch::try_new(plan.output(), arrays)?)
+}
+
+fn aggregate<S: Store>(store: &S, plan: &Plan, aggregation: &Aggregation) -> Result<RecordBatch> {
+    let mut result = Aggregator::new(aggregation, plan.range(), plan.output());
+    for block in plan.blocks() {
+        let mut partial = Aggregator::new(aggregation, plan.range(), plan.output());
+        search_block(store, plan, block, usize::MAX, |batch, rows| {
+            partial.update(batch, &rows)
+        })?;
+        result.merge(partial);
+    }
+    result.finish(plan.limit())
+}
+
+/// Calls `visit` with every scanned batch of `block` having rows matching
+/// the filters, and these rows, up to `limit` of them
+fn search_block<S, F>(
+    store: &S,
+    plan: &Plan,
+    block: &TableBlock,
+    limit: usize,
+    mut visit: F,
+) -> Result<()>
+where
+    S: Store,
+    F: FnMut(&RecordBatch, Vec<u32>) -> Result<()>,
+{
+    let candidates = plan.candidates(store, block)?;
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let names = plan.scan().iter().map(String::as_str).collect::<Vec<_>>();
+    let projection = block.schema().projection(&names)?;
+    let mut left = limit;
+    let mut rows = candidates.iter().peekable();
+    let mut offset = 0;
+    for batch in block.scan(store, Some(projection))? {
+        let batch = batch?;
+        let end = offset + batch.num_rows() as u32;
+        let columns = Columns::new(plan.scan(), &batch);
+
+        let mut matches = vec![];
+        while let Some(&row) = rows.peek() {
+            if row >= end || matches.len() == left {
+                break;
+            }
+            rows.next();
+            let i = (row - offset) as usize;
+            if plan.filters().iter().all(|f| columns.matches(f, i)) {
+                matches.push(i as u32);
+            }
+        }
+        offset = end;
+
+        if !matches.is_empty() {
+            left -= matches.len();
+            visit(&batch, matches)?;
+        }
+        if left == 0 || rows.peek().is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Columns of a batch, named by `names`: `Plan::scan` for scanned batches
+struct Columns<'a> {
+    names: &'a [String],
+    batch: &'a RecordBatch,
+    strings: Vec<&'a StringArray>,
+}
+
+impl<'a> Columns<'a> {
+    fn new(names: &'a [String], batch: &'a RecordBatch) -> Self {
+        let strings = batch
+            .columns()
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<StringArray>())
+            .collect();
+        Self {
+            names,
+            batch,
+            strings,
+        }
+    }
+
+    fn array(&self, name: &str) -> Result<&'a ArrayRef> {
+        let i = self
+            .names
+            .iter()
+            .position(|n| n == name)
+            .ok_or_else(|| anyhow!("Column {} isn't scanned", name))?;
+        Ok(self.batch.column(i))
+    }
+
+    fn matches(&self, filter: &Filter, row: usize) -> bool {
+        match filter {
+            Filter::Time(start, end) => match self.int(TIME_COL_NAME, row) {
+                Some(Some(time)) => *start <= time && time < *end,
+                _ => false,
+            },
+            Filter::Line { op, value, regex } => {
+                let mut values = self
+                    .strings
+                    .iter()
+                    .filter(|c| c.is_valid(row))
+                    .map(|c| c.value(row));
+                match (op, regex) {
+                    (LineOp::Contains, _) => values.any(|v| v.contains(value.as_str())),
+                    (LineOp::NotContains, _) => !values.any(|v| v.contains(value.as_str())),
+                    (LineOp::Match, Some(regex)) => values.any(|v| regex.is_match(v)),
+                    (LineOp::NotMatch, Some(regex)) => !values.any(|v| regex.is_match(v)),
+                    _ => false,
+                }
+            }
+            Filter::Field {
+                field,
+                op,
+                value,
+                regex,
+            } => {
+                if let Some(regex) = regex {
+                    let text = match (self.string(field, row), self.int(field, row)) {
+                        (Some(Some(s)), _) => Some(s.to_owned()),
+                        (_, Some(Some(n))) => Some(n.to_string()),
+                        _ => None,
+                    };
+                    let is_match = text.map(|t| regex.is_match(&t));
+                    return match op {
+                        CmpOp::NotRe => is_match != Some(true),
+                        _ => is_match == Some(true),
+                    };
+                }
+                match (value, self.string(field, row), self.int(field, row)) {
+                    (Value::Str(v), Some(Some(s)), _) => compare(*op, s, v.as_str()),
+                    (Value::Int(v), _, Some(Some(n))) => compare(*op, n, *v),
+                    // null
+                    _ => *op == CmpOp::Neq,
+                }
+            }
+        }
+    }
+
+    /// `None` if `name` isn't a string column, `Some(None)` for nulls
+    fn string(&self, name: &str, row: usize) -> Option<Option<&'a str>> {
+        let array = self.array(name).ok()?;
+        let array = array.as_any().downcast_ref::<StringArray>()?;
+        Some(if array.is_valid(row) {
+            Some(array.value(row))
+        } else {
+            None
+        })
+    }
+
+    /// `None` if `name` isn't an int column, `Some(None)` for nulls
+    fn int(&self, name: &str, row: usize) -> Option<Option<i32>> {
+        let array = self.array(name).ok()?;
+        let array = array.as_any().downcast_ref::<Int32Array>()?;
+        Some(if array.is_valid(row) {
+            Some(array.value(row))
+        } else {
+            None
+        })
+    }
+}
+
+fn compare<T: PartialOrd>(op: CmpOp, a: T, b: T) -> bool {
+    match op {
+        CmpOp::Eq => a == b,
+        CmpOp::Neq => a != b,
+        CmpOp::Gt => a > b,
+        CmpOp::Gte => a >= b,
+        CmpOp::Lt => a < b,
+        CmpOp::Lte => a <= b,
+        CmpOp::Re | CmpOp::NotRe => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{log_table, store};
+    use arrow::array::UInt64Array;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn run_query_test() {
+        let store = store("exec_table");
+        log_table(
+            &store,
+            "exec_table",
+            &[
+                (
+                    0,
+                    ["GET /index ok", "GET /login timeout", "POST /login ok"],
+                    ["200", "504", "200"],
+                    [10, 3000, 20],
+                ),
+                (
+                    100,
+                    ["GET /api timeout", "GET /api ok", "DELETE /api Timeout"],
+                    ["504", "200", "500"],
+                    [2500, 15, 40],
+                ),
+                (
+                    200,
+                    ["GET /health ok", "GET /health ok", "POST /api timeout"],
+                    ["200", "200", "504"],
+                    [1, 1, 4000],
+                ),
+            ],
+        );
+
+        let rows = |query: &str| -> Vec<Vec<String>> {
+            let batches = match run(&store, query).unwrap() {
+                Output::Batches(batches) => batches,
+                Output::Explain(plan) => panic!("unexpected plan {}", plan),
+            };
+            let mut rows = vec![];
+            for batch in batches {
+                for i in 0..batch.num_rows() {
+                    let row = batch
+                        .columns()
+                        .iter()
+                        .map(|c| {
+                            let any = c.as_any();
+                            if let Some(s) = any.downcast_ref::<StringArray>() {
+                                s.value(i).to_owned()
+                            } else if let Some(n) = any.downcast_ref::<Int32Array>() {
+                                n.value(i).to_string()
+                            } else {
+                                let n = any.downcast_ref::<UInt64Array>().unwrap();
+                                n.value(i).to_string()
+                            }
+                        })
+                        .collect();
+                    rows.push(row);
+                }
+            }
+            rows
+        };
+
+        // `|=` is case sensitive, unlike the trigram lookup
+        assert_eq!(
+            rows(r#"exec_table |= "timeout" | fields message"#),
+            vec![
+                vec!["GET /login timeout"],
+                vec!["GET /api timeout"],
+                vec!["POST /api timeout"],
+            ]
+        );
+        assert_eq!(
+            rows(
+                r#"exec_table{status="504"} |= "timeout" | latency > 2600 | fields message, latency"#
+            ),
+            vec![
+                vec!["GET /login timeout", "3000"],
+                vec!["POST /api timeout", "4000"],
+            ]
+        );
+        assert_eq!(
+            rows(r#"exec_table |~ "(?i)timeout" | fields time, status [100, 200]"#),
+            vec![vec!["100", "504"], vec!["102", "500"]]
+        );
+        assert_eq!(
+            rows(r#"exec_table{status=~"5.."} | fields time | limit 2"#),
+            vec![vec!["1"], vec!["100"]]
+        );
+        assert_eq!(
+            rows(r#"exec_table{status!="200"} != "timeout""#),
+            vec![vec!["102", "DELETE /api Timeout", "500", "40"]]
+        );
+        assert!(rows(r#"exec_table |= "timeout" [300, 400]"#).is_empty());
+
+        // aggregated across blocks
+        assert_eq!(
+            rows(r#"exec_table |= "timeout" | count_over_time() by (status)"#),
+            vec![vec!["504", "3"]]
+        );
+        assert_eq!(
+            rows("exec_table | topk(2) by (status) | limit 1"),
+            vec![vec!["200", "5"]]
+        );
+        assert_eq!(
+            rows(r#"exec_table{status="504"} | count_over_time(100s) [0, 150]"#),
+            vec![vec!["0", "1"], vec!["100", "1"]]
+        );
+
+        let plan = match run(
+            &store,
+            r#"explain exec_table{status="504"} |= "timeout" [100, 300]"#,
+        )
+        .unwrap()
+        {
+            Output::Explain(plan) => plan,
+            Output::Batches(_) => panic!("expected a plan"),
+        };
+        for line in &[
+            "Blocks: 2 of 3, pruned by time: [b0]\n",
+            "Filters:\n  time in [100, 300)\n  status = \"504\"\n  line |= \"timeout\"\n",
+            "Scan: time, message, status, latency\n",
+            "Candidates:\n  b1: 1 of 3 rows\n  b2: 1 of 3 rows\n",
+        ] {
+            assert!(plan.contains(line), "{} not in {}", line, plan);
+        }
+
+        assert!(run(&store, "exec_table |= ").is_err());
+        store.clean("exec_table").unwrap();
+    }
+}
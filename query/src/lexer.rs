@@ -0,0 +1,284 @@
+//! Splits a query into tokens, each with the byte offset it starts at
+
+use crate::parser::ParseError;
+use std::fmt;
+use std::time::Duration;
+
+const UNIT_ERROR: &str = "invalid duration unit, expected s, m, h, d or w";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    Ident(String),
+    /// `"..."` with escapes, or raw between backticks
+    Str(String),
+    Int(i64),
+    Float(f64),
+    /// `5m`, `1h30m`
+    Duration(Duration),
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Comma,
+    Pipe,
+    /// `|=`
+    PipeEq,
+    /// `|~`
+    PipeTilde,
+    Eq,
+    /// `!=`
+    Neq,
+    /// `=~`
+    EqTilde,
+    /// `!~`
+    NotTilde,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub tok: Tok,
+    pub pos: usize,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Ident(s) => write!(f, "`{}`", s),
+            Tok::Str(s) => write!(f, "string {:?}", s),
+            Tok::Int(n) => write!(f, "number {}", n),
+            Tok::Float(n) => write!(f, "number {:?}", n),
+            Tok::Duration(_) => write!(f, "duration"),
+            Tok::Eof => write!(f, "end of query"),
+            punct => write!(f, "`{}`", punct.as_str()),
+        }
+    }
+}
+
+impl Tok {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Tok::LBrace => "{",
+            Tok::RBrace => "}",
+            Tok::LBracket => "[",
+            Tok::RBracket => "]",
+            Tok::LParen => "(",
+            Tok::RParen => ")",
+            Tok::Comma => ",",
+            Tok::Pipe => "|",
+            Tok::PipeEq => "|=",
+            Tok::PipeTilde => "|~",
+            Tok::Eq => "=",
+            Tok::Neq => "!=",
+            Tok::EqTilde => "=~",
+            Tok::NotTilde => "!~",
+            Tok::Gt => ">",
+            Tok::Gte => ">=",
+            Tok::Lt => "<",
+            Tok::Lte => "<=",
+            _ => "",
+        }
+    }
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut lexer = Lexer { input, pos: 0 };
+    let mut tokens = vec![];
+    loop {
+        let token = lexer.next_token()?;
+        let eof = token.tok == Tok::Eof;
+        tokens.push(token);
+        if eof {
+            return Ok(tokens);
+        }
+    }
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn peek_is<F: Fn(char) -> bool>(&self, f: F) -> bool {
+        matches!(self.peek(), Some(c) if f(c))
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn error(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        ParseError::new(self.input, pos, message)
+    }
+
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        while self.peek_is(char::is_whitespace) {
+            self.bump();
+        }
+        let pos = self.pos;
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Ok(Token { tok: Tok::Eof, pos }),
+        };
+        let next = self.peek();
+        let two = |lexer: &mut Self, tok| {
+            lexer.bump();
+            tok
+        };
+        let tok = match c {
+            '{' => Tok::LBrace,
+            '}' => Tok::RBrace,
+            '[' => Tok::LBracket,
+            ']' => Tok::RBracket,
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            ',' => Tok::Comma,
+            '|' if next == Some('=') => two(self, Tok::PipeEq),
+            '|' if next == Some('~') => two(self, Tok::PipeTilde),
+            '|' => Tok::Pipe,
+            '=' if next == Some('~') => two(self, Tok::EqTilde),
+            '=' => Tok::Eq,
+            '!' if next == Some('=') => two(self, Tok::Neq),
+            '!' if next == Some('~') => two(self, Tok::NotTilde),
+            '>' if next == Some('=') => two(self, Tok::Gte),
+            '>' => Tok::Gt,
+            '<' if next == Some('=') => two(self, Tok::Lte),
+            '<' => Tok::Lt,
+            '"' => Tok::Str(self.string(pos)?),
+            '`' => Tok::Str(self.raw_string(pos)?),
+            '-' if matches!(next, Some(c) if c.is_ascii_digit()) => self.number(pos)?,
+            c if c.is_ascii_digit() => self.number(pos)?,
+            c if c.is_alphabetic() || c == '_' => {
+                while self.peek_is(|c| c.is_alphanumeric() || c == '_') {
+                    self.bump();
+                }
+                Tok::Ident(self.input[pos..self.pos].to_owned())
+            }
+            c => return Err(self.error(pos, format!("unexpected character {:?}", c))),
+        };
+        Ok(Token { tok, pos })
+    }
+
+    fn string(&mut self, start: usize) -> Result<String, ParseError> {
+        let mut s = String::new();
+        loop {
+            let pos = self.pos;
+            match self.bump() {
+                None => return Err(self.error(start, "unterminated string")),
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.escape(pos)?),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self, pos: usize) -> Result<char, ParseError> {
+        let c = match self.bump() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+            Some('u') if self.peek() == Some('{') => {
+                self.bump();
+                let digits = self.pos;
+                while self.peek_is(|c| c.is_ascii_hexdigit()) {
+                    self.bump();
+                }
+                let code = u32::from_str_radix(&self.input[digits..self.pos], 16).ok();
+                match (code.and_then(std::char::from_u32), self.bump()) {
+                    (Some(c), Some('}')) => c,
+                    _ => return Err(self.error(pos, "invalid unicode escape")),
+                }
+            }
+            _ => return Err(self.error(pos, "invalid escape")),
+        };
+        Ok(c)
+    }
+
+    fn raw_string(&mut self, start: usize) -> Result<String, ParseError> {
+        match self.input[self.pos..].find('`') {
+            Some(len) => {
+                let s = self.input[self.pos..self.pos + len].to_owned();
+                self.pos += len + 1;
+                Ok(s)
+            }
+            None => Err(self.error(start, "unterminated string")),
+        }
+    }
+
+    /// an integer, a float, or a duration if followed by units
+    fn number(&mut self, start: usize) -> Result<Tok, ParseError> {
+        while self.peek_is(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+        let fraction = self.input[self.pos..].starts_with('.')
+            && matches!(self.input[self.pos + 1..].chars().next(), Some(c) if c.is_ascii_digit());
+        if fraction {
+            self.bump();
+            while self.peek_is(|c| c.is_ascii_digit()) {
+                self.bump();
+            }
+            return self.input[start..self.pos]
+                .parse()
+                .map(Tok::Float)
+                .map_err(|_| self.error(start, "invalid number"));
+        }
+        if !self.peek_is(char::is_alphabetic) {
+            return self.input[start..self.pos]
+                .parse()
+                .map(Tok::Int)
+                .map_err(|_| self.error(start, "number out of range"));
+        }
+
+        // 1h30m
+        let mut secs = 0u64;
+        let mut digits = start;
+        loop {
+            let n: u64 = self.input[digits..self.pos]
+                .parse()
+                .map_err(|_| self.error(start, "invalid duration"))?;
+            let unit_pos = self.pos;
+            let unit = match self.bump() {
+                Some('s') => 1,
+                Some('m') => 60,
+                Some('h') => 3_600,
+                Some('d') => 86_400,
+                Some('w') => 604_800,
+                _ => return Err(self.error(unit_pos, UNIT_ERROR)),
+            };
+            secs = n
+                .checked_mul(unit)
+                .and_then(|s| secs.checked_add(s))
+                .ok_or_else(|| self.error(start, "duration out of range"))?;
+
+            digits = self.pos;
+            while self.peek_is(|c| c.is_ascii_digit()) {
+                self.bump();
+            }
+            if digits == self.pos {
+                break;
+            }
+            if !self.peek_is(char::is_alphabetic) {
+                return Err(self.error(self.pos, UNIT_ERROR));
+            }
+        }
+        if self.peek_is(|c| c.is_alphanumeric() || c == '_') {
+            return Err(self.error(start, "invalid duration"));
+        }
+        Ok(Tok::Duration(Duration::from_secs(secs)))
+    }
+}
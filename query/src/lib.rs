@@ -1,3 +1,17 @@
+pub mod agg;
+pub mod ast;
+pub mod exec;
+pub mod lexer;
+pub mod parser;
+pub mod plan;
+pub mod sql;
+pub mod substring;
+pub mod tail;
+pub mod trigram;
+
+#[cfg(test)]
+mod test_util;
+
 #[cfg(test)]
 mod tests {
     #[test]
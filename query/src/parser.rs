@@ -0,0 +1,678 @@
+//! Parses log queries into a `Query`, see `ast` for the syntax
+//!
+//! ```text
+//! query    := 'explain'? ident selector? (line | '|' stage)* range?
+//! selector := '{' (matcher (',' matcher)*)? '}'
+//! matcher  := ident ('=' | '!=' | '=~' | '!~') string
+//! line     := ('|=' | '!=' | '|~' | '!~') string
+//! stage    := ident cmp (string | int) | 'fields' ident (',' ident)* | 'limit' int
+//!           | aggregate
+//! aggregate:= ('count_over_time' | 'rate') '(' duration? ')' by?
+//!           | 'topk' '(' int (',' duration)? ')' by
+//!           | 'quantile' '(' number ',' ident (',' duration)? ')' by?
+//! by       := 'by' '(' ident (',' ident)* ')'
+//! range    := '[' duration ']' | '[' int ',' int ']'
+//! ```
+//!
+//! Strings are double quoted with rust like escapes, or raw between
+//! backticks. Regexes are checked while parsing, and only `limit` can follow
+//! an aggregation. Errors point at the byte where the query went wrong.
+
+use crate::ast::*;
+use crate::lexer::{tokenize, Tok, Token};
+use regex::Regex;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    message: String,
+    /// byte offset in the query
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl ParseError {
+    pub(crate) fn new(input: &str, pos: usize, message: impl Into<String>) -> Self {
+        let before = &input[..pos];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            message: message.into(),
+            pos,
+            line,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// 1 based
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// 1 based, in chars
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    let tokens = tokenize(input)?;
+    Parser {
+        input,
+        tokens,
+        i: 0,
+    }
+    .query()
+}
+
+impl FromStr for Query {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    /// always ends with `Tok::Eof`
+    tokens: Vec<Token>,
+    i: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.i]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.i].clone();
+        if token.tok != Tok::Eof {
+            self.i += 1;
+        }
+        token
+    }
+
+    fn error(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        ParseError::new(self.input, pos, message)
+    }
+
+    /// error at the next token
+    fn expected(&self, what: &str) -> ParseError {
+        let token = self.peek();
+        self.error(token.pos, format!("expected {}, found {}", what, token.tok))
+    }
+
+    fn expect(&mut self, tok: Tok, what: &str) -> Result<(), ParseError> {
+        if self.peek().tok != tok {
+            return Err(self.expected(what));
+        }
+        self.next();
+        Ok(())
+    }
+
+    fn ident(&mut self, what: &str) -> Result<String, ParseError> {
+        match self.peek().tok.clone() {
+            Tok::Ident(name) => {
+                self.next();
+                Ok(name)
+            }
+            _ => Err(self.expected(what)),
+        }
+    }
+
+    fn string(&mut self) -> Result<(String, usize), ParseError> {
+        match self.peek().clone() {
+            Token {
+                tok: Tok::Str(s),
+                pos,
+            } => {
+                self.next();
+                Ok((s, pos))
+            }
+            _ => Err(self.expected("a string")),
+        }
+    }
+
+    fn int(&mut self) -> Result<i32, ParseError> {
+        match self.peek().clone() {
+            Token {
+                tok: Tok::Int(n),
+                pos,
+            } => {
+                self.next();
+                i32::try_from(n).map_err(|_| self.error(pos, "number out of range"))
+            }
+            _ => Err(self.expected("a number")),
+        }
+    }
+
+    fn regex(&self, regex: &str, pos: usize) -> Result<(), ParseError> {
+        match Regex::new(regex) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                // syntax errors span several lines, the last one says what's wrong
+                let err = err.to_string();
+                let reason = err.lines().last().unwrap_or_default();
+                let reason = reason.trim_start_matches("error: ");
+                Err(self.error(pos, format!("invalid regex: {}", reason)))
+            }
+        }
+    }
+
+    fn query(&mut self) -> Result<Query, ParseError> {
+        // `explain` is a table name when nothing follows it
+        let explain = self.peek().tok == Tok::Ident("explain".into())
+            && matches!(
+                self.tokens.get(self.i + 1),
+                Some(Token {
+                    tok: Tok::Ident(_),
+                    ..
+                })
+            );
+        if explain {
+            self.next();
+        }
+        let table = self.ident("a table name")?;
+        let matchers = if self.peek().tok == Tok::LBrace {
+            self.selector()?
+        } else {
+            vec![]
+        };
+
+        let mut pipeline = vec![];
+        let mut range = None;
+        let mut aggregated = false;
+        loop {
+            let pos = self.peek().pos;
+            let op = match self.peek().tok {
+                Tok::PipeEq => LineOp::Contains,
+                Tok::Neq => LineOp::NotContains,
+                Tok::PipeTilde => LineOp::Match,
+                Tok::NotTilde => LineOp::NotMatch,
+                Tok::Pipe => {
+                    self.next();
+                    let pos = self.peek().pos;
+                    let stage = self.stage()?;
+                    if aggregated && !matches!(stage, Stage::Limit(_)) {
+                        return Err(self.error(pos, "only `limit` can follow an aggregation"));
+                    }
+                    aggregated |= matches!(stage, Stage::Aggregate(_));
+                    pipeline.push(stage);
+                    continue;
+                }
+                Tok::LBracket => {
+                    range = Some(self.range()?);
+                    break;
+                }
+                Tok::Eof => break,
+                _ => return Err(self.expected("`|`, a line filter or a time range")),
+            };
+            if aggregated {
+                return Err(self.error(pos, "only `limit` can follow an aggregation"));
+            }
+            self.next();
+            let (value, pos) = self.string()?;
+            if let LineOp::Match | LineOp::NotMatch = op {
+                self.regex(&value, pos)?;
+            }
+            pipeline.push(Stage::Line(LineFilter { op, value }));
+        }
+        self.expect(Tok::Eof, "end of query")?;
+
+        Ok(Query {
+            explain,
+            table,
+            matchers,
+            pipeline,
+            range,
+        })
+    }
+
+    fn selector(&mut self) -> Result<Vec<Matcher>, ParseError> {
+        self.expect(Tok::LBrace, "`{`")?;
+        let mut matchers = vec![];
+        if self.peek().tok == Tok::RBrace {
+            self.next();
+            return Ok(matchers);
+        }
+        loop {
+            let field = self.ident("a field name")?;
+            let op = match self.peek().tok {
+                Tok::Eq => MatchOp::Eq,
+                Tok::Neq => MatchOp::Neq,
+                Tok::EqTilde => MatchOp::Re,
+                Tok::NotTilde => MatchOp::NotRe,
+                _ => return Err(self.expected("`=`, `!=`, `=~` or `!~`")),
+            };
+            self.next();
+            let (value, pos) = self.string()?;
+            if let MatchOp::Re | MatchOp::NotRe = op {
+                self.regex(&value, pos)?;
+            }
+            matchers.push(Matcher { field, op, value });
+
+            match self.peek().tok {
+                Tok::Comma => self.next(),
+                Tok::RBrace => {
+                    self.next();
+                    return Ok(matchers);
+                }
+                _ => return Err(self.expected("`,` or `}`")),
+            };
+        }
+    }
+
+    fn stage(&mut self) -> Result<Stage, ParseError> {
+        let pos = self.peek().pos;
+        let name = self.ident("a stage")?;
+        if self.peek().tok == Tok::LParen {
+            return self.aggregation(&name, pos).map(Stage::Aggregate);
+        }
+        let op = match self.peek().tok {
+            Tok::Eq => CmpOp::Eq,
+            Tok::Neq => CmpOp::Neq,
+            Tok::EqTilde => CmpOp::Re,
+            Tok::NotTilde => CmpOp::NotRe,
+            Tok::Gt => CmpOp::Gt,
+            Tok::Gte => CmpOp::Gte,
+            Tok::Lt => CmpOp::Lt,
+            Tok::Lte => CmpOp::Lte,
+            _ => match name.as_str() {
+                "fields" => {
+                    let mut fields = vec![self.ident("a field name")?];
+                    while self.peek().tok == Tok::Comma {
+                        self.next();
+                        fields.push(self.ident("a field name")?);
+                    }
+                    return Ok(Stage::Fields(fields));
+                }
+                "limit" => {
+                    let limit_pos = self.peek().pos;
+                    let limit = self.int()?;
+                    return usize::try_from(limit)
+                        .map(Stage::Limit)
+                        .map_err(|_| self.error(limit_pos, "limit can't be negative"));
+                }
+                _ => {
+                    return Err(self.error(
+                        pos,
+                        format!(
+                            "unknown stage `{}`, expected `fields`, `limit`, an aggregation or a field filter",
+                            name
+                        ),
+                    ))
+                }
+            },
+        };
+        self.next();
+
+        let value = match self.peek().tok {
+            Tok::Int(_) if op != CmpOp::Re && op != CmpOp::NotRe => Value::Int(self.int()?),
+            _ => {
+                let (value, pos) = self.string()?;
+                if let CmpOp::Re | CmpOp::NotRe = op {
+                    self.regex(&value, pos)?;
+                }
+                Value::Str(value)
+            }
+        };
+        Ok(Stage::Filter(FieldFilter {
+            field: name,
+            op,
+            value,
+        }))
+    }
+
+    /// `name(args) by (fields)`, at the `(`
+    fn aggregation(&mut self, name: &str, pos: usize) -> Result<Aggregation, ParseError> {
+        self.expect(Tok::LParen, "`(`")?;
+        let func = match name {
+            "count_over_time" => AggFunc::Count,
+            "rate" => AggFunc::Rate,
+            "topk" => {
+                let k_pos = self.peek().pos;
+                let k = self.int()?;
+                if k <= 0 {
+                    return Err(self.error(k_pos, "k has to be positive"));
+                }
+                AggFunc::TopK(k as usize)
+            }
+            "quantile" => {
+                let q_pos = self.peek().pos;
+                let q = match self.peek().tok {
+                    Tok::Float(q) => q,
+                    Tok::Int(n) => n as f64,
+                    _ => return Err(self.expected("a quantile")),
+                };
+                self.next();
+                if !(0.0..=1.0).contains(&q) {
+                    return Err(self.error(q_pos, "quantile has to be between 0 and 1"));
+                }
+                self.expect(Tok::Comma, "`,`")?;
+                AggFunc::Quantile(q, self.ident("a field name")?)
+            }
+            _ => {
+                return Err(self.error(
+                    pos,
+                    format!(
+                        "unknown aggregation `{}`, expected `count_over_time`, `rate`, `topk` or `quantile`",
+                        name
+                    ),
+                ))
+            }
+        };
+
+        // the step comes after the other arguments
+        let step = match (&func, &self.peek().tok) {
+            (AggFunc::Count, Tok::Duration(_)) | (AggFunc::Rate, Tok::Duration(_)) => {
+                Some(self.step()?)
+            }
+            (AggFunc::TopK(_), Tok::Comma) | (AggFunc::Quantile(..), Tok::Comma) => {
+                self.next();
+                Some(self.step()?)
+            }
+            _ => None,
+        };
+        self.expect(Tok::RParen, "`)`")?;
+
+        let mut by = vec![];
+        if self.peek().tok == Tok::Ident("by".into()) {
+            self.next();
+            self.expect(Tok::LParen, "`(`")?;
+            loop {
+                by.push(self.ident("a field name")?);
+                match self.peek().tok {
+                    Tok::Comma => self.next(),
+                    Tok::RParen => {
+                        self.next();
+                        break;
+                    }
+                    _ => return Err(self.expected("`,` or `)`")),
+                };
+            }
+        }
+        if let (AggFunc::TopK(_), true) = (&func, by.is_empty()) {
+            return Err(self.error(pos, "topk needs groups, add `by (field)`"));
+        }
+        Ok(Aggregation { func, step, by })
+    }
+
+    fn step(&mut self) -> Result<Duration, ParseError> {
+        match self.peek().clone() {
+            Token {
+                tok: Tok::Duration(step),
+                pos,
+            } => {
+                self.next();
+                if step.as_secs() == 0 {
+                    return Err(self.error(pos, "step can't be 0"));
+                }
+                Ok(step)
+            }
+            _ => Err(self.expected("a step duration")),
+        }
+    }
+
+    fn range(&mut self) -> Result<TimeRange, ParseError> {
+        self.expect(Tok::LBracket, "`[`")?;
+        let start_pos = self.peek().pos;
+        let range = match self.peek().tok {
+            Tok::Duration(d) => {
+                self.next();
+                TimeRange::Last(d)
+            }
+            Tok::Int(_) => {
+                let start = self.int()?;
+                self.expect(Tok::Comma, "`,`")?;
+                let end = self.int()?;
+                if end <= start {
+                    return Err(self.error(start_pos, "empty time range, end is before start"));
+                }
+                TimeRange::Between(start, end)
+            }
+            _ => return Err(self.expected("a duration or a start time")),
+        };
+        self.expect(Tok::RBracket, "`]`")?;
+        Ok(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_query_test() {
+        let query = parse(
+            r#"nginx{status="500", useragent=~"curl.*"}
+                |= "timeout" != `C:\health` |~ "err(or)?"
+                | latency >= 200 | method != "GET" | fields message, status | limit 10
+                [1h30m]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query {
+                explain: false,
+                table: "nginx".into(),
+                matchers: vec![
+                    Matcher {
+                        field: "status".into(),
+                        op: MatchOp::Eq,
+                        value: "500".into(),
+                    },
+                    Matcher {
+                        field: "useragent".into(),
+                        op: MatchOp::Re,
+                        value: "curl.*".into(),
+                    },
+                ],
+                pipeline: vec![
+                    Stage::Line(LineFilter {
+                        op: LineOp::Contains,
+                        value: "timeout".into(),
+                    }),
+                    Stage::Line(LineFilter {
+                        op: LineOp::NotContains,
+                        value: r"C:\health".into(),
+                    }),
+                    Stage::Line(LineFilter {
+                        op: LineOp::Match,
+                        value: "err(or)?".into(),
+                    }),
+                    Stage::Filter(FieldFilter {
+                        field: "latency".into(),
+                        op: CmpOp::Gte,
+                        value: Value::Int(200),
+                    }),
+                    Stage::Filter(FieldFilter {
+                        field: "method".into(),
+                        op: CmpOp::Neq,
+                        value: Value::Str("GET".into()),
+                    }),
+                    Stage::Fields(vec!["message".into(), "status".into()]),
+                    Stage::Limit(10),
+                ],
+                range: Some(TimeRange::Last(Duration::from_secs(5400))),
+            }
+        );
+
+        // printed back the same
+        let printed = query.to_string();
+        assert_eq!(
+            printed,
+            r#"nginx{status="500", useragent=~"curl.*"} |= "timeout" != "C:\\health" |~ "err(or)?" | latency >= 200 | method != "GET" | fields message, status | limit 10 [1h30m]"#
+        );
+        assert_eq!(printed.parse::<Query>().unwrap(), query);
+
+        let query = parse("explain nginx |= \"a\"").unwrap();
+        assert!(query.explain);
+        assert_eq!(query.to_string(), "explain nginx |= \"a\"");
+        let query = parse("explain |= \"a\"").unwrap();
+        assert!(!query.explain);
+        assert_eq!(query.table, "explain");
+
+        let query = parse("nginx [1614556800, 1614560400]").unwrap();
+        assert!(query.matchers.is_empty() && query.pipeline.is_empty());
+        assert_eq!(
+            query.range,
+            Some(TimeRange::Between(1614556800, 1614560400))
+        );
+        assert_eq!(
+            parse(r#"nginx{} |= "caf\u{e9}\t\"x\"""#).unwrap().pipeline,
+            vec![Stage::Line(LineFilter {
+                op: LineOp::Contains,
+                value: "café\t\"x\"".into(),
+            })]
+        );
+        assert_eq!(
+            TimeRange::Last(Duration::from_secs(60)).bounds(1000),
+            (940, 1001)
+        );
+
+        let query = parse(
+            "apache |= \"GET\" | quantile(0.99, size, 5m) by (status, method) | limit 10 [1d]",
+        )
+        .unwrap();
+        assert_eq!(
+            query.pipeline[1],
+            Stage::Aggregate(Aggregation {
+                func: AggFunc::Quantile(0.99, "size".into()),
+                step: Some(Duration::from_secs(300)),
+                by: vec!["status".into(), "method".into()],
+            })
+        );
+        for query in &[
+            "apache | count_over_time()",
+            "apache | rate(1m) [1h]",
+            "apache | topk(10) by (ipaddress)",
+            "apache | topk(3, 1h) by (ipaddress)",
+            "apache | quantile(1.0, size)",
+        ] {
+            assert_eq!(&parse(query).unwrap().to_string(), query);
+        }
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let error = |query: &str| {
+            let err = parse(query).unwrap_err();
+            (err.column(), err.message().to_owned())
+        };
+
+        assert_eq!(
+            error(""),
+            (1, "expected a table name, found end of query".into())
+        );
+        assert_eq!(
+            error(r#"nginx{status="500" method="GET"}"#),
+            (20, "expected `,` or `}`, found `method`".into())
+        );
+        assert_eq!(
+            error(r#"nginx{status="500""#),
+            (19, "expected `,` or `}`, found end of query".into())
+        );
+        assert_eq!(
+            error(r#"nginx{status=500}"#),
+            (14, "expected a string, found number 500".into())
+        );
+        assert_eq!(
+            error(r#"nginx{status>"500"}"#),
+            (13, "expected `=`, `!=`, `=~` or `!~`, found `>`".into())
+        );
+        assert_eq!(
+            error(r#"nginx |= "time"#),
+            (10, "unterminated string".into())
+        );
+        assert_eq!(error(r#"nginx |= "a\qb""#), (12, "invalid escape".into()));
+        assert_eq!(
+            error(r#"nginx |~ "err(or""#),
+            (10, "invalid regex: unclosed group".into())
+        );
+        assert_eq!(
+            error(r#"nginx |= "a" | sort message"#),
+            (
+                16,
+                "unknown stage `sort`, expected `fields`, `limit`, an aggregation or a field filter"
+                    .into()
+            )
+        );
+        assert_eq!(
+            error("nginx | latency > 99999999999"),
+            (19, "number out of range".into())
+        );
+        assert_eq!(
+            error("nginx | limit -1"),
+            (15, "limit can't be negative".into())
+        );
+        assert_eq!(
+            error("nginx [5y]"),
+            (9, "invalid duration unit, expected s, m, h, d or w".into())
+        );
+        assert_eq!(
+            error("nginx [20, 10]"),
+            (8, "empty time range, end is before start".into())
+        );
+        assert_eq!(
+            error(r#"nginx [5m] |= "a""#),
+            (12, "expected end of query, found `|=`".into())
+        );
+        assert_eq!(
+            error("nginx | count_over_time() | fields status"),
+            (29, "only `limit` can follow an aggregation".into())
+        );
+        assert_eq!(
+            error(r#"nginx | rate() |= "a""#),
+            (16, "only `limit` can follow an aggregation".into())
+        );
+        assert_eq!(
+            error("nginx | topk(10)"),
+            (9, "topk needs groups, add `by (field)`".into())
+        );
+        assert_eq!(
+            error("nginx | quantile(99, size)"),
+            (18, "quantile has to be between 0 and 1".into())
+        );
+        assert_eq!(error("nginx | rate(0s)"), (14, "step can't be 0".into()));
+        assert_eq!(
+            error("nginx | sum(size)"),
+            (
+                9,
+                "unknown aggregation `sum`, expected `count_over_time`, `rate`, `topk` or `quantile`"
+                    .into()
+            )
+        );
+        assert_eq!(
+            error("nginx # comment"),
+            (7, "unexpected character '#'".into())
+        );
+
+        // lines & columns count chars, offsets bytes
+        let err = parse("nginx{é=\"a\"}\n  |= \"é\" @").unwrap_err();
+        assert_eq!((err.line(), err.column(), err.pos()), (2, 10, 24));
+        assert_eq!(err.to_string(), "2:10: unexpected character '@'");
+    }
+}
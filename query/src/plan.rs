@@ -0,0 +1,586 @@
+//! Physical plans of log queries
+//!
+//! A `Query` is planned against the current snapshot of its table:
+//!
+//! 1. blocks whose time stats can't overlap the time range are pruned
+//! 2. predicates every matching row has to satisfy on a string column (`=`,
+//!    `=~`, `|=`, `|~`) become trigram lookups, whose posting lists narrow the
+//!    rows of a block down to candidates, intersected across lookups
+//! 3. candidates are checked against every filter by scanning only the
+//!    columns they need, then projected & limited, see `exec`, or
+//!    aggregated, see `agg`
+//!
+//! `Plan::explain` shows every step along with the candidates of each block,
+//! without scanning any.
+
+use crate::agg;
+use crate::ast::{AggFunc, Aggregation, CmpOp, LineOp, MatchOp, Query, Stage, Value};
+use crate::trigram::{candidates, literal_query, RegexQuery, TrigramQuery};
+use anyhow::{anyhow, Result};
+use arrow::datatypes::{DataType as ArrowDataType, Schema as ArrowSchema, SchemaRef};
+use regex::Regex;
+use roaring::RoaringBitmap;
+use std::fmt::{self, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use store::manifest::Snapshot;
+use store::schema::TIME_COL_NAME;
+use store::table::{Table, TableBlock};
+use store::Store;
+
+pub struct Planner {
+    /// current time, for ranges like `[5m]`
+    now: i32,
+}
+
+impl Default for Planner {
+    fn default() -> Self {
+        Self { now: unix_now() }
+    }
+}
+
+impl Planner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// plans as if it was `now`, in seconds
+    pub fn now(mut self, now: i32) -> Self {
+        self.now = now;
+        self
+    }
+
+    pub fn plan(&self, table: &Table, query: &Query) -> Result<Plan> {
+        self.plan_at(table, &table.snapshot(), query)
+    }
+
+    /// Plans `query` against the blocks of `snapshot`, a version of `table`
+    pub fn plan_at(&self, table: &Table, snapshot: &Snapshot, query: &Query) -> Result<Plan> {
+        if table.name() != query.table {
+            return Err(anyhow!(
+                "Query is on table {}, not {}",
+                query.table,
+                table.name()
+            ));
+        }
+        let schema = table.schema().arrow_schema();
+        let string_columns = schema
+            .fields()
+            .iter()
+            .filter(|f| f.data_type() == &ArrowDataType::Utf8)
+            .map(|f| f.name().clone())
+            .collect::<Vec<_>>();
+
+        let mut lookups = vec![];
+        let mut filters = vec![];
+        let mut projection = None;
+        let mut aggregation = None;
+        let mut limit: Option<usize> = None;
+
+        let range = query.range.map(|range| range.bounds(self.now));
+        if let Some((start, end)) = range {
+            if schema.index_of(TIME_COL_NAME).is_ok() {
+                filters.push(Filter::Time(start, end));
+            }
+        }
+
+        // selector matchers are field filters on string columns
+        let matchers = query.matchers.iter().map(|m| {
+            let op = match m.op {
+                MatchOp::Eq => CmpOp::Eq,
+                MatchOp::Neq => CmpOp::Neq,
+                MatchOp::Re => CmpOp::Re,
+                MatchOp::NotRe => CmpOp::NotRe,
+            };
+            (m.field.as_str(), op, Value::Str(m.value.clone()), true)
+        });
+        let stages = query.pipeline.iter().filter_map(|stage| match stage {
+            Stage::Filter(f) => Some((f.field.as_str(), f.op, f.value.clone(), false)),
+            _ => None,
+        });
+        for (field, op, value, selector) in matchers.chain(stages) {
+            let data_type = schema
+                .field_with_name(field)
+                .map_err(|_| anyhow!("Unknown field: {}", field))?
+                .data_type();
+            if selector && data_type != &ArrowDataType::Utf8 {
+                return Err(anyhow!(
+                    "Field {} isn't a string, filter it with `| {} {} ...`",
+                    field,
+                    field,
+                    op.as_str()
+                ));
+            }
+            let filter = Filter::field(field, op, value, data_type)?;
+            if let Some(lookup) = filter.lookup() {
+                lookups.push(lookup);
+            }
+            filters.push(filter);
+        }
+
+        for stage in &query.pipeline {
+            match stage {
+                Stage::Line(line) => {
+                    let filter = Filter::line(line.op, &line.value)?;
+                    if let Some(lookup) = filter.lookup() {
+                        lookups.push(lookup);
+                    }
+                    filters.push(filter);
+                }
+                Stage::Fields(fields) => {
+                    for field in fields {
+                        schema
+                            .field_with_name(field)
+                            .map_err(|_| anyhow!("Unknown field: {}", field))?;
+                    }
+                    projection = Some(fields.clone());
+                }
+                Stage::Limit(n) => limit = Some(limit.map_or(*n, |l| l.min(*n))),
+                Stage::Aggregate(a) => aggregation = Some(a.clone()),
+                Stage::Filter(_) => {}
+            }
+        }
+
+        let (projection, output, read) = match &aggregation {
+            Some(aggregation) => {
+                if projection.is_some() {
+                    return Err(anyhow!("Can't pick the fields of an aggregation"));
+                }
+                if let (AggFunc::Rate, None, None) = (&aggregation.func, aggregation.step, range) {
+                    return Err(anyhow!("rate needs a step or a time range"));
+                }
+                let output = agg::output_schema(aggregation, &schema)?;
+                let projection = output.fields().iter().map(|f| f.name().clone()).collect();
+                (projection, output, agg::columns(aggregation))
+            }
+            None => {
+                let projection = projection
+                    .unwrap_or_else(|| schema.fields().iter().map(|f| f.name().clone()).collect());
+                let output = Arc::new(ArrowSchema::new(
+                    projection
+                        .iter()
+                        .map(|name| schema.field_with_name(name).cloned())
+                        .collect::<std::result::Result<Vec<_>, _>>()?,
+                ));
+                let read = projection.clone();
+                (projection, output, read)
+            }
+        };
+
+        // columns to read, in table order
+        let scan = schema
+            .fields()
+            .iter()
+            .map(|f| f.name())
+            .filter(|name| {
+                read.contains(name)
+                    || filters.iter().any(|f| match f {
+                        Filter::Time(..) => *name == TIME_COL_NAME,
+                        Filter::Line { .. } => string_columns.contains(name),
+                        Filter::Field { field, .. } => field == *name,
+                    })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let (blocks, pruned): (Vec<_>, Vec<_>) =
+            snapshot
+                .blocks()
+                .iter()
+                .cloned()
+                .partition(|block| match range {
+                    Some((start, end)) => block.overlaps(TIME_COL_NAME, start, end),
+                    None => true,
+                });
+
+        Ok(Plan {
+            query: query.to_string(),
+            table: table.name().to_owned(),
+            range,
+            blocks,
+            pruned,
+            lookups,
+            filters,
+            scan,
+            projection,
+            output,
+            aggregation,
+            limit,
+        })
+    }
+}
+
+/// Current time in seconds, as stored in time columns
+pub(crate) fn unix_now() -> i32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().min(i32::MAX as u64) as i32)
+}
+
+/// Trigram query narrowing rows down with the postings of `field`, or of
+/// every string column
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub field: Option<String>,
+    pub trigrams: TrigramQuery,
+}
+
+/// Checked on every candidate row
+#[derive(Debug, Clone)]
+pub enum Filter {
+    /// time column in `[start, end)`
+    Time(i32, i32),
+    /// on every string column of a row, a null column never matches
+    Line {
+        op: LineOp,
+        value: String,
+        regex: Option<Regex>,
+    },
+    /// `value` has the type of the column, regexes are anchored. Null values
+    /// only match `!=` & `!~`.
+    Field {
+        field: String,
+        op: CmpOp,
+        value: Value,
+        regex: Option<Regex>,
+    },
+}
+
+impl Filter {
+    fn line(op: LineOp, value: &str) -> Result<Self> {
+        let regex = match op {
+            LineOp::Match | LineOp::NotMatch => Some(Regex::new(value)?),
+            LineOp::Contains | LineOp::NotContains => None,
+        };
+        Ok(Filter::Line {
+            op,
+            value: value.to_owned(),
+            regex,
+        })
+    }
+
+    fn field(field: &str, op: CmpOp, value: Value, data_type: &ArrowDataType) -> Result<Self> {
+        let value = match (data_type, value) {
+            (ArrowDataType::Utf8, Value::Int(n)) => Value::Str(n.to_string()),
+            (ArrowDataType::Int32, Value::Str(s)) if op != CmpOp::Re && op != CmpOp::NotRe => {
+                let n = s
+                    .parse()
+                    .map_err(|_| anyhow!("Field {} is a number, not {:?}", field, s))?;
+                Value::Int(n)
+            }
+            (_, value) => value,
+        };
+        let regex = match (op, &value) {
+            (CmpOp::Re, Value::Str(re)) | (CmpOp::NotRe, Value::Str(re)) => {
+                Some(Regex::new(&anchored(re))?)
+            }
+            _ => None,
+        };
+        Ok(Filter::Field {
+            field: field.to_owned(),
+            op,
+            value,
+            regex,
+        })
+    }
+
+    /// the trigram lookup every row passing the filter passes
+    fn lookup(&self) -> Option<Lookup> {
+        let (field, trigrams) = match self {
+            Filter::Line {
+                op: LineOp::Contains,
+                value,
+                ..
+            } => (None, literal_query(value)),
+            Filter::Line {
+                op: LineOp::Match,
+                value,
+                ..
+            } => (None, RegexQuery::new(value).ok()?.trigrams().clone()),
+            Filter::Field {
+                field,
+                op: CmpOp::Eq,
+                value: Value::Str(value),
+                ..
+            } => (Some(field.clone()), literal_query(value)),
+            Filter::Field {
+                field,
+                op: CmpOp::Re,
+                value: Value::Str(value),
+                ..
+            } => (
+                Some(field.clone()),
+                RegexQuery::new(&anchored(value)).ok()?.trigrams().clone(),
+            ),
+            _ => return None,
+        };
+        if trigrams == TrigramQuery::All {
+            return None;
+        }
+        Some(Lookup { field, trigrams })
+    }
+}
+
+fn anchored(regex: &str) -> String {
+    format!("^(?:{})$", regex)
+}
+
+pub struct Plan {
+    query: String,
+    table: String,
+    /// `[start, end)` of the time range
+    range: Option<(i32, i32)>,
+    blocks: Vec<Arc<TableBlock>>,
+    /// blocks outside of the time range
+    pruned: Vec<Arc<TableBlock>>,
+    lookups: Vec<Lookup>,
+    filters: Vec<Filter>,
+    /// columns read from the blocks
+    scan: Vec<String>,
+    /// columns returned
+    projection: Vec<String>,
+    output: SchemaRef,
+    aggregation: Option<Aggregation>,
+    /// of the rows returned, aggregated or not
+    limit: Option<usize>,
+}
+
+impl Plan {
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn range(&self) -> Option<(i32, i32)> {
+        self.range
+    }
+
+    /// blocks to search
+    pub fn blocks(&self) -> &[Arc<TableBlock>] {
+        &self.blocks
+    }
+
+    /// blocks skipped as they can't have rows in the time range
+    pub fn pruned(&self) -> &[Arc<TableBlock>] {
+        &self.pruned
+    }
+
+    pub fn lookups(&self) -> &[Lookup] {
+        &self.lookups
+    }
+
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+
+    pub fn scan(&self) -> &[String] {
+        &self.scan
+    }
+
+    pub fn projection(&self) -> &[String] {
+        &self.projection
+    }
+
+    /// schema of the batches returned
+    pub fn output(&self) -> SchemaRef {
+        self.output.clone()
+    }
+
+    pub fn aggregation(&self) -> Option<&Aggregation> {
+        self.aggregation.as_ref()
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Rows of `block` every lookup keeps
+    pub fn candidates<S: Store>(&self, store: &S, block: &TableBlock) -> Result<RoaringBitmap> {
+        let mut rows = (0..block.rows() as u32).collect::<RoaringBitmap>();
+        for lookup in &self.lookups {
+            if rows.is_empty() {
+                break;
+            }
+            rows &= candidates(
+                store,
+                &self.table,
+                block,
+                lookup.field.as_deref(),
+                &lookup.trigrams,
+            )?;
+        }
+        Ok(rows)
+    }
+
+    /// The plan & the candidates of every block
+    pub fn explain<S: Store>(&self, store: &S) -> Result<String> {
+        let mut out = self.to_string();
+        writeln!(out, "Candidates:")?;
+        for block in &self.blocks {
+            let candidates = self.candidates(store, block)?;
+            writeln!(
+                out,
+                "  {}: {} of {} rows",
+                block.name(),
+                candidates.len(),
+                block.rows()
+            )?;
+        }
+        Ok(out)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Query: {}", self.query)?;
+        writeln!(f, "Table: {}", self.table)?;
+        if let Some((start, end)) = self.range {
+            writeln!(f, "Time range: [{}, {})", start, end)?;
+        }
+        let names = |blocks: &[Arc<TableBlock>]| {
+            blocks
+                .iter()
+                .map(|b| b.name().to_owned())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(
+            f,
+            "Blocks: {} of {}, pruned by time: [{}]",
+            self.blocks.len(),
+            self.blocks.len() + self.pruned.len(),
+            names(&self.pruned)
+        )?;
+        writeln!(f, "Index lookups:")?;
+        for lookup in &self.lookups {
+            let field = lookup.field.as_deref().unwrap_or("*");
+            writeln!(f, "  {}: {}", field, lookup.trigrams)?;
+        }
+        writeln!(f, "Filters:")?;
+        for filter in &self.filters {
+            writeln!(f, "  {}", filter)?;
+        }
+        writeln!(f, "Scan: {}", self.scan.join(", "))?;
+        if let Some(aggregation) = &self.aggregation {
+            writeln!(f, "Aggregate: {}", aggregation)?;
+        }
+        writeln!(f, "Output: {}", self.projection.join(", "))?;
+        if let Some(limit) = self.limit {
+            writeln!(f, "Limit: {}", limit)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Time(start, end) => write!(f, "{} in [{}, {})", TIME_COL_NAME, start, end),
+            Filter::Line { op, value, .. } => write!(f, "line {} {:?}", op.as_str(), value),
+            Filter::Field {
+                field, op, value, ..
+            } => write!(f, "{} {} {}", field, op.as_str(), value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use pretty_assertions::assert_eq;
+    use store::builder::SchemaBuilder;
+    use store::schema::FieldType;
+
+    #[test]
+    fn plan_test() {
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("message", FieldType::Str)
+            .indexed_field("status", FieldType::Str)
+            .field("latency", FieldType::Int)
+            .build()
+            .unwrap();
+        let table = Table::new("plan_table", schema);
+        let planner = Planner::new().now(1000);
+        let plan = |query: &str| planner.plan(&table, &parse(query).unwrap());
+
+        let p = plan(
+            r#"plan_table{status="500", message=~"GET .*"} |= "timeout" != "health" | latency >= 200 | status = 500 | fields message [10, 20]"#,
+        )
+        .unwrap();
+        assert_eq!(p.range(), Some((10, 20)));
+        assert_eq!(
+            p.lookups()
+                .iter()
+                .map(|l| l.field.as_deref())
+                .collect::<Vec<_>>(),
+            vec![Some("status"), Some("message"), Some("status"), None]
+        );
+        assert_eq!(p.lookups()[0].trigrams.to_string(), r#""500""#);
+        assert_eq!(
+            p.filters()
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "time in [10, 20)",
+                r#"status = "500""#,
+                r#"message =~ "GET .*""#,
+                "latency >= 200",
+                r#"status = "500""#,
+                r#"line |= "timeout""#,
+                r#"line != "health""#,
+            ]
+        );
+        // line filters read every string column
+        assert_eq!(p.scan(), ["time", "message", "status", "latency"]);
+        assert_eq!(p.projection(), ["message"]);
+        assert_eq!(p.output().field(0).name(), "message");
+
+        let p =
+            plan("plan_table | latency > 5 | fields message | limit 10 | limit 3 [1m]").unwrap();
+        assert_eq!(p.range(), Some((940, 1001)));
+        assert!(p.lookups().is_empty());
+        assert_eq!(p.scan(), ["time", "message", "latency"]);
+        assert_eq!(p.limit(), Some(3));
+
+        let error = |query: &str| plan(query).err().unwrap().to_string();
+        assert_eq!(
+            error("plan_table | method = \"GET\""),
+            "Unknown field: method"
+        );
+        assert_eq!(error("plan_table | fields method"), "Unknown field: method");
+        assert_eq!(
+            error("plan_table{latency=\"5\"}"),
+            "Field latency isn't a string, filter it with `| latency = ...`"
+        );
+        assert_eq!(
+            error("plan_table | latency > \"fast\""),
+            "Field latency is a number, not \"fast\""
+        );
+        assert_eq!(error("nginx"), "Query is on table nginx, not plan_table");
+
+        let p =
+            plan("plan_table |= \"GET\" | quantile(0.99, latency, 5m) by (status) [1h]").unwrap();
+        assert_eq!(p.scan(), ["time", "message", "status", "latency"]);
+        assert_eq!(p.projection(), ["time", "status", "quantile"]);
+        assert!(p
+            .to_string()
+            .contains("Aggregate: quantile(0.99, latency, 5m) by (status)\n"));
+        let p = plan("plan_table | topk(3) by (status)").unwrap();
+        assert_eq!(p.scan(), ["status"]);
+        assert_eq!(p.projection(), ["status", "count"]);
+        assert_eq!(
+            error("plan_table | rate()"),
+            "rate needs a step or a time range"
+        );
+        assert_eq!(
+            error("plan_table | fields status | count_over_time()"),
+            "Can't pick the fields of an aggregation"
+        );
+        assert_eq!(
+            error("plan_table | quantile(0.5, status)"),
+            "Field status isn't a number"
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! SQL over log tables, with datafusion
+//!
+//! `LogTable` is a datafusion `TableProvider` reading only the projected
+//! columns of a table. Filters comparing a column with a literal are pushed
+//! down into a log query, so time ranges prune blocks and `=`, `LIKE` look
+//! their trigrams up in the index before any row is scanned:
+//!
+//! ```text
+//! SELECT status, count(*) FROM apache WHERE time > now() - 3600 AND message LIKE '%timeout%' GROUP BY status
+//! ```
+//!
+//! Times are in seconds, `now()` being the time of the query. Filters which
+//! can't be pushed down are applied by datafusion on the scanned rows, but
+//! `now()` can only be used in pushed down ones.
+//!
+//! Scans are planned up front but read lazily, a partition per block, so
+//! `EXPLAIN` reads nothing.
+
+use crate::ast::{CmpOp, FieldFilter, Query, Stage, TimeRange, Value};
+use crate::exec::execute_block;
+use crate::plan::{unix_now, Plan, Planner};
+use anyhow::Result;
+use arrow::array::ArrayRef;
+use arrow::datatypes::{DataType as ArrowDataType, SchemaRef};
+use arrow::error::Result as ArrowResult;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::datasource::datasource::{Statistics, TableProvider, TableProviderFilterPushDown};
+use datafusion::error::{DataFusionError, Result as DataFusionResult};
+use datafusion::execution::context::ExecutionContext;
+use datafusion::logical_plan::{create_udf, Expr, Operator};
+use datafusion::optimizer::utils::expr_sub_expressions;
+use datafusion::physical_plan::udf::ScalarUDF;
+use datafusion::physical_plan::{
+    ExecutionPlan, Partitioning, RecordBatchStream, SendableRecordBatchStream,
+};
+use datafusion::scalar::ScalarValue;
+use futures::stream::Stream;
+use std::any::Any;
+use std::convert::TryFrom;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::vec;
+use store::schema::TIME_COL_NAME;
+use store::table::Table;
+use store::Store;
+
+/// Name of the current time function
+pub const NOW: &str = "now";
+
+/// Registers table `name` of `store` in `ctx`, along with `now()`
+pub fn register<S: Store + Send + Sync + 'static>(
+    ctx: &mut ExecutionContext,
+    store: Arc<S>,
+    name: &str,
+) -> Result<()> {
+    let table = LogTable::open(store, name)?;
+    ctx.register_table(name, Box::new(table));
+    ctx.register_udf(now_udf());
+    Ok(())
+}
+
+/// `now()`, the time of the query in seconds. Only known to the filters
+/// pushed down into a scan: datafusion doesn't give functions without
+/// arguments a row count, so evaluating it fails.
+pub fn now_udf() -> ScalarUDF {
+    create_udf(
+        NOW,
+        vec![],
+        Arc::new(ArrowDataType::Int32),
+        Arc::new(|_: &[ArrayRef]| Err(now_error())),
+    )
+}
+
+fn now_error() -> DataFusionError {
+    DataFusionError::Plan(format!(
+        "{}() can only be compared with a column, in filters pushed down to a log table",
+        NOW
+    ))
+}
+
+pub struct LogTable<S> {
+    store: Arc<S>,
+    table: Arc<Table>,
+    schema: SchemaRef,
+    /// `now()` of the query being planned, read by its first filter pushdown
+    /// & taken by its scan, so both see the same time
+    now: Mutex<Option<i32>>,
+}
+
+/// Filter pushed down into the log query of a scan
+#[derive(Debug, Clone, PartialEq)]
+enum Pushdown {
+    /// time column in `[start, end)`
+    Time(i64, i64),
+    Field(FieldFilter),
+}
+
+impl<S: Store> LogTable<S> {
+    pub fn new(store: Arc<S>, table: Arc<Table>) -> Self {
+        let schema = table.schema().arrow_schema();
+        Self {
+            store,
+            table,
+            schema,
+            now: Mutex::new(None),
+        }
+    }
+
+    pub fn open(store: Arc<S>, name: &str) -> Result<Self> {
+        let table = Table::open(store.as_ref(), name)?;
+        Ok(Self::new(store, Arc::new(table)))
+    }
+
+    /// Log query of a scan, `now` being the current time
+    fn query(&self, projection: &Option<Vec<usize>>, filters: &[Expr], now: i32) -> Query {
+        let mut pipeline = vec![];
+        let mut range: Option<(i64, i64)> = None;
+        for filter in filters {
+            match self.pushdown(filter, now) {
+                Some(Pushdown::Time(start, end)) => {
+                    range = Some(match range {
+                        Some((s, e)) => (s.max(start), e.min(end)),
+                        None => (start, end),
+                    })
+                }
+                Some(Pushdown::Field(filter)) => pipeline.push(Stage::Filter(filter)),
+                None => {}
+            }
+        }
+        if let Some(projection) = projection {
+            // datafusion reads at least a column, even for `count(*)`
+            let fields = self.schema.fields();
+            let indices = if projection.is_empty() {
+                vec![0]
+            } else {
+                projection.clone()
+            };
+            pipeline.push(Stage::Fields(
+                indices.iter().map(|&i| fields[i].name().clone()).collect(),
+            ));
+        }
+
+        let clamp = |t: i64| t.max(i32::MIN as i64).min(i32::MAX as i64) as i32;
+        Query {
+            explain: false,
+            table: self.table.name().to_owned(),
+            matchers: vec![],
+            pipeline,
+            range: range.map(|(start, end)| TimeRange::Between(clamp(start), clamp(end))),
+        }
+    }
+
+    /// `column op literal`, or the other way around
+    fn pushdown(&self, filter: &Expr, now: i32) -> Option<Pushdown> {
+        let (column, op, value) = match filter {
+            Expr::BinaryExpr { left, op, right } => match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(column), value) => (column, cmp_op(op)?, value),
+                (value, Expr::Column(column)) => (column, flip(cmp_op(op)?)?, value),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let data_type = self.schema.field_with_name(column).ok()?.data_type();
+
+        if column == TIME_COL_NAME {
+            let t = constant(value, now)?;
+            return match op {
+                CmpOp::Eq => Some(Pushdown::Time(t, t.saturating_add(1))),
+                CmpOp::Gt => Some(Pushdown::Time(t.saturating_add(1), i64::MAX)),
+                CmpOp::Gte => Some(Pushdown::Time(t, i64::MAX)),
+                CmpOp::Lt => Some(Pushdown::Time(i64::MIN, t)),
+                CmpOp::Lte => Some(Pushdown::Time(i64::MIN, t.saturating_add(1))),
+                _ => None,
+            };
+        }
+        let value = match (data_type, op, value) {
+            (ArrowDataType::Utf8, CmpOp::Re, Expr::Literal(ScalarValue::Utf8(Some(s))))
+            | (ArrowDataType::Utf8, CmpOp::NotRe, Expr::Literal(ScalarValue::Utf8(Some(s)))) => {
+                Value::Str(like_regex(s))
+            }
+            (ArrowDataType::Utf8, _, Expr::Literal(ScalarValue::Utf8(Some(s)))) => {
+                Value::Str(s.clone())
+            }
+            (ArrowDataType::Int32, CmpOp::Re, _) | (ArrowDataType::Int32, CmpOp::NotRe, _) => {
+                return None
+            }
+            (ArrowDataType::Int32, _, value) => {
+                Value::Int(i32::try_from(constant(value, now)?).ok()?)
+            }
+            _ => return None,
+        };
+        Some(Pushdown::Field(FieldFilter {
+            field: column.clone(),
+            op,
+            value,
+        }))
+    }
+}
+
+impl<S: Store + Send + Sync + 'static> TableProvider for LogTable<S> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn scan(
+        &self,
+        projection: &Option<Vec<usize>>,
+        _batch_size: usize,
+        filters: &[Expr],
+    ) -> DataFusionResult<Arc<dyn ExecutionPlan>> {
+        let now = self.now.lock().unwrap().take().unwrap_or_else(unix_now);
+        let query = self.query(projection, filters, now);
+        let plan = Planner::new()
+            .now(now)
+            .plan(&self.table, &query)
+            .map_err(|e| DataFusionError::Plan(e.to_string()))?;
+        Ok(Arc::new(LogTableExec {
+            store: self.store.clone(),
+            plan: Arc::new(plan),
+        }))
+    }
+
+    fn statistics(&self) -> Statistics {
+        let rows = self
+            .table
+            .snapshot()
+            .blocks()
+            .iter()
+            .map(|b| b.rows())
+            .sum();
+        Statistics {
+            num_rows: Some(rows),
+            ..Default::default()
+        }
+    }
+
+    fn supports_filter_pushdown(
+        &self,
+        filter: &Expr,
+    ) -> DataFusionResult<TableProviderFilterPushDown> {
+        let now = *self.now.lock().unwrap().get_or_insert_with(unix_now);
+        let support = match self.pushdown(filter, now) {
+            // log queries match nulls with `!=`, unlike sql
+            Some(Pushdown::Field(FieldFilter { op: CmpOp::Neq, .. }))
+            | Some(Pushdown::Field(FieldFilter {
+                op: CmpOp::NotRe, ..
+            })) => TableProviderFilterPushDown::Inexact,
+            Some(_) => return Ok(TableProviderFilterPushDown::Exact),
+            None => TableProviderFilterPushDown::Unsupported,
+        };
+        // datafusion would evaluate it
+        if calls_now(filter)? {
+            return Err(now_error());
+        }
+        Ok(support)
+    }
+}
+
+/// Scan of a log table, partition `i` searching block `i` of the plan
+pub struct LogTableExec<S> {
+    store: Arc<S>,
+    plan: Arc<Plan>,
+}
+
+impl<S> fmt::Debug for LogTableExec<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogTableExec")
+            .field("table", &self.plan.table())
+            .field("blocks", &self.plan.blocks().len())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<S: Store + Send + Sync + 'static> ExecutionPlan for LogTableExec<S> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.plan.output()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(self.plan.blocks().len().max(1))
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> DataFusionResult<Arc<dyn ExecutionPlan>> {
+        if children.is_empty() {
+            Ok(Arc::new(LogTableExec {
+                store: self.store.clone(),
+                plan: self.plan.clone(),
+            }))
+        } else {
+            Err(DataFusionError::Internal(format!(
+                "Children cannot be replaced in {:?}",
+                self
+            )))
+        }
+    }
+
+    async fn execute(&self, partition: usize) -> DataFusionResult<SendableRecordBatchStream> {
+        let batches = match self.plan.blocks().get(partition) {
+            Some(block) => execute_block(self.store.as_ref(), &self.plan, block, usize::MAX)
+                .map_err(|e| DataFusionError::Execution(e.to_string()))?,
+            None => vec![],
+        };
+        Ok(Box::pin(BatchStream {
+            schema: self.plan.output(),
+            batches: batches.into_iter(),
+        }))
+    }
+}
+
+/// Batches of a block, already read
+struct BatchStream {
+    schema: SchemaRef,
+    batches: vec::IntoIter<RecordBatch>,
+}
+
+impl Stream for BatchStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.batches.next().map(Ok))
+    }
+}
+
+impl RecordBatchStream for BatchStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+fn cmp_op(op: &Operator) -> Option<CmpOp> {
+    Some(match op {
+        Operator::Eq => CmpOp::Eq,
+        Operator::NotEq => CmpOp::Neq,
+        Operator::Gt => CmpOp::Gt,
+        Operator::GtEq => CmpOp::Gte,
+        Operator::Lt => CmpOp::Lt,
+        Operator::LtEq => CmpOp::Lte,
+        Operator::Like => CmpOp::Re,
+        Operator::NotLike => CmpOp::NotRe,
+        _ => return None,
+    })
+}
+
+/// `op` of `literal op column`, as `column op literal`
+fn flip(op: CmpOp) -> Option<CmpOp> {
+    match op {
+        CmpOp::Gt => Some(CmpOp::Lt),
+        CmpOp::Gte => Some(CmpOp::Lte),
+        CmpOp::Lt => Some(CmpOp::Gt),
+        CmpOp::Lte => Some(CmpOp::Gte),
+        CmpOp::Re | CmpOp::NotRe => None,
+        op => Some(op),
+    }
+}
+
+/// true if `expr` calls `now()` anywhere
+fn calls_now(expr: &Expr) -> DataFusionResult<bool> {
+    if let Expr::ScalarUDF { fun, .. } = expr {
+        if fun.name == NOW {
+            return Ok(true);
+        }
+    }
+    for expr in expr_sub_expressions(expr)? {
+        if calls_now(&expr)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Value of an integer expression of literals and `now()`
+fn constant(expr: &Expr, now: i32) -> Option<i64> {
+    match expr {
+        Expr::Literal(ScalarValue::Int32(Some(n))) => Some(*n as i64),
+        Expr::Literal(ScalarValue::Int64(Some(n))) => Some(*n),
+        Expr::ScalarUDF { fun, args } if fun.name == NOW && args.is_empty() => Some(now as i64),
+        Expr::BinaryExpr { left, op, right } => {
+            let (left, right) = (constant(left, now)?, constant(right, now)?);
+            match op {
+                Operator::Plus => left.checked_add(right),
+                Operator::Minus => left.checked_sub(right),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Regex matching what the `LIKE` pattern does, `%` being any string and
+/// `_` any char, newlines included
+fn like_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?s)");
+    for c in pattern.chars() {
+        match c {
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::execute;
+    use crate::test_util::{log_table, store};
+    use arrow::array::{Array, Int32Array, StringArray, UInt64Array};
+    use datafusion::logical_plan::{binary_expr, col, lit};
+    use datafusion::physical_plan::collect;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn log_table_test() {
+        let store = Arc::new(store("sql_table"));
+        let table = log_table(
+            store.as_ref(),
+            "sql_table",
+            &[
+                (
+                    0,
+                    ["GET /index ok", "GET /login timeout", "POST /login ok"],
+                    ["200", "504", "200"],
+                    [10, 3000, 20],
+                ),
+                (
+                    100,
+                    ["GET /api\ntimeout", "GET /api ok", "DELETE /api Timeout"],
+                    ["504", "200", "500"],
+                    [2500, 15, 40],
+                ),
+            ],
+        );
+        let provider = LogTable::new(store.clone(), Arc::new(table));
+
+        let now_expr = Expr::ScalarUDF {
+            fun: Arc::new(now_udf()),
+            args: vec![],
+        };
+        let filters = vec![
+            col("time").gt(binary_expr(now_expr.clone(), Operator::Minus, lit(10))),
+            binary_expr(col("message"), Operator::Like, lit("%time_ut%")),
+            lit(20).lt_eq(col("latency")),
+            col("status").not_eq(lit("200")),
+            binary_expr(col("latency"), Operator::Plus, lit(1)).gt(lit(3)),
+        ];
+        assert_eq!(
+            filters
+                .iter()
+                .map(|f| format!("{:?}", provider.supports_filter_pushdown(f).unwrap()))
+                .collect::<Vec<_>>(),
+            vec!["Exact", "Exact", "Exact", "Inexact", "Unsupported"]
+        );
+        // datafusion can't evaluate now()
+        let unsupported = binary_expr(col("time"), Operator::Plus, lit(1)).gt(now_expr.clone());
+        assert!(provider.supports_filter_pushdown(&unsupported).is_err());
+        let inexact = col("latency").not_eq(now_expr);
+        assert!(provider.supports_filter_pushdown(&inexact).is_err());
+
+        let query = provider.query(&Some(vec![1, 3]), &filters, 102);
+        assert_eq!(
+            query.to_string(),
+            r#"sql_table | message =~ "(?s).*time.ut.*" | latency >= 20 | status != "200" | fields message, latency [93, 2147483647]"#
+        );
+        let plan = Planner::new().plan(&provider.table, &query).unwrap();
+        assert_eq!(plan.pruned().len(), 1);
+        let batches = execute(store.as_ref(), &plan).unwrap();
+        assert_eq!(batches.len(), 1);
+        let messages = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let latency = batches[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            (messages.value(0), latency.value(0)),
+            ("GET /api\ntimeout", 2500)
+        );
+
+        let query = provider.query(&Some(vec![]), &[], 0);
+        assert_eq!(query.to_string(), "sql_table | fields time");
+
+        // a partition per block, read once executed. The scan ends the
+        // query, the next one gets a new now()
+        assert!(provider.now.lock().unwrap().is_some());
+        let scan = provider.scan(&Some(vec![2]), 1024, &[]).unwrap();
+        assert_eq!(scan.output_partitioning().partition_count(), 2);
+        assert!(provider.now.lock().unwrap().is_none());
+
+        let mut ctx = ExecutionContext::new();
+        register(&mut ctx, store.clone(), "sql_table").unwrap();
+        let sql = "SELECT status, COUNT(*) FROM sql_table WHERE latency > 15 AND time < now() GROUP BY status ORDER BY status";
+        let plan = ctx.create_logical_plan(sql).unwrap();
+        let plan = ctx.optimize(&plan).unwrap();
+        let plan = ctx.create_physical_plan(&plan).unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let batches = runtime.block_on(collect(plan)).unwrap();
+        let mut rows = vec![];
+        for batch in &batches {
+            let status = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            let count = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap();
+            for i in 0..batch.num_rows() {
+                rows.push((status.value(i).to_owned(), count.value(i)));
+            }
+        }
+        assert_eq!(
+            rows,
+            vec![
+                ("200".to_owned(), 1),
+                ("500".to_owned(), 1),
+                ("504".to_owned(), 2),
+            ]
+        );
+
+        let sql = "SELECT status FROM sql_table WHERE time + 1 > now()";
+        let plan = ctx.create_logical_plan(sql).unwrap();
+        let err = ctx.optimize(&plan).unwrap_err();
+        assert!(
+            err.to_string().contains("now() can only be compared"),
+            "{}",
+            err
+        );
+
+        store.clean("sql_table").unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Literal substring search over trigram postings
+//!
+//! The needle is split into trigrams by the same `trigram` analyzer used at
+//! index time, so the lookup is always case-insensitive; rows holding all of
+//! them are then checked against the raw line, honoring `case_sensitive`.
+//! Needles shorter than a trigram can't use the index and scan the block.
+//! Like `RegexQuery`, it searches every string column unless given a `field`.
+
+use crate::trigram::{candidates, literal_query, verify, TrigramQuery};
+use anyhow::Result;
+use roaring::RoaringBitmap;
+use store::table::TableBlock;
+use store::Store;
+
+pub struct SubstringQuery {
+    needle: String,
+    /// lowercased needle, for case-insensitive matching
+    folded: String,
+    case_sensitive: bool,
+    trigrams: TrigramQuery,
+    field: Option<String>,
+}
+
+impl SubstringQuery {
+    /// Case-insensitive search of `needle`
+    pub fn new(needle: &str) -> Self {
+        Self {
+            needle: needle.to_owned(),
+            folded: needle.to_lowercase(),
+            case_sensitive: false,
+            trigrams: literal_query(needle),
+            field: None,
+        }
+    }
+
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    /// only searches column `name` instead of every string column
+    pub fn field(mut self, name: &str) -> Self {
+        self.field = Some(name.to_owned());
+        self
+    }
+
+    pub fn needle(&self) -> &str {
+        &self.needle
+    }
+
+    pub fn trigrams(&self) -> &TrigramQuery {
+        &self.trigrams
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        if self.case_sensitive {
+            value.contains(&self.needle)
+        } else {
+            value.to_lowercase().contains(&self.folded)
+        }
+    }
+
+    /// Rows of `block` the postings can't rule out
+    pub fn candidates<S: Store>(
+        &self,
+        store: &S,
+        table: &str,
+        block: &TableBlock,
+    ) -> Result<RoaringBitmap> {
+        candidates(store, table, block, self.field.as_deref(), &self.trigrams)
+    }
+
+    /// Rows of `block` with a searched column containing the needle
+    pub fn search<S: Store>(
+        &self,
+        store: &S,
+        table: &str,
+        block: &TableBlock,
+    ) -> Result<RoaringBitmap> {
+        let candidates = self.candidates(store, table, block)?;
+        verify(store, block, self.field.as_deref(), &candidates, |value| {
+            self.is_match(value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{indexed_block, store};
+    use arrow::array::{Int32Array, StringArray};
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use store::builder::SchemaBuilder;
+    use store::schema::FieldType;
+
+    #[test]
+    fn substring_search_test() {
+        let store = store("substring_table");
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("message", FieldType::Str)
+            .indexed_field("useragent", FieldType::Str)
+            .build()
+            .unwrap();
+
+        let messages = vec![
+            "read: connection reset by peer",
+            "Connection Reset while writing",
+            "connection refused",
+            "ok",
+        ];
+        let block = indexed_block(
+            &store,
+            "substring_table",
+            "block",
+            &schema,
+            vec![
+                Arc::new(Int32Array::from(vec![0, 1, 2, 3])),
+                Arc::new(StringArray::from(messages)),
+                Arc::new(StringArray::from(vec![
+                    "curl/7.68.0",
+                    "Mozilla/5.0",
+                    "Mozilla/5.0",
+                    "curl/7.68.0",
+                ])),
+            ],
+        );
+
+        let rows = |query: &SubstringQuery| {
+            query
+                .search(&store, "substring_table", &block)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+
+        let query = SubstringQuery::new("connection reset");
+        let candidates = query.candidates(&store, "substring_table", &block).unwrap();
+        assert_eq!(candidates.iter().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(rows(&query), vec![0, 1]);
+        assert_eq!(rows(&query.case_sensitive(true)), vec![0]);
+
+        // too short for a trigram, every row is checked
+        let query = SubstringQuery::new("OK");
+        assert_eq!(query.trigrams(), &TrigramQuery::All);
+        assert_eq!(rows(&query), vec![3]);
+        assert_eq!(rows(&query.case_sensitive(true)), Vec::<u32>::new());
+
+        assert_eq!(rows(&SubstringQuery::new("timeout")), Vec::<u32>::new());
+
+        // each field has its own postings
+        assert_eq!(rows(&SubstringQuery::new("curl")), vec![0, 3]);
+        let query = SubstringQuery::new("curl").field("useragent");
+        let candidates = query.candidates(&store, "substring_table", &block).unwrap();
+        assert_eq!(candidates.iter().collect::<Vec<_>>(), vec![0, 3]);
+        assert_eq!(rows(&query), vec![0, 3]);
+        assert_eq!(
+            rows(&SubstringQuery::new("curl").field("message")),
+            Vec::<u32>::new()
+        );
+
+        store.clean("substring_table").unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! Live tail of log queries, `tail -f` with the filters of a query
+//!
+//! A tail subscribes to the table of its query on the writer, then yields
+//! the matching rows of:
+//!
+//! 1. the blocks of the table at that moment, searched like `exec` does
+//! 2. the rows still in memtables at that moment
+//! 3. every row written from then on, as it is logged
+//!
+//! Rows of 2 & 3 are checked against the same filters as the blocks, with
+//! `exec::filter_batch`. A range like `[5m]` starts 5 minutes before the tail
+//! and never ends. The stream ends at the limit or once the writer is
+//! dropped, and fails if the tail falls more than the writer's
+//! `tail_buffer` rows behind.
+
+use crate::ast::{Stage, TimeRange};
+use crate::exec::{execute_block, filter_batch};
+use crate::parser::parse;
+use crate::plan::{unix_now, Plan, Planner};
+use anyhow::{anyhow, Result};
+use arrow::record_batch::RecordBatch;
+use ingest::flush::{Subscription, Writer};
+use ingest::memtable::{MemTable, BATCH_SIZE};
+use std::collections::VecDeque;
+use std::vec;
+use store::schema::Schema;
+use store::Store;
+
+/// Starts tailing `query` over the rows of `writer`, whose blocks are in
+/// `store`
+pub fn tail<'a, W, S>(writer: &Writer<W>, store: &'a S, query: &str) -> Result<Tail<'a, S>>
+where
+    W: wal::Store,
+    S: Store,
+{
+    let mut query = parse(query)?;
+    if query.explain {
+        return Err(anyhow!("Can't tail an explain query"));
+    }
+    if query
+        .pipeline
+        .iter()
+        .any(|s| matches!(s, Stage::Aggregate(_)))
+    {
+        return Err(anyhow!("Can't tail an aggregation"));
+    }
+    let now = unix_now();
+    if let Some(TimeRange::Last(d)) = query.range {
+        let (start, _) = TimeRange::Last(d).bounds(now);
+        query.range = Some(TimeRange::Between(start, i32::MAX));
+    }
+
+    let subscription = writer.subscribe(&query.table)?;
+    let tables = writer.tables();
+    let table = &tables[&query.table];
+    let plan = Planner::new()
+        .now(now)
+        .plan_at(table, &subscription.snapshot, &query)?;
+
+    let unflushed = subscription.unflushed.clone().into_iter();
+    Ok(Tail {
+        store,
+        schema: table.schema().clone(),
+        left: plan.limit().unwrap_or(usize::MAX),
+        plan,
+        next_block: 0,
+        unflushed,
+        subscription,
+        pending: VecDeque::new(),
+    })
+}
+
+/// Matching rows, in batches of the plan's output schema
+pub struct Tail<'a, S> {
+    store: &'a S,
+    plan: Plan,
+    schema: Schema,
+    /// rows that can still be queued
+    left: usize,
+    next_block: usize,
+    unflushed: vec::IntoIter<RecordBatch>,
+    subscription: Subscription,
+    pending: VecDeque<RecordBatch>,
+}
+
+impl<'a, S: Store> Tail<'a, S> {
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        loop {
+            if let Some(batch) = self.pending.pop_front() {
+                return Ok(Some(batch));
+            }
+            if self.left == 0 {
+                return Ok(None);
+            }
+
+            if let Some(block) = self.plan.blocks().get(self.next_block) {
+                self.next_block += 1;
+                for batch in execute_block(self.store, &self.plan, block, self.left)? {
+                    self.queue(batch);
+                }
+                continue;
+            }
+            if let Some(batch) = self.unflushed.next() {
+                if let Some(batch) = filter_batch(&self.plan, &batch, self.left)? {
+                    self.queue(batch);
+                }
+                continue;
+            }
+
+            // waits for a row, then takes the ones already there too
+            let (lsn, row) = match self.subscription.recv()? {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            let mut memtable = MemTable::new();
+            memtable.insert(lsn, &self.schema, &row)?;
+            while memtable.len() < BATCH_SIZE {
+                match self.subscription.try_recv()? {
+                    Some((lsn, row)) => memtable.insert(lsn, &self.schema, &row)?,
+                    None => break,
+                }
+            }
+            for batch in memtable.batches(self.plan.table())? {
+                if let Some(batch) = filter_batch(&self.plan, &batch, self.left)? {
+                    self.queue(batch);
+                }
+            }
+        }
+    }
+
+    fn queue(&mut self, batch: RecordBatch) {
+        self.left -= batch.num_rows();
+        self.pending.push_back(batch);
+    }
+}
+
+impl<'a, S: Store> Iterator for Tail<'a, S> {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_batch() {
+            Ok(batch) => batch.map(Ok),
+            Err(e) => {
+                self.left = 0;
+                self.pending.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int32Array, StringArray};
+    use ingest::flush::WriterBuilder;
+    use ingest::fst::TermIndexer;
+    use ingest::row::{Row, Value};
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use store::builder::SchemaBuilder;
+    use store::schema::FieldType;
+    use store::FSBlobStore;
+    use wal::{MemStore, Wal};
+
+    #[test]
+    fn tail_test() {
+        let root = std::env::temp_dir().join("akiradb-query").join("tail_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let store = || FSBlobStore {
+            root: root.clone(),
+            blobs: vec![],
+        };
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("message", FieldType::Str)
+            .field("latency", FieldType::Int)
+            .build()
+            .unwrap();
+        let writer = WriterBuilder::new(Wal::new(MemStore::new()), store())
+            .table("api", schema)
+            .indexer(Arc::new(TermIndexer::new().unwrap()))
+            .build()
+            .unwrap();
+        let write = |time: i32, message: &str, latency: i32| {
+            let row = Row::new(
+                "api",
+                vec![
+                    Value::Int(time),
+                    Value::Str(message.into()),
+                    Value::Int(latency),
+                ],
+            );
+            writer.write(&row).unwrap();
+        };
+
+        write(1, "GET /api timeout", 3000);
+        write(2, "GET /api ok", 10);
+        writer.flush().unwrap();
+        write(3, "POST /api timeout", 2500);
+        write(4, "POST /api timeout", 20);
+
+        let store = store();
+        let query = r#"api |= "timeout" | latency > 100 | fields time, message"#;
+        let live = tail(&writer, &store, query).unwrap();
+        assert!(tail(&writer, &store, "api | count_over_time()").is_err());
+        assert!(tail(&writer, &store, "nginx").is_err());
+
+        // rows written once tailing are yielded after the earlier ones
+        write(5, "GET /health ok", 1000);
+        write(6, "DELETE /api timeout", 4000);
+        drop(writer);
+
+        let mut rows = vec![];
+        for batch in live {
+            let batch = batch.unwrap();
+            let time = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            let message = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            for i in 0..batch.num_rows() {
+                rows.push((time.value(i), message.value(i).to_owned()));
+            }
+        }
+        assert_eq!(
+            rows,
+            vec![
+                (1, "GET /api timeout".to_owned()),
+                (3, "POST /api timeout".to_owned()),
+                (6, "DELETE /api timeout".to_owned()),
+            ]
+        );
+    }
+}
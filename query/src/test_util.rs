@@ -0,0 +1,73 @@
+//! Indexed blocks & tables for the tests of the crate
+
+use arrow::array::{ArrayRef, Int32Array, StringArray};
+use arrow::record_batch::RecordBatch;
+use ingest::fst::TermIndexer;
+use std::sync::Arc;
+use store::builder::SchemaBuilder;
+use store::compaction::BlockIndexer;
+use store::manifest::TableEdit;
+use store::schema::{FieldType, Schema};
+use store::table::{Table, TableBlock};
+use store::{FSBlobStore, Store};
+
+/// Store under `./root`, without any file of `table`
+pub fn store(table: &str) -> FSBlobStore {
+    let store = FSBlobStore {
+        root: "./root".into(),
+        blobs: vec![],
+    };
+    store.clean(table).unwrap();
+    store
+}
+
+/// Writes block `name` of `table` out of `columns` & indexes it
+pub fn indexed_block(
+    store: &FSBlobStore,
+    table: &str,
+    name: &str,
+    schema: &Schema,
+    columns: Vec<ArrayRef>,
+) -> TableBlock {
+    let batch = RecordBatch::try_new(schema.arrow_schema(), columns).unwrap();
+    let batches = std::slice::from_ref(&batch);
+    let mut block = TableBlock::write(store, table, name, schema.clone(), batches).unwrap();
+    for path in TermIndexer::new()
+        .unwrap()
+        .index(store, table, &block, batches)
+        .unwrap()
+    {
+        block.add_index(path);
+    }
+    block
+}
+
+/// Rows of a block of `log_table`: the time of its first row, then the
+/// message, status & latency of each row
+pub type LogBlock<'a> = (i32, [&'a str; 3], [&'a str; 3], [i32; 3]);
+
+/// Access log table `name`, its blocks being `b0`, `b1`... Message & status
+/// are indexed.
+pub fn log_table(store: &FSBlobStore, name: &str, blocks: &[LogBlock]) -> Table {
+    let schema = SchemaBuilder::new()
+        .timestamp()
+        .indexed_field("message", FieldType::Str)
+        .indexed_field("status", FieldType::Str)
+        .field("latency", FieldType::Int)
+        .build()
+        .unwrap();
+    let table = Table::open_or_create(store, name, schema.clone()).unwrap();
+    let mut edit = TableEdit::new();
+    for (i, (time, messages, status, latency)) in blocks.iter().enumerate() {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(vec![*time, time + 1, time + 2])),
+            Arc::new(StringArray::from(messages.to_vec())),
+            Arc::new(StringArray::from(status.to_vec())),
+            Arc::new(Int32Array::from(latency.to_vec())),
+        ];
+        let block = indexed_block(store, name, &format!("b{}", i), &schema, columns);
+        edit = edit.add_block(block);
+    }
+    table.commit(store, edit).unwrap();
+    table
+}
@@ -0,0 +1,758 @@
+//! Regex search over trigram postings
+//!
+//! A regex is compiled into a boolean query of the trigrams every match has
+//! to contain, following Russ Cox's "Regular Expression Matching with a
+//! Trigram Index" (codesearch). Every node of the regex syntax tree is summed
+//! up by an `Info`:
+//!
+//! - `emptyable`: it can match the empty string
+//! - `exact`: all the strings it can match, while that set is small
+//! - `prefix` / `suffix`: strings every match starts / ends with
+//! - `query`: trigrams every match contains
+//!
+//! and the info of a node is computed from the ones of its children.
+//! Trigrams come from the `trigram` analyzer, so they are lowercased like the
+//! indexed ones. The query only narrows rows down to candidates using the
+//! postings of the searched column (or of every string column), candidates
+//! are then checked against the real regex.
+
+use anyhow::{anyhow, Result};
+use arrow::array::{Array, StringArray};
+use arrow::datatypes::DataType as ArrowDataType;
+use ingest::analyzer::{Analyzer, TRIGRAM};
+use ingest::postings::{term_path, PostingsReader};
+use regex::Regex;
+use regex_syntax::hir::{Class, Hir, HirKind, Literal, RepetitionKind, RepetitionRange};
+use roaring::RoaringBitmap;
+use std::collections::BTreeSet;
+use std::fmt;
+use store::table::TableBlock;
+use store::Store;
+
+const NGRAM: usize = 3;
+/// exact sets bigger than this are turned into a query
+const MAX_EXACT: usize = 16;
+/// prefix / suffix sets bigger than this are cut to `NGRAM - 1` chars
+const MAX_SET: usize = 32;
+/// classes with more chars than this are seen as "any char"
+const MAX_CLASS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrigramQuery {
+    /// any row can match
+    All,
+    And(Vec<TrigramQuery>),
+    Or(Vec<TrigramQuery>),
+    Trigram(String),
+}
+
+impl TrigramQuery {
+    pub fn and(self, other: TrigramQuery) -> TrigramQuery {
+        match (self, other) {
+            (TrigramQuery::All, q) | (q, TrigramQuery::All) => q,
+            (TrigramQuery::And(mut a), TrigramQuery::And(b)) => {
+                for q in b {
+                    if !a.contains(&q) {
+                        a.push(q);
+                    }
+                }
+                TrigramQuery::And(a)
+            }
+            (TrigramQuery::And(mut a), q) | (q, TrigramQuery::And(mut a)) => {
+                if !a.contains(&q) {
+                    a.push(q);
+                }
+                TrigramQuery::And(a)
+            }
+            (a, b) if a == b => a,
+            (a, b) => TrigramQuery::And(vec![a, b]),
+        }
+    }
+
+    pub fn or(self, other: TrigramQuery) -> TrigramQuery {
+        match (self, other) {
+            (TrigramQuery::All, _) | (_, TrigramQuery::All) => TrigramQuery::All,
+            (a, b) if a == b => a,
+            (a, b) if !a.is_or() && !b.is_or() => factor(a, b),
+            (TrigramQuery::Or(mut a), TrigramQuery::Or(b)) => {
+                for q in b {
+                    if !a.contains(&q) {
+                        a.push(q);
+                    }
+                }
+                TrigramQuery::Or(a)
+            }
+            (TrigramQuery::Or(mut a), q) | (q, TrigramQuery::Or(mut a)) => {
+                if !a.contains(&q) {
+                    a.push(q);
+                }
+                TrigramQuery::Or(a)
+            }
+            (a, b) => TrigramQuery::Or(vec![a, b]),
+        }
+    }
+
+    fn is_or(&self) -> bool {
+        matches!(self, TrigramQuery::Or(_))
+    }
+
+    fn into_terms(self) -> Vec<TrigramQuery> {
+        match self {
+            TrigramQuery::And(qs) => qs,
+            q => vec![q],
+        }
+    }
+
+    /// all trigrams of the query
+    pub fn trigrams(&self) -> BTreeSet<&str> {
+        match self {
+            TrigramQuery::All => BTreeSet::new(),
+            TrigramQuery::Trigram(t) => Some(t.as_str()).into_iter().collect(),
+            TrigramQuery::And(qs) | TrigramQuery::Or(qs) => {
+                qs.iter().flat_map(|q| q.trigrams()).collect()
+            }
+        }
+    }
+
+    /// Rows that can match, `all` being every row of the block
+    pub fn eval(&self, reader: &PostingsReader, all: &RoaringBitmap) -> Result<RoaringBitmap> {
+        match self {
+            TrigramQuery::All => Ok(all.clone()),
+            TrigramQuery::Trigram(t) => Ok(reader.get(t)?.unwrap_or_else(RoaringBitmap::new)),
+            TrigramQuery::And(qs) => {
+                let mut rows = all.clone();
+                for q in qs {
+                    rows &= q.eval(reader, all)?;
+                    if rows.is_empty() {
+                        break;
+                    }
+                }
+                Ok(rows)
+            }
+            TrigramQuery::Or(qs) => {
+                let mut rows = RoaringBitmap::new();
+                for q in qs {
+                    rows |= q.eval(reader, all)?;
+                }
+                Ok(rows)
+            }
+        }
+    }
+}
+
+/// `(a AND b) OR (a AND c)` as `a AND (b OR c)`, and `a OR (a AND b)` as `a`
+fn factor(a: TrigramQuery, b: TrigramQuery) -> TrigramQuery {
+    let (a, b) = (a.into_terms(), b.into_terms());
+    let common = a
+        .iter()
+        .filter(|q| b.contains(q))
+        .cloned()
+        .collect::<Vec<_>>();
+    if common.is_empty() {
+        let and = |qs: Vec<TrigramQuery>| qs.into_iter().fold(TrigramQuery::All, TrigramQuery::and);
+        return TrigramQuery::Or(vec![and(a), and(b)]);
+    }
+
+    let rest = |qs: Vec<TrigramQuery>| {
+        qs.into_iter()
+            .filter(|q| !common.contains(q))
+            .fold(TrigramQuery::All, TrigramQuery::and)
+    };
+    let (a, b) = (rest(a), rest(b));
+    let common = common
+        .into_iter()
+        .fold(TrigramQuery::All, TrigramQuery::and);
+    if a == TrigramQuery::All || b == TrigramQuery::All {
+        return common;
+    }
+    common.and(a.or(b))
+}
+
+impl fmt::Display for TrigramQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter<'_>, qs: &[TrigramQuery], op: &str| {
+            write!(f, "(")?;
+            for (i, q) in qs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", op)?;
+                }
+                write!(f, "{}", q)?;
+            }
+            write!(f, ")")
+        };
+
+        match self {
+            TrigramQuery::All => write!(f, "*"),
+            TrigramQuery::Trigram(t) => write!(f, "{:?}", t),
+            TrigramQuery::And(qs) => join(f, qs, "AND"),
+            TrigramQuery::Or(qs) => join(f, qs, "OR"),
+        }
+    }
+}
+
+/// A regex & the trigram query narrowing down the rows it can match
+pub struct RegexQuery {
+    regex: Regex,
+    trigrams: TrigramQuery,
+    field: Option<String>,
+}
+
+impl RegexQuery {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let hir = regex_syntax::Parser::new().parse(pattern)?;
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+            trigrams: analyze(&hir).into_query(),
+            field: None,
+        })
+    }
+
+    /// only searches column `name` instead of every string column
+    pub fn field(mut self, name: &str) -> Self {
+        self.field = Some(name.to_owned());
+        self
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    pub fn trigrams(&self) -> &TrigramQuery {
+        &self.trigrams
+    }
+
+    /// Rows of `block` the postings can't rule out
+    pub fn candidates<S: Store>(
+        &self,
+        store: &S,
+        table: &str,
+        block: &TableBlock,
+    ) -> Result<RoaringBitmap> {
+        candidates(store, table, block, self.field.as_deref(), &self.trigrams)
+    }
+
+    /// Rows of `block` with a searched column matching the regex
+    pub fn search<S: Store>(
+        &self,
+        store: &S,
+        table: &str,
+        block: &TableBlock,
+    ) -> Result<RoaringBitmap> {
+        let candidates = self.candidates(store, table, block)?;
+        verify(store, block, self.field.as_deref(), &candidates, |value| {
+            self.regex.is_match(value)
+        })
+    }
+}
+
+/// Rows of `block` the postings of `field`, or of every string column,
+/// can't rule out for `query`. Columns without trigram postings can't be
+/// narrowed down.
+pub(crate) fn candidates<S: Store>(
+    store: &S,
+    table: &str,
+    block: &TableBlock,
+    field: Option<&str>,
+    query: &TrigramQuery,
+) -> Result<RoaringBitmap> {
+    let all = (0..block.rows() as u32).collect::<RoaringBitmap>();
+    if query == &TrigramQuery::All {
+        return Ok(all);
+    }
+
+    let mut rows = RoaringBitmap::new();
+    for (_, column) in string_columns(block, field)? {
+        let path = term_path(table, block.name(), column);
+        if block.schema().analyzer(column) != Some(TRIGRAM) || !block.indexes().contains(&path) {
+            return Ok(all);
+        }
+        let reader = PostingsReader::open(store, table, block.name(), column)?;
+        rows |= query.eval(&reader, &all)?;
+    }
+    Ok(rows)
+}
+
+/// `candidates` for which `is_match` holds on `field`, or on any string
+/// column
+pub(crate) fn verify<S: Store, F: Fn(&str) -> bool>(
+    store: &S,
+    block: &TableBlock,
+    field: Option<&str>,
+    candidates: &RoaringBitmap,
+    is_match: F,
+) -> Result<RoaringBitmap> {
+    let columns = string_columns(block, field)?
+        .into_iter()
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let mut matches = RoaringBitmap::new();
+    if candidates.is_empty() || columns.is_empty() {
+        return Ok(matches);
+    }
+
+    let mut rows = candidates.iter().peekable();
+    let mut offset = 0;
+    for batch in block.scan(store, Some(columns))? {
+        let batch = batch?;
+        let end = offset + batch.num_rows() as u32;
+        let values = batch
+            .columns()
+            .iter()
+            .filter_map(|c| c.as_any().downcast_ref::<StringArray>())
+            .collect::<Vec<_>>();
+
+        while let Some(&row) = rows.peek() {
+            if row >= end {
+                break;
+            }
+            rows.next();
+            let i = (row - offset) as usize;
+            if values.iter().any(|v| v.is_valid(i) && is_match(v.value(i))) {
+                matches.insert(row);
+            }
+        }
+        offset = end;
+    }
+    Ok(matches)
+}
+
+/// `(index, name)` of `field`, or of every string column
+fn string_columns<'a>(
+    block: &'a TableBlock,
+    field: Option<&'a str>,
+) -> Result<Vec<(usize, &'a str)>> {
+    let schema = block.schema();
+    match field {
+        Some(name) => {
+            let i = schema.projection(&[name])?[0];
+            match schema.iter().nth(i) {
+                Some(f) if f.data_type() == &ArrowDataType::Utf8 => Ok(vec![(i, name)]),
+                _ => Err(anyhow!("Column {} isn't a string", name)),
+            }
+        }
+        None => Ok(schema
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.data_type() == &ArrowDataType::Utf8)
+            .map(|(i, f)| (i, f.name().as_str()))
+            .collect()),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Info {
+    emptyable: bool,
+    exact: Option<BTreeSet<String>>,
+    prefix: BTreeSet<String>,
+    suffix: BTreeSet<String>,
+    query: TrigramQuery,
+}
+
+impl Info {
+    fn empty_string() -> Self {
+        Self::exact(Some(String::new()).into_iter().collect(), true)
+    }
+
+    fn any_char() -> Self {
+        Self::unknown(false)
+    }
+
+    fn any_string() -> Self {
+        Self::unknown(true)
+    }
+
+    fn exact(strings: BTreeSet<String>, emptyable: bool) -> Self {
+        Self {
+            emptyable,
+            exact: Some(strings),
+            prefix: BTreeSet::new(),
+            suffix: BTreeSet::new(),
+            query: TrigramQuery::All,
+        }
+    }
+
+    fn unknown(emptyable: bool) -> Self {
+        let any = Some(String::new()).into_iter().collect::<BTreeSet<_>>();
+        Self {
+            emptyable,
+            exact: None,
+            prefix: any.clone(),
+            suffix: any,
+            query: TrigramQuery::All,
+        }
+    }
+
+    fn prefixes(&self) -> &BTreeSet<String> {
+        self.exact.as_ref().unwrap_or(&self.prefix)
+    }
+
+    fn suffixes(&self) -> &BTreeSet<String> {
+        self.exact.as_ref().unwrap_or(&self.suffix)
+    }
+
+    /// gives up the exact set, keeping what it says in the query, prefix &
+    /// suffix
+    fn inexact(mut self) -> Self {
+        if let Some(exact) = self.exact.take() {
+            self.query = self.query.and(set_query(&exact));
+            self.prefix = exact.clone();
+            self.suffix = exact;
+        }
+        self
+    }
+
+    fn concat(self, other: Info) -> Self {
+        let exact = match (&self.exact, &other.exact) {
+            (Some(a), Some(b)) if a.len() * b.len() <= MAX_EXACT => Some(cross(a, b)),
+            _ => None,
+        };
+
+        // trigrams across the two parts, an exact set keeps them already
+        let boundary = match (self.suffixes(), other.prefixes()) {
+            (s, p) if exact.is_none() && s.len() * p.len() <= MAX_SET => set_query(&cross(s, p)),
+            _ => TrigramQuery::All,
+        };
+
+        let (prefix, suffix) = if exact.is_some() {
+            (BTreeSet::new(), BTreeSet::new())
+        } else {
+            let prefix = match &self.exact {
+                Some(a) => cross(a, &other.prefix),
+                None if self.emptyable => union(&self.prefix, other.prefixes()),
+                None => self.prefix.clone(),
+            };
+            let suffix = match &other.exact {
+                Some(b) => cross(&self.suffix, b),
+                None if other.emptyable => union(self.suffixes(), &other.suffix),
+                None => other.suffix.clone(),
+            };
+            (prefix, suffix)
+        };
+
+        let mut query = self.query.and(other.query).and(boundary);
+        if exact.is_none() {
+            // exact sets that are lost still tell which trigrams are needed
+            for part in self.exact.iter().chain(other.exact.iter()) {
+                query = query.and(set_query(part));
+            }
+        }
+
+        Self {
+            emptyable: self.emptyable && other.emptyable,
+            exact,
+            prefix,
+            suffix,
+            query,
+        }
+        .simplify()
+    }
+
+    fn alternate(self, other: Info) -> Self {
+        let (x, y) = match (&self.exact, &other.exact) {
+            (Some(_), Some(_)) => (self, other),
+            _ => (self.inexact(), other.inexact()),
+        };
+
+        Self {
+            emptyable: x.emptyable || y.emptyable,
+            exact: match (&x.exact, &y.exact) {
+                (Some(a), Some(b)) => Some(union(a, b)),
+                _ => None,
+            },
+            prefix: union(&x.prefix, &y.prefix),
+            suffix: union(&x.suffix, &y.suffix),
+            query: x.query.or(y.query),
+        }
+        .simplify()
+    }
+
+    fn simplify(mut self) -> Self {
+        if matches!(&self.exact, Some(e) if e.len() > MAX_EXACT) {
+            self = self.inexact();
+        }
+        if self.exact.is_none() {
+            if self.prefix.len() > MAX_SET {
+                self.query = self.query.and(set_query(&self.prefix));
+                self.prefix = self
+                    .prefix
+                    .iter()
+                    .map(|s| s.chars().take(NGRAM - 1).collect())
+                    .collect();
+            }
+            if self.suffix.len() > MAX_SET {
+                self.query = self.query.and(set_query(&self.suffix));
+                self.suffix = self
+                    .suffix
+                    .iter()
+                    .map(|s| {
+                        let chars = s.chars().collect::<Vec<_>>();
+                        chars[chars.len().saturating_sub(NGRAM - 1)..]
+                            .iter()
+                            .collect()
+                    })
+                    .collect();
+            }
+            self.prefix = prune(&self.prefix, |s, t| s.starts_with(t));
+            self.suffix = prune(&self.suffix, |s, t| s.ends_with(t));
+        }
+        self
+    }
+
+    fn into_query(self) -> TrigramQuery {
+        let info = self.inexact();
+        info.query
+            .and(set_query(&info.prefix))
+            .and(set_query(&info.suffix))
+    }
+}
+
+fn analyze(hir: &Hir) -> Info {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => Info::empty_string(),
+        HirKind::Literal(Literal::Unicode(c)) => chars(Some(*c)),
+        HirKind::Literal(Literal::Byte(b)) if b.is_ascii() => chars(Some(*b as char)),
+        HirKind::Literal(Literal::Byte(_)) => Info::any_char(),
+        HirKind::Class(Class::Unicode(class)) => {
+            let size = class
+                .iter()
+                .map(|r| r.end() as usize - r.start() as usize + 1)
+                .sum::<usize>();
+            if size > MAX_CLASS {
+                return Info::any_char();
+            }
+            chars(
+                class
+                    .iter()
+                    .flat_map(|r| r.start() as u32..=r.end() as u32)
+                    .filter_map(std::char::from_u32),
+            )
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let size = class
+                .iter()
+                .map(|r| r.end() as usize - r.start() as usize + 1)
+                .sum::<usize>();
+            if size > MAX_CLASS || !class.is_all_ascii() {
+                return Info::any_char();
+            }
+            chars(
+                class
+                    .iter()
+                    .flat_map(|r| r.start()..=r.end())
+                    .map(|b| b as char),
+            )
+        }
+        HirKind::Repetition(rep) => {
+            let (min, max) = match &rep.kind {
+                RepetitionKind::ZeroOrOne => (0, Some(1)),
+                RepetitionKind::ZeroOrMore => (0, None),
+                RepetitionKind::OneOrMore => (1, None),
+                RepetitionKind::Range(RepetitionRange::Exactly(n)) => (*n, Some(*n)),
+                RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (*n, None),
+                RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => (*m, Some(*n)),
+            };
+            match (min, max) {
+                (0, Some(0)) => Info::empty_string(),
+                (0, Some(1)) => analyze(&rep.hir).alternate(Info::empty_string()),
+                (0, _) => Info::any_string(),
+                (1, Some(1)) => analyze(&rep.hir),
+                // every match starts, ends with & holds a match of the sub regex
+                _ => analyze(&rep.hir).inexact(),
+            }
+        }
+        HirKind::Group(group) => analyze(&group.hir),
+        HirKind::Concat(hirs) => hirs
+            .iter()
+            .fold(Info::empty_string(), |info, hir| info.concat(analyze(hir))),
+        HirKind::Alternation(hirs) => hirs
+            .iter()
+            .map(analyze)
+            .fold(None, |acc: Option<Info>, info| match acc {
+                Some(acc) => Some(acc.alternate(info)),
+                None => Some(info),
+            })
+            .unwrap_or_else(Info::empty_string),
+    }
+}
+
+/// single chars, lowercased like the analyzer does when that neither changes
+/// their length nor depends on the chars around them (Σ at the end of a word
+/// is ς). Others keep their case until their trigram is lowercased whole.
+fn chars(chars: impl IntoIterator<Item = char>) -> Info {
+    let strings = chars
+        .into_iter()
+        .map(|c| {
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(l), None) if c != 'Σ' => l.to_string(),
+                _ => c.to_string(),
+            }
+        })
+        .collect();
+    Info::exact(strings, false)
+}
+
+fn cross(a: &BTreeSet<String>, b: &BTreeSet<String>) -> BTreeSet<String> {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| format!("{}{}", x, y)))
+        .collect()
+}
+
+fn union(a: &BTreeSet<String>, b: &BTreeSet<String>) -> BTreeSet<String> {
+    a.union(b).cloned().collect()
+}
+
+/// drops strings made redundant by a shorter one, e.g. `ab` in a prefix set
+/// that already has `a`
+fn prune(set: &BTreeSet<String>, covers: impl Fn(&str, &str) -> bool) -> BTreeSet<String> {
+    set.iter()
+        .filter(|s| !set.iter().any(|t| t != *s && covers(s, t)))
+        .cloned()
+        .collect()
+}
+
+/// Trigrams of any string of `set`: an OR over the strings of an AND of
+/// their trigrams. Strings too short to have a trigram match anything.
+fn set_query(set: &BTreeSet<String>) -> TrigramQuery {
+    if set.is_empty() || set.iter().any(|s| s.chars().count() < NGRAM) {
+        return TrigramQuery::All;
+    }
+
+    // a string holding another one needs all of its trigrams & more
+    let set = prune(set, |s, t| s.contains(t));
+    set.iter()
+        .map(|s| literal_query(s))
+        .fold(None, |acc: Option<TrigramQuery>, q| match acc {
+            Some(acc) => Some(acc.or(q)),
+            None => Some(q),
+        })
+        .unwrap_or(TrigramQuery::All)
+}
+
+/// Trigrams of a literal, `All` if it's too short to have one
+pub(crate) fn literal_query(s: &str) -> TrigramQuery {
+    if s.chars().count() < NGRAM {
+        return TrigramQuery::All;
+    }
+    Analyzer::trigram()
+        .tokens(s)
+        .into_iter()
+        .fold(TrigramQuery::All, |q, token| {
+            q.and(TrigramQuery::Trigram(token.to_str().to_owned()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{indexed_block, store};
+    use arrow::array::Int32Array;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use store::builder::SchemaBuilder;
+    use store::schema::FieldType;
+
+    fn plan(pattern: &str) -> String {
+        RegexQuery::new(pattern).unwrap().trigrams().to_string()
+    }
+
+    #[test]
+    fn trigram_plan_test() {
+        assert_eq!(plan("abcd"), r#"("abc" AND "bcd")"#);
+        assert_eq!(plan("ab"), "*");
+        assert_eq!(plan("a.*b"), "*");
+        assert_eq!(plan("abc|xyz"), r#"("abc" OR "xyz")"#);
+        assert_eq!(plan("(?i)Abc"), r#""abc""#);
+        assert_eq!(plan("ab[cd]"), r#"("abc" OR "abd")"#);
+        assert_eq!(plan("(abc)+"), r#""abc""#);
+        assert_eq!(plan("x(abc)?y"), "*");
+        assert_eq!(plan("abc.*def"), r#"("abc" AND "def")"#);
+        assert_eq!(plan("ΟΔΟΣ"), r#"("οδο" AND "δος")"#);
+
+        assert_eq!(
+            plan("ERROR.*timeout [0-9]+"),
+            concat!(
+                r#"("err" AND "rro" AND "ror" AND "tim" AND "ime" AND "meo" AND "eou" AND "out" AND "ut " AND "#,
+                r#"("t 0" OR "t 1" OR "t 2" OR "t 3" OR "t 4" OR "t 5" OR "t 6" OR "t 7" OR "t 8" OR "t 9"))"#
+            )
+        );
+        assert_eq!(
+            plan("GET /api/v[12]/users"),
+            concat!(
+                r#"("get" AND "et " AND "t /" AND " /a" AND "/ap" AND "api" AND "pi/" AND "i/v" AND "#,
+                r#""/us" AND "use" AND "ser" AND "ers" AND "#,
+                r#"(("/v1" AND "v1/" AND "1/u") OR ("/v2" AND "v2/" AND "2/u")))"#
+            )
+        );
+    }
+
+    #[test]
+    fn regex_search_test() {
+        let store = store("regex_table");
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("message", FieldType::Str)
+            .build()
+            .unwrap();
+
+        let messages = vec![
+            "ERROR upstream timeout 504",
+            "INFO request done",
+            "error upstream timeout 504",
+            "ERROR db timeout after retry",
+            "WARN slow; ERROR cache timeout 30",
+            "ΟΔΟΣ ΑΘΗΝΑΣ closed",
+            "İstanbul gateway down",
+        ];
+        let block = indexed_block(
+            &store,
+            "regex_table",
+            "block",
+            &schema,
+            vec![
+                Arc::new(Int32Array::from(vec![0, 1, 2, 3, 4, 5, 6])),
+                Arc::new(StringArray::from(messages)),
+            ],
+        );
+
+        let query = RegexQuery::new("ERROR.*timeout [0-9]+").unwrap();
+        let candidates = query.candidates(&store, "regex_table", &block).unwrap();
+        // the index is lowercased, the regex is not
+        assert_eq!(candidates.iter().collect::<Vec<_>>(), vec![0, 2, 4]);
+        let matches = query.search(&store, "regex_table", &block).unwrap();
+        assert_eq!(matches.iter().collect::<Vec<_>>(), vec![0, 4]);
+
+        // trigrams are lowercased whole like the indexed ones, a final Σ
+        // being ς & İ two chars
+        for (pattern, row) in &[
+            ("ΟΔΟΣ", 5),
+            ("ΑΘΗΝΑΣ c", 5),
+            ("İstanbul", 6),
+            ("(?i)İSTANBUL", 6),
+        ] {
+            let query = RegexQuery::new(pattern).unwrap();
+            let matches = query.search(&store, "regex_table", &block).unwrap();
+            assert_eq!(
+                matches.iter().collect::<Vec<_>>(),
+                vec![*row],
+                "{}",
+                pattern
+            );
+        }
+
+        let query = RegexQuery::new("nothing here").unwrap();
+        assert!(query
+            .candidates(&store, "regex_table", &block)
+            .unwrap()
+            .is_empty());
+
+        let query = RegexQuery::new("timeout [0-9]+").unwrap().field("message");
+        let matches = query.search(&store, "regex_table", &block).unwrap();
+        assert_eq!(matches.iter().collect::<Vec<_>>(), vec![0, 2, 4]);
+        let query = RegexQuery::new("timeout").unwrap().field("time");
+        assert!(query.search(&store, "regex_table", &block).is_err());
+
+        store.clean("regex_table").unwrap();
+    }
+}
@@ -2,75 +2,154 @@
 //! WRITES DONT BLOCK READ
 
 use akiradb::util::config;
-use akiradb::util::file;
-use ingest::fst;
-use ingest::tokenizer::{NGramTokenizer, Token, Tokenizer};
+use akiradb::util::file::{self, Chunks};
+use ingest::analyzer::Analyzer;
+use ingest::fst::TermDict;
+use ingest::spill::{self, SpillingBuilder};
+use rayon::prelude::*;
 use serde_json::Value;
-use std::collections::BTreeSet;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
 use store::{FSBlobStore, Store};
 
-fn main() -> std::io::Result<()> {
-    let cfg = config::Opt::from_args();
-    let tokenizer = NGramTokenizer::new(3).unwrap();
-    let block_size = 2usize.pow(32);
+/// where sorted runs are spilled, relative to the store root
+const SPILL_DIR: &str = "spill";
 
-    cfg.files.iter().for_each(|filename| {
-        if cfg.verbose {
-            println!("parsing file {}", filename.display())
+/// Adds the postings of every json key of a chunk, its first line being row
+/// `row`, then spills them if over budget. Values that aren't strings are
+/// indexed as their json text, nulls & lines that aren't objects are skipped.
+fn index_chunk<S: Store>(
+    chunk: &str,
+    row: u32,
+    analyzer: &Analyzer,
+    postings: &mut SpillingBuilder<S>,
+) -> anyhow::Result<()> {
+    for (line, row) in chunk.lines().zip(row..) {
+        let json: serde_json::Result<Value> = serde_json::from_str(line);
+        let json = match json {
+            Ok(Value::Object(json)) => json,
+            Ok(_) => {
+                println!("NOT AN OBJECT:: row {}", row);
+                continue;
+            }
+            Err(err) => {
+                println!("PARSING ERROR:: {}", err);
+                continue;
+            }
+        };
+        for (k, v) in &json {
+            let value = match v {
+                Value::Null => continue,
+                Value::String(s) => Cow::Borrowed(s.as_str()),
+                v => Cow::Owned(v.to_string()),
+            };
+            let field = postings.column(k);
+            if !field.insert_ascii_trigrams(&value, row) {
+                analyzer.analyze(&value, |token| field.insert(token, row));
+            }
         }
+    }
+    postings.maybe_spill()
+}
 
-        // Open the file in read-only mode (ignoring errors).
-        let mut reader = file::reader(&filename, &cfg);
-
-        let mut tokens = BTreeSet::<Token>::new();
-        println!("Block size is :: {}", block_size);
+/// `main` fails with io errors, others being turned into one by message
+fn io_error(e: anyhow::Error) -> io::Error {
+    e.downcast::<io::Error>()
+        .unwrap_or_else(|e| io::Error::other(e.to_string()))
+}
 
-        let mut buf = String::with_capacity(8 * 1024);
-        let mut i = 0;
-        while reader.read_line(&mut buf).unwrap_or(0) > 0 {
-            let json: serde_json::Result<Value> = serde_json::from_str(&buf);
-            match json {
-                Ok(json) => {
-                    for (k, _) in json.as_object().unwrap() {
-                        tokens.extend(
-                            tokenizer
-                                .tokenize(json.get(k).unwrap().as_str().unwrap())
-                                .collect::<Vec<Token>>(),
-                        );
-                    }
-                }
-                Err(err) => println!("PARSING ERROR:: {}", err),
-            }
-            i = i + 1;
-            buf.clear();
-        }
+fn main() -> io::Result<()> {
+    let cfg = config::Opt::from_args();
+    let analyzer = Analyzer::trigram();
 
-        // tokens.sort_unstable();
-        println!("TOTAL READ:: {}", i);
-        println!("TOTAL ELEMENTS:: {}", tokens.len());
-        let mut unique = 0;
-        let mut term_builder = fst::TermDictBuilder::new(Vec::<u8>::new()).unwrap();
+    if let Some(threads) = cfg.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
 
-        for token in tokens {
-            //           println!("{:?}", token)
-            match term_builder.insert(token.as_ref()) {
-                Ok(s) => (unique += 1), //println!("SUCESS:: {:#?}", token),
-                Err(e) => (),           //println!("FAILED:: {:#?}", e),
-            }
-        }
+    let store = FSBlobStore {
+        root: "./".into(),
+        blobs: vec![],
+    };
+    store.clean(SPILL_DIR).map_err(io_error)?;
 
-        println!("UNIQUE:: {}", unique);
+    // every thread buffers postings up to its share of the budget, then
+    // spills them as a sorted run. Chunks being indexed come on top of it.
+    let budget = cfg.memory_budget * 1024 * 1024 / rayon::current_num_threads();
+    let next_run = AtomicUsize::new(0);
+    println!("Memory budget per thread is :: {}", budget);
 
-        let term_vec = term_builder.build();
+    // files are read on this thread in line-aligned chunks, which are indexed
+    // on the pool as they come. Row ids are line numbers across all files.
+    let chunk_size = cfg.chunk_size.max(1) * 1024 * 1024;
+    let mut rows = 0u32;
+    let chunks = cfg
+        .files
+        .iter()
+        .flat_map(|filename| {
+            if cfg.verbose {
+                println!("parsing file {}", filename.display())
+            }
+            Chunks::new(file::reader(filename, &cfg), chunk_size)
+        })
+        .map(|chunk| {
+            let chunk = chunk?;
+            let row = rows;
+            rows = u32::try_from(chunk.lines().count())
+                .ok()
+                .and_then(|lines| rows.checked_add(lines))
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "More lines than u32 row ids")
+                })?;
+            Ok((row, chunk))
+        });
+    // the first error stops every thread & is returned
+    let spilled = chunks
+        .par_bridge()
+        .try_fold(
+            || SpillingBuilder::new(&store, SPILL_DIR, budget, &next_run),
+            |mut postings, chunk: io::Result<(u32, String)>| -> anyhow::Result<_> {
+                let (row, chunk) = chunk?;
+                index_chunk(&chunk, row, &analyzer, &mut postings)?;
+                Ok(postings)
+            },
+        )
+        .map(|postings| postings?.finish())
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(io_error)?;
+    println!("TOTAL READ:: {}", rows);
 
-        let store = FSBlobStore {
-            root: "./".into(),
-            blobs: vec![],
-        };
+    // per json key, the runs spilled by every thread
+    let mut runs = BTreeMap::<String, Vec<String>>::new();
+    for (field, more) in spilled.into_iter().flatten() {
+        runs.entry(field).or_default().extend(more);
+    }
 
-        let terms = term_vec.unwrap();
-        store.put("final.term", terms.clone()).unwrap();
-    });
+    // one term dictionary per field, final.<field>.term & final.<field>.post,
+    // streamed to the files as the runs are merged
+    for (field, runs) in &runs {
+        let term_path = format!("final.{}.term", field);
+        let terms = BufWriter::new(File::create(store.root.join(&term_path))?);
+        let postings = BufWriter::new(File::create(
+            store.root.join(format!("final.{}.post", field)),
+        )?);
+        let (terms, postings) =
+            spill::merge_runs(&store, SPILL_DIR, field, runs, terms, postings).map_err(io_error)?;
+        terms.into_inner()?.sync_all()?;
+        postings.into_inner()?.sync_all()?;
+        let unique = TermDict::mmap(&store, Path::new(&term_path))
+            .map_err(io_error)?
+            .len();
+        println!("UNIQUE {}:: {}", field, unique);
+    }
+    store.clean(SPILL_DIR).map_err(io_error)?;
 
     Ok(())
 }
@@ -15,6 +15,18 @@ pub struct Opt {
     /// Output file
     #[structopt(short, long, parse(from_os_str))]
     pub output: Option<PathBuf>,
+
+    /// Size in MB of the chunks files are split in to be indexed in parallel
+    #[structopt(long, default_value = "64")]
+    pub chunk_size: usize,
+
+    /// Memory in MB postings are buffered in before being spilled to disk
+    #[structopt(long, default_value = "4096")]
+    pub memory_budget: usize,
+
+    /// Indexing threads, defaults to the number of cpus
+    #[structopt(short, long)]
+    pub threads: Option<usize>,
 }
 
 impl Opt {
@@ -1,9 +1,9 @@
 use super::config::Opt;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::PathBuf;
 
-pub fn reader(file: &PathBuf, opt: &Opt) -> Box<dyn BufRead> {
+pub fn reader(file: &PathBuf, opt: &Opt) -> Box<dyn BufRead + Send> {
     if opt.verbose {
         println!("Opening log file:: {}", file.display())
     }
@@ -16,3 +16,57 @@ pub fn reader(file: &PathBuf, opt: &Opt) -> Box<dyn BufRead> {
 
     Box::new(BufReader::with_capacity(8 * 8 * 1024, file))
 }
+
+/// Splits a reader in chunks of whole lines, of about `size` bytes each
+pub struct Chunks<R> {
+    reader: R,
+    size: usize,
+}
+
+impl<R: BufRead> Chunks<R> {
+    pub fn new(reader: R, size: usize) -> Self {
+        Self { reader, size }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<String> {
+        let mut buf = Vec::with_capacity(self.size);
+        (&mut self.reader)
+            .take(self.size as u64)
+            .read_to_end(&mut buf)?;
+        if !buf.is_empty() && buf.last() != Some(&b'\n') {
+            // finish the last line
+            self.reader.read_until(b'\n', &mut buf)?;
+        }
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<R: BufRead> Iterator for Chunks<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_chunk() {
+            Ok(chunk) if chunk.is_empty() => None,
+            Ok(chunk) => Some(Ok(chunk)),
+            Err(err) => {
+                // don't keep reading past a bad chunk
+                self.size = 0;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_test() {
+        let data = "a\nbbbbb\ncc\nd";
+        let chunks = Chunks::new(data.as_bytes(), 3)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(chunks, vec!["a\nbbbbb\n", "cc\n", "d"]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::schema::{FieldType, Schema, TIME_COL_NAME};
+use crate::schema::{FieldType, Schema, DEFAULT_ANALYZER, TIME_COL_NAME};
 use anyhow::Result;
 use arrow::array::StringArray;
 use arrow::datatypes::{DataType as ArrowDataType, Field as ArrowField};
@@ -9,6 +9,8 @@ use std::sync::Arc;
 pub struct SchemaBuilder {
     domain: Option<String>,
     fields: Vec<ArrowField>,
+    /// indexed fields & their analyzer
+    indexed: Vec<(String, String)>,
 }
 
 impl SchemaBuilder {
@@ -25,12 +27,33 @@ impl SchemaBuilder {
         self.add_column(name, field_type.into(), false)
     }
 
+    /// string field with a term index
+    pub fn indexed_field(self, name: &str, field_type: impl Into<ArrowDataType>) -> Self {
+        self.analyzed_field(name, field_type, DEFAULT_ANALYZER)
+    }
+
+    /// string field with a term index built by `analyzer`
+    pub fn analyzed_field(
+        mut self,
+        name: &str,
+        field_type: impl Into<ArrowDataType>,
+        analyzer: &str,
+    ) -> Self {
+        self.indexed.push((name.to_owned(), analyzer.to_owned()));
+        self.add_column(name, field_type.into(), false)
+    }
+
     pub fn timestamp(mut self) -> Self {
         self.add_column(TIME_COL_NAME, FieldType::Time.into(), false)
     }
 
     pub fn build(self) -> Result<Schema> {
-        Schema::new_with_fields(self.domain, self.fields)
+        let schema = Schema::new_with_fields(self.domain, self.fields)?;
+        self.indexed
+            .iter()
+            .try_fold(schema, |schema, (name, analyzer)| {
+                schema.index_with(name, analyzer)
+            })
     }
 
     fn add_column(mut self, name: &str, field_type: ArrowDataType, nullable: bool) -> Self {
@@ -0,0 +1,462 @@
+//! Merges small blocks of a table into bigger time sorted ones
+//!
+//! Blocks are ordered by their min time and only runs of neighbours are
+//! merged, so merged blocks keep a tight time range for pruning. Which runs
+//! get merged depends on the `CompactionPolicy`:
+//!
+//! - `Tiered`: blocks are grouped in tiers by row count, each tier `fanout`
+//!   times bigger than the previous one, and `fanout` neighbours of the same
+//!   tier are merged into one block of the next tier. Rows are rewritten
+//!   few times, at the cost of more blocks.
+//! - `Leveled`: neighbours smaller than `target_rows` are merged until they
+//!   reach it. Rows are rewritten more often, blocks end up close to
+//!   `target_rows`.
+//!
+//! A merged block replaces its inputs in one manifest commit. Its side
+//! indexes (term dictionaries...) are merged out of the inputs' by the
+//! `BlockIndexer`s of the compactor, following the rows as they get sorted,
+//! so there is one per merged block instead of one per input. Indexers that
+//! can't merge rebuild them from the merged rows.
+
+use crate::manifest::{Snapshot, TableEdit};
+use crate::schema::{Schema, TIME_COL_NAME};
+use crate::table::{Table, TableBlock};
+use crate::Store;
+use anyhow::{anyhow, Result};
+use arrow::array::{Array, ArrayRef};
+use arrow::compute::{concat, sort_to_indices, take};
+use arrow::record_batch::RecordBatch;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub const DEFAULT_FANOUT: usize = 4;
+pub const DEFAULT_MIN_ROWS: usize = 8 * 1024;
+const BATCH_ROWS: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// tier 0 holds blocks under `min_rows` rows, tier n blocks under
+    /// `min_rows * fanout^n` rows
+    Tiered {
+        fanout: usize,
+        min_rows: usize,
+    },
+    Leveled {
+        target_rows: usize,
+    },
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::Tiered {
+            fanout: DEFAULT_FANOUT,
+            min_rows: DEFAULT_MIN_ROWS,
+        }
+    }
+}
+
+/// Builds side files of a block from its rows
+pub trait BlockIndexer: Send + Sync {
+    /// Writes the index of `block` & returns the paths of its files
+    fn index(
+        &self,
+        store: &dyn Store,
+        table: &str,
+        block: &TableBlock,
+        batches: &[RecordBatch],
+    ) -> Result<Vec<PathBuf>>;
+
+    /// Writes the index of `block`, compacted out of `inputs`, & returns the
+    /// paths of its files. Row `r` of the inputs laid end to end is row
+    /// `moved[r]` of `block`. Indexes from the rows by default.
+    fn merge(
+        &self,
+        store: &dyn Store,
+        table: &str,
+        block: &TableBlock,
+        inputs: &[Arc<TableBlock>],
+        moved: &[usize],
+        batches: &[RecordBatch],
+    ) -> Result<Vec<PathBuf>> {
+        let _ = (inputs, moved);
+        self.index(store, table, block, batches)
+    }
+}
+
+#[derive(Default)]
+pub struct Compactor {
+    policy: CompactionPolicy,
+    indexers: Vec<Arc<dyn BlockIndexer>>,
+}
+
+impl Compactor {
+    pub fn new(policy: CompactionPolicy) -> Self {
+        Self {
+            policy,
+            indexers: vec![],
+        }
+    }
+
+    pub fn indexer(mut self, indexer: Arc<dyn BlockIndexer>) -> Self {
+        self.indexers.push(indexer);
+        self
+    }
+
+    pub fn policy(&self) -> &CompactionPolicy {
+        &self.policy
+    }
+
+    /// Runs of neighbour blocks to merge, each run into one block
+    pub fn plan(&self, snapshot: &Snapshot) -> Vec<Vec<Arc<TableBlock>>> {
+        let mut blocks = snapshot.blocks().to_vec();
+        blocks.sort_by_key(|block| {
+            let time = block.stats().column(TIME_COL_NAME).map(|s| (s.min, s.max));
+            (time, block.name().to_owned())
+        });
+
+        match self.policy {
+            CompactionPolicy::Tiered { fanout, min_rows } => {
+                plan_tiered(&blocks, fanout.max(2), min_rows)
+            }
+            CompactionPolicy::Leveled { target_rows } => plan_leveled(&blocks, target_rows),
+        }
+    }
+
+    /// Runs one round of compaction over `table`, returns the number of
+    /// blocks written
+    pub fn compact<S: Store>(&self, store: &S, table: &Table) -> Result<usize> {
+        let snapshot = table.snapshot();
+        let version = snapshot.version();
+        let runs = self.plan(&snapshot);
+        let compacted = runs.len();
+        // inputs are only deleted once nothing holds them
+        drop(snapshot);
+
+        for (i, run) in runs.into_iter().enumerate() {
+            // unique as every round starts from a newer version, & of the
+            // same length however many times rows were compacted
+            let name = format!("c{}-{}", version, i);
+
+            let block = self.merge(store, table, &name, &run)?;
+            let files = block
+                .indexes()
+                .iter()
+                .chain(Some(block.path()))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let edit = run
+                .iter()
+                .fold(TableEdit::new().add_block(block), |edit, b| {
+                    edit.remove_block(b.name())
+                });
+            if let Err(e) = table.commit(store, edit) {
+                // the inputs changed under us (retention...), drop the output
+                for path in files {
+                    let _ = store.delete(path.to_str().unwrap());
+                }
+                return Err(e);
+            }
+        }
+        table.collect_garbage(store)?;
+        Ok(compacted)
+    }
+
+    fn merge<S: Store>(
+        &self,
+        store: &S,
+        table: &Table,
+        name: &str,
+        run: &[Arc<TableBlock>],
+    ) -> Result<TableBlock> {
+        let schema = table.schema();
+        let mut batches = vec![];
+        for block in run {
+            if block.schema() != schema {
+                return Err(anyhow!(
+                    "Block {} doesn't match the schema of table {}",
+                    block.name(),
+                    table.name()
+                ));
+            }
+            batches.extend(block.read(store, None)?);
+        }
+
+        let (batches, moved) = sort_by_time(schema, &batches)?;
+        let mut block = TableBlock::write(store, table.name(), name, schema.clone(), &batches)?;
+        for indexer in &self.indexers {
+            for path in indexer.merge(store, table.name(), &block, run, &moved, &batches)? {
+                block.add_index(path);
+            }
+        }
+        Ok(block)
+    }
+}
+
+fn tier(rows: usize, fanout: usize, min_rows: usize) -> usize {
+    let mut tier = 0;
+    let mut limit = min_rows.max(1);
+    while rows >= limit && limit < usize::MAX {
+        tier += 1;
+        limit = limit.saturating_mul(fanout);
+    }
+    tier
+}
+
+fn plan_tiered(
+    blocks: &[Arc<TableBlock>],
+    fanout: usize,
+    min_rows: usize,
+) -> Vec<Vec<Arc<TableBlock>>> {
+    let mut runs = vec![];
+    let mut start = 0;
+    for end in 1..=blocks.len() {
+        let same_tier = end < blocks.len()
+            && tier(blocks[end].rows(), fanout, min_rows)
+                == tier(blocks[start].rows(), fanout, min_rows);
+        if !same_tier {
+            runs.extend(blocks[start..end].chunks_exact(fanout).map(|c| c.to_vec()));
+            start = end;
+        }
+    }
+    runs
+}
+
+fn plan_leveled(blocks: &[Arc<TableBlock>], target_rows: usize) -> Vec<Vec<Arc<TableBlock>>> {
+    let mut runs = vec![];
+    let mut run: Vec<Arc<TableBlock>> = vec![];
+    let mut rows = 0;
+
+    let mut close = |run: &mut Vec<Arc<TableBlock>>, rows: &mut usize| {
+        if run.len() > 1 {
+            runs.push(std::mem::take(run));
+        }
+        run.clear();
+        *rows = 0;
+    };
+
+    for block in blocks {
+        if block.rows() >= target_rows {
+            close(&mut run, &mut rows);
+            continue;
+        }
+        if rows + block.rows() > target_rows {
+            close(&mut run, &mut rows);
+        }
+        rows += block.rows();
+        run.push(block.clone());
+    }
+    close(&mut run, &mut rows);
+    runs
+}
+
+/// Concatenates `batches`, sorts the rows by time & cuts them back into
+/// batches of `BATCH_ROWS` rows. Also returns where each row went.
+fn sort_by_time(
+    schema: &Schema,
+    batches: &[RecordBatch],
+) -> Result<(Vec<RecordBatch>, Vec<usize>)> {
+    if batches.is_empty() {
+        return Ok((vec![], vec![]));
+    }
+
+    let arrow_schema = schema.arrow_schema();
+    let columns = (0..arrow_schema.fields().len())
+        .map(|i| {
+            let arrays = batches
+                .iter()
+                .map(|b| b.column(i).as_ref())
+                .collect::<Vec<&dyn Array>>();
+            Ok(concat(&arrays)?)
+        })
+        .collect::<Result<Vec<ArrayRef>>>()?;
+
+    let rows = columns[0].len();
+    let mut moved = (0..rows).collect::<Vec<_>>();
+    let columns = match arrow_schema.index_of(TIME_COL_NAME) {
+        Ok(time) => {
+            let indices = sort_to_indices(&columns[time], None)?;
+            for (row, from) in indices.values().iter().enumerate() {
+                moved[*from as usize] = row;
+            }
+            columns
+                .iter()
+                .map(|c| Ok(take(c.as_ref(), &indices, None)?))
+                .collect::<Result<Vec<ArrayRef>>>()?
+        }
+        Err(_) => columns,
+    };
+
+    let batches = (0..rows)
+        .step_by(BATCH_ROWS)
+        .map(|offset| {
+            let len = BATCH_ROWS.min(rows - offset);
+            let columns = columns.iter().map(|c| c.slice(offset, len)).collect();
+            Ok(RecordBatch::try_new(arrow_schema.clone(), columns)?)
+        })
+        .collect::<Result<_>>()?;
+    Ok((batches, moved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::SchemaBuilder;
+    use crate::schema::FieldType;
+    use crate::stats::{BlockStats, ColumnStats};
+    use crate::FSBlobStore;
+    use arrow::array::{Int32Array, StringArray};
+    use pretty_assertions::assert_eq;
+
+    fn schema() -> Schema {
+        SchemaBuilder::new()
+            .timestamp()
+            .field("status", FieldType::Str)
+            .build()
+            .unwrap()
+    }
+
+    fn block(name: &str, min: i32, rows: usize) -> TableBlock {
+        let mut stats = BlockStats {
+            rows,
+            ..Default::default()
+        };
+        stats.merge_column(TIME_COL_NAME, ColumnStats { min, max: min + 1 });
+        TableBlock::from_parts(name.into(), schema(), name.into(), stats, vec![])
+    }
+
+    fn planned(compactor: &Compactor, blocks: Vec<TableBlock>) -> Vec<Vec<String>> {
+        let table = Table::new("plan", schema());
+        let edit = blocks
+            .into_iter()
+            .fold(TableEdit::new(), |edit, b| edit.add_block(b));
+        let (snapshot, _) = table.snapshot().apply(edit).unwrap();
+        compactor
+            .plan(&snapshot)
+            .iter()
+            .map(|run| run.iter().map(|b| b.name().to_owned()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn tiered_plan_test() {
+        let compactor = Compactor::new(CompactionPolicy::Tiered {
+            fanout: 2,
+            min_rows: 10,
+        });
+        let runs = planned(
+            &compactor,
+            vec![
+                block("e", 40, 5),
+                block("a", 0, 5),
+                block("b", 10, 5),
+                block("c", 20, 15),
+                block("d", 30, 5),
+                block("f", 50, 5),
+            ],
+        );
+        // "c" is a tier up & splits the runs, "f" waits for a neighbour
+        assert_eq!(runs, vec![vec!["a", "b"], vec!["d", "e"]]);
+    }
+
+    #[test]
+    fn leveled_plan_test() {
+        let compactor = Compactor::new(CompactionPolicy::Leveled { target_rows: 10 });
+        let runs = planned(
+            &compactor,
+            vec![
+                block("a", 0, 4),
+                block("b", 10, 4),
+                block("c", 20, 4),
+                block("d", 30, 12),
+                block("e", 40, 3),
+                block("f", 50, 3),
+            ],
+        );
+        assert_eq!(runs, vec![vec!["a", "b"], vec!["e", "f"]]);
+    }
+
+    struct CountIndexer;
+
+    impl BlockIndexer for CountIndexer {
+        fn index(
+            &self,
+            store: &dyn Store,
+            table: &str,
+            block: &TableBlock,
+            batches: &[RecordBatch],
+        ) -> Result<Vec<PathBuf>> {
+            let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+            let path = PathBuf::from(table).join(format!("{}.count", block.name()));
+            store.put(path.to_str().unwrap(), rows.to_string().into_bytes())?;
+            Ok(vec![path])
+        }
+    }
+
+    #[test]
+    fn compact_test() {
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        store.clean("compact_table").unwrap();
+        let schema = schema();
+        let table = Table::new("compact_table", schema.clone());
+
+        for (name, times) in &[("a", vec![5, 1]), ("b", vec![3, 2]), ("c", vec![9, 0])] {
+            let status = vec![*name; times.len()];
+            let batch = RecordBatch::try_new(
+                schema.arrow_schema(),
+                vec![
+                    Arc::new(Int32Array::from(times.clone())),
+                    Arc::new(StringArray::from(status)),
+                ],
+            )
+            .unwrap();
+            let block =
+                TableBlock::write(&store, table.name(), name, schema.clone(), &[batch]).unwrap();
+            table
+                .commit(&store, TableEdit::new().add_block(block))
+                .unwrap();
+        }
+
+        let compactor = Compactor::new(CompactionPolicy::Leveled { target_rows: 100 })
+            .indexer(Arc::new(CountIndexer));
+        assert_eq!(compactor.compact(&store, &table).unwrap(), 1);
+        assert_eq!(compactor.compact(&store, &table).unwrap(), 0);
+
+        let snapshot = table.snapshot();
+        assert_eq!(snapshot.blocks().len(), 1);
+        let merged = &snapshot.blocks()[0];
+        assert_eq!(merged.name(), "c3-0");
+        assert_eq!(merged.rows(), 6);
+        assert_eq!(
+            merged.stats().column(TIME_COL_NAME),
+            Some(&ColumnStats { min: 0, max: 9 })
+        );
+
+        let batches = merged.read(&store, None).unwrap();
+        let time = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert_eq!(time.values(), &[0, 1, 2, 3, 5, 9]);
+        let status = batches[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(status.value(0), "c");
+        assert_eq!(status.value(5), "c");
+
+        let mut count = vec![];
+        store
+            .get(merged.indexes()[0].to_str().unwrap(), &mut count)
+            .unwrap();
+        assert_eq!(count, b"6".to_vec());
+        assert!(!store.exist("compact_table/a.arrow"));
+
+        store.clean("compact_table").unwrap();
+    }
+}
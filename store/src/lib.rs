@@ -4,15 +4,20 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::path::PathBuf;
 
-mod builder;
-mod schema;
-mod table;
+pub mod builder;
+pub mod compaction;
+pub mod manifest;
+pub mod schema;
+pub mod stats;
+pub mod table;
 
 pub trait Store {
     fn root(&self) -> &PathBuf;
     fn get<'a>(&self, key: &str, buf: &'a mut Vec<u8>) -> Result<&'a mut Vec<u8>>;
     fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
     fn delete(&self, key: &str) -> Result<()>;
+    /// atomically replaces `to` with `from`, durably once it returns
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
     fn list(&self) -> Result<Vec<FSBlob>>;
     fn exist(&self, key: &str) -> bool;
     fn clean(&self, key: &str) -> Result<()>;
@@ -41,6 +46,7 @@ impl Store for FSBlobStore {
         let mut file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(path)?;
         file.lock_exclusive()?;
         file.write_all(&data[..]).and_then(|_| file.sync_all())?;
@@ -69,6 +75,19 @@ impl Store for FSBlobStore {
         Ok(())
     }
 
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let to = self.root.join(to);
+        if let Some(prefix) = to.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+        std::fs::rename(self.root.join(from), &to)?;
+        // the rename only survives a crash once its directory is synced
+        if let Some(prefix) = to.parent() {
+            File::open(prefix)?.sync_all()?;
+        }
+        Ok(())
+    }
+
     fn list(&self) -> Result<Vec<FSBlob>> {
         Ok(Vec::new())
     }
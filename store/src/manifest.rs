@@ -0,0 +1,307 @@
+//! Versioned list of the blocks of a table
+//!
+//! Every change to a table (flush, compaction, ...) is a `TableEdit` that
+//! produces a new `Snapshot` with the next version number. The snapshot is
+//! persisted as `<table>/MANIFEST-<version>` and `<table>/CURRENT` is then
+//! pointed at it, both written to a temp file and renamed, so a crash leaves
+//! either the old or the new version on disk, never a mix.
+//!
+//! Readers hold an `Arc<Snapshot>` and never wait on writers; blocks removed
+//! by a newer version are deleted only once no snapshot uses them anymore.
+
+use crate::schema::{FieldType, Schema, DEFAULT_ANALYZER, TIME_COL_NAME};
+use crate::stats::BlockStats;
+use crate::table::TableBlock;
+use crate::Store;
+use anyhow::{anyhow, Result};
+use arrow::datatypes::{DataType as ArrowDataType, Field as ArrowField, Schema as ArrowSchema};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const CURRENT: &str = "CURRENT";
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+const TMP_EXT: &str = "tmp";
+
+/// Immutable view of a table at some version
+#[derive(Default)]
+pub struct Snapshot {
+    version: u64,
+    flushed_lsn: u64,
+    blocks: Vec<Arc<TableBlock>>,
+}
+
+impl Snapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// last wal lsn whose rows are in the blocks
+    pub fn flushed_lsn(&self) -> u64 {
+        self.flushed_lsn
+    }
+
+    pub fn blocks(&self) -> &[Arc<TableBlock>] {
+        &self.blocks
+    }
+
+    pub fn rows(&self) -> usize {
+        self.blocks.iter().map(|b| b.rows()).sum()
+    }
+
+    /// Blocks that can have rows with time in `[start, end)`. Tables without
+    /// a time column can't be pruned, so all their blocks are returned.
+    pub fn blocks_in_range(&self, start: i32, end: i32) -> Vec<&TableBlock> {
+        self.blocks
+            .iter()
+            .filter(|block| block.overlaps(TIME_COL_NAME, start, end))
+            .map(|block| block.as_ref())
+            .collect()
+    }
+
+    /// Applies `edit`, returning the next version & the blocks it removed
+    pub(crate) fn apply(&self, edit: TableEdit) -> Result<(Snapshot, Vec<Arc<TableBlock>>)> {
+        for name in &edit.remove {
+            if !self.blocks.iter().any(|b| b.name() == name) {
+                return Err(anyhow!("Unknown block: {}", name));
+            }
+        }
+
+        let (removed, mut blocks): (Vec<_>, Vec<_>) = self
+            .blocks
+            .iter()
+            .cloned()
+            .partition(|b| edit.remove.iter().any(|name| name == b.name()));
+
+        for block in edit.add {
+            if blocks.iter().any(|b| b.name() == block.name()) {
+                return Err(anyhow!("Duplicate block: {}", block.name()));
+            }
+            blocks.push(Arc::new(block));
+        }
+
+        let next = Snapshot {
+            version: self.version + 1,
+            flushed_lsn: self.flushed_lsn.max(edit.flushed_lsn.unwrap_or(0)),
+            blocks,
+        };
+        Ok((next, removed))
+    }
+}
+
+/// Blocks to add & remove in one atomic step
+#[derive(Default)]
+pub struct TableEdit {
+    add: Vec<TableBlock>,
+    remove: Vec<String>,
+    flushed_lsn: Option<u64>,
+}
+
+impl TableEdit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_block(mut self, block: TableBlock) -> Self {
+        self.add.push(block);
+        self
+    }
+
+    pub fn remove_block(mut self, name: impl Into<String>) -> Self {
+        self.remove.push(name.into());
+        self
+    }
+
+    /// marks rows up to `lsn` as flushed, so they aren't replayed again
+    pub fn flushed_lsn(mut self, lsn: u64) -> Self {
+        self.flushed_lsn = Some(lsn);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty() && self.flushed_lsn.is_none()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Column {
+    name: String,
+    #[serde(rename = "type")]
+    field_type: String,
+    nullable: bool,
+    #[serde(default)]
+    indexed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    analyzer: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BlockEntry {
+    name: String,
+    path: PathBuf,
+    columns: Vec<Column>,
+    stats: BlockStats,
+    #[serde(default)]
+    indexes: Vec<PathBuf>,
+}
+
+/// On disk form of a snapshot
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    table: String,
+    version: u64,
+    flushed_lsn: u64,
+    columns: Vec<Column>,
+    blocks: Vec<BlockEntry>,
+}
+
+impl Manifest {
+    /// Persists `snapshot` & makes it the current version of `table`
+    pub(crate) fn write<S: Store>(
+        store: &S,
+        table: &str,
+        schema: &Schema,
+        snapshot: &Snapshot,
+    ) -> Result<()> {
+        let manifest = Manifest {
+            table: table.to_owned(),
+            version: snapshot.version,
+            flushed_lsn: snapshot.flushed_lsn,
+            columns: columns(schema)?,
+            blocks: snapshot
+                .blocks
+                .iter()
+                .map(|block| {
+                    Ok(BlockEntry {
+                        name: block.name().to_owned(),
+                        path: block.path().clone(),
+                        columns: columns(block.schema())?,
+                        stats: block.stats().clone(),
+                        indexes: block.indexes().to_vec(),
+                    })
+                })
+                .collect::<Result<_>>()?,
+        };
+
+        let name = manifest_name(snapshot.version);
+        put_atomic(
+            store,
+            &key(table, &name),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        put_atomic(store, &key(table, CURRENT), name.into_bytes())?;
+
+        // older manifests aren't needed once CURRENT moved on, which the
+        // rename made durable
+        if snapshot.version > 1 {
+            let previous = key(table, &manifest_name(snapshot.version - 1));
+            if store.exist(&previous) {
+                store.delete(&previous)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the current version of `table`, `None` if it was never written
+    pub(crate) fn read<S: Store>(store: &S, table: &str) -> Result<Option<(Schema, Snapshot)>> {
+        let current = key(table, CURRENT);
+        if !store.exist(&current) {
+            return Ok(None);
+        }
+
+        let mut buf = vec![];
+        store.get(&current, &mut buf)?;
+        let name = String::from_utf8(buf)?.trim().to_owned();
+        if !name.starts_with(MANIFEST_PREFIX) {
+            return Err(anyhow!("Invalid CURRENT for table {}: {}", table, name));
+        }
+
+        let mut buf = vec![];
+        store.get(&key(table, &name), &mut buf)?;
+        let manifest: Manifest = serde_json::from_slice(&buf)?;
+        if manifest.table != table {
+            return Err(anyhow!(
+                "Manifest of table {} found under {}",
+                manifest.table,
+                table
+            ));
+        }
+
+        let blocks = manifest
+            .blocks
+            .into_iter()
+            .map(|entry| {
+                Ok(Arc::new(TableBlock::from_parts(
+                    entry.name,
+                    schema(entry.columns)?,
+                    entry.path,
+                    entry.stats,
+                    entry.indexes,
+                )))
+            })
+            .collect::<Result<_>>()?;
+
+        let snapshot = Snapshot {
+            version: manifest.version,
+            flushed_lsn: manifest.flushed_lsn,
+            blocks,
+        };
+        Ok(Some((schema(manifest.columns)?, snapshot)))
+    }
+}
+
+fn key(table: &str, name: &str) -> String {
+    PathBuf::from(table).join(name).to_str().unwrap().to_owned()
+}
+
+fn manifest_name(version: u64) -> String {
+    format!("{}{:020}", MANIFEST_PREFIX, version)
+}
+
+fn put_atomic<S: Store>(store: &S, key: &str, data: Vec<u8>) -> Result<()> {
+    let tmp = format!("{}.{}", key, TMP_EXT);
+    store.put(&tmp, data)?;
+    store.rename(&tmp, key)
+}
+
+fn columns(schema: &Schema) -> Result<Vec<Column>> {
+    schema
+        .iter()
+        .map(|field| {
+            Ok(Column {
+                name: field.name().clone(),
+                field_type: FieldType::try_from(field.data_type())?.as_str().to_owned(),
+                nullable: field.is_nullable(),
+                indexed: schema.is_indexed(field.name()),
+                analyzer: schema.analyzer(field.name()).map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+fn schema(columns: Vec<Column>) -> Result<Schema> {
+    let indexed = columns
+        .iter()
+        .filter(|column| column.indexed)
+        .map(|column| {
+            let analyzer = column.analyzer.as_deref().unwrap_or(DEFAULT_ANALYZER);
+            (column.name.clone(), analyzer.to_owned())
+        })
+        .collect::<Vec<_>>();
+    let fields = columns
+        .into_iter()
+        .map(|column| {
+            let field_type = FieldType::try_from(column.field_type.as_str())?;
+            Ok(ArrowField::new(
+                &column.name,
+                ArrowDataType::from(field_type),
+                column.nullable,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let schema = Schema::try_from(ArrowSchema::new(fields))?;
+    indexed.iter().try_fold(schema, |schema, (name, analyzer)| {
+        schema.index_with(name, analyzer)
+    })
+}
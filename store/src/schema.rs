@@ -2,20 +2,32 @@
 
 use anyhow::anyhow;
 use anyhow::Result;
-use arrow::datatypes::{DataType as ArrowDataType, Field as ArrowField, Schema as ArrowSchema};
+use arrow::datatypes::{
+    DataType as ArrowDataType, Field as ArrowField, Schema as ArrowSchema,
+    SchemaRef as ArrowSchemaRef,
+};
 use arrow::record_batch::RecordBatch;
 use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
 
 pub const TIME_COL_NAME: &str = "time";
+/// analyzer of indexed columns that don't pick one
+pub const DEFAULT_ANALYZER: &str = "trigram";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Schema {
     inner: ArrowSchema,
+    /// string columns with a term index & the name of the analyzer splitting
+    /// their values into terms, in column order
+    indexed: Vec<(String, String)>,
 }
 
 impl Schema {
     pub fn new(inner: ArrowSchema) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            indexed: vec![],
+        }
     }
 
     pub fn iter(&self) -> SchemaIterator<'_> {
@@ -33,6 +45,55 @@ impl Schema {
     pub fn inner(self) -> ArrowSchema {
         self.inner
     }
+
+    /// indices of `columns`, to be used as a scan projection
+    pub fn projection(&self, columns: &[&str]) -> Result<Vec<usize>> {
+        columns
+            .iter()
+            .map(|name| Ok(self.inner.index_of(name)?))
+            .collect()
+    }
+
+    /// arrow schema to build record batches with
+    pub fn arrow_schema(&self) -> ArrowSchemaRef {
+        Arc::new(self.inner.clone())
+    }
+
+    /// Marks string column `name` as having a term index
+    pub fn index(self, name: &str) -> Result<Self> {
+        self.index_with(name, DEFAULT_ANALYZER)
+    }
+
+    /// Marks string column `name` as having a term index built by `analyzer`
+    pub fn index_with(mut self, name: &str, analyzer: &str) -> Result<Self> {
+        let field = self.inner.field_with_name(name)?;
+        if field.data_type() != &ArrowDataType::Utf8 {
+            return Err(anyhow!("Only string columns can be indexed: {}", name));
+        }
+        self.indexed.retain(|(n, _)| n != name);
+        self.indexed.push((name.to_owned(), analyzer.to_owned()));
+        let fields = self.inner.fields();
+        let position = |(n, _): &(String, String)| fields.iter().position(|f| f.name() == n);
+        self.indexed.sort_by_key(position);
+        Ok(self)
+    }
+
+    /// names of the columns with a term index
+    pub fn indexed(&self) -> impl Iterator<Item = &str> {
+        self.indexed.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn is_indexed(&self, name: &str) -> bool {
+        self.analyzer(name).is_some()
+    }
+
+    /// analyzer of column `name`, if it has a term index
+    pub fn analyzer(&self, name: &str) -> Option<&str> {
+        self.indexed
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, analyzer)| analyzer.as_str())
+    }
 }
 
 impl TryFrom<ArrowSchema> for Schema {
@@ -50,7 +111,7 @@ impl TryFrom<ArrowSchema> for Schema {
             }
         }
 
-        Ok(Self { inner })
+        Ok(Self::new(inner))
     }
 }
 
@@ -77,6 +138,16 @@ pub enum FieldType {
     Time,
 }
 
+impl FieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Str => "string",
+            FieldType::Int => "int",
+            FieldType::Time => "time",
+        }
+    }
+}
+
 // we also need way to get field type from string
 impl TryFrom<&str> for FieldType {
     type Error = anyhow::Error;
@@ -162,4 +233,33 @@ mod tests {
             schema.unwrap_err().to_string(),
         );
     }
+
+    #[test]
+    fn indexed_columns() {
+        let fields = vec![
+            ArrowField::new("STR_COL", ArrowDataType::Utf8, false),
+            ArrowField::new("INT_COL", ArrowDataType::Int32, false),
+            ArrowField::new("OTHER_STR_COL", ArrowDataType::Utf8, false),
+        ];
+        let schema = Schema::try_from(ArrowSchema::new(fields))
+            .unwrap()
+            .index("OTHER_STR_COL")
+            .unwrap()
+            .index_with("STR_COL", "word")
+            .unwrap();
+        assert_eq!(
+            schema.indexed().collect::<Vec<_>>(),
+            vec!["STR_COL", "OTHER_STR_COL"]
+        );
+        assert!(schema.is_indexed("STR_COL"));
+        assert!(!schema.is_indexed("INT_COL"));
+        assert_eq!(schema.analyzer("STR_COL"), Some("word"));
+        assert_eq!(schema.analyzer("OTHER_STR_COL"), Some(DEFAULT_ANALYZER));
+
+        assert_eq!(
+            "Only string columns can be indexed: INT_COL",
+            schema.clone().index("INT_COL").unwrap_err().to_string()
+        );
+        assert!(schema.index("UNK_COL").is_err());
+    }
 }
@@ -0,0 +1,174 @@
+//! Per block column statistics
+//!
+//! Min/max of every numeric column, the time column included, so whole
+//! blocks can be skipped without opening them. They are kept in the block
+//! header as arrow schema metadata (`akiradb.min.<col>`, `akiradb.max.<col>`).
+
+use crate::schema::Schema;
+use anyhow::{anyhow, Result};
+use arrow::array::Int32Array;
+use arrow::datatypes::DataType as ArrowDataType;
+use arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+const MIN_KEY: &str = "akiradb.min.";
+const MAX_KEY: &str = "akiradb.max.";
+const ROWS_KEY: &str = "akiradb.rows";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl ColumnStats {
+    pub fn merge(self, other: ColumnStats) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// true if any value in `[start, end)` can be in the column
+    pub fn overlaps(&self, start: i32, end: i32) -> bool {
+        self.min < end && self.max >= start
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub rows: usize,
+    /// only columns with at least one non null value have stats
+    pub columns: BTreeMap<String, ColumnStats>,
+}
+
+impl BlockStats {
+    pub fn from_batches(schema: &Schema, batches: &[RecordBatch]) -> Self {
+        let mut stats = Self {
+            rows: batches.iter().map(|b| b.num_rows()).sum(),
+            columns: BTreeMap::new(),
+        };
+
+        for (i, field) in schema.iter().enumerate() {
+            if field.data_type() != &ArrowDataType::Int32 {
+                continue;
+            }
+
+            for batch in batches {
+                let array = match batch.column(i).as_any().downcast_ref::<Int32Array>() {
+                    Some(array) => array,
+                    None => continue,
+                };
+                if let (Some(min), Some(max)) =
+                    (arrow::compute::min(array), arrow::compute::max(array))
+                {
+                    stats.merge_column(field.name(), ColumnStats { min, max });
+                }
+            }
+        }
+        stats
+    }
+
+    pub fn column(&self, name: &str) -> Option<&ColumnStats> {
+        self.columns.get(name)
+    }
+
+    pub fn merge_column(&mut self, name: &str, column: ColumnStats) {
+        let merged = match self.columns.get(name) {
+            Some(stats) => stats.merge(column),
+            None => column,
+        };
+        self.columns.insert(name.to_owned(), merged);
+    }
+
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert(ROWS_KEY.to_owned(), self.rows.to_string());
+        for (name, stats) in &self.columns {
+            metadata.insert(format!("{}{}", MIN_KEY, name), stats.min.to_string());
+            metadata.insert(format!("{}{}", MAX_KEY, name), stats.max.to_string());
+        }
+        metadata
+    }
+
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self> {
+        let rows = metadata
+            .get(ROWS_KEY)
+            .ok_or_else(|| anyhow!("Block header has no row count"))?
+            .parse()?;
+
+        let mut columns = BTreeMap::new();
+        for (key, min) in metadata {
+            if let Some(name) = key.strip_prefix(MIN_KEY) {
+                let max = metadata
+                    .get(&format!("{}{}", MAX_KEY, name))
+                    .ok_or_else(|| anyhow!("Block header has no max for column: {}", name))?;
+                columns.insert(
+                    name.to_owned(),
+                    ColumnStats {
+                        min: min.parse()?,
+                        max: max.parse()?,
+                    },
+                );
+            }
+        }
+        Ok(Self { rows, columns })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::SchemaBuilder;
+    use crate::schema::FieldType;
+    use arrow::array::StringArray;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+
+    #[test]
+    fn block_stats_test() {
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .field("status", FieldType::Str)
+            .field("size", FieldType::Int)
+            .build()
+            .unwrap();
+
+        let batch = |time: Vec<i32>, size: Vec<i32>| {
+            let status = vec!["200"; time.len()];
+            RecordBatch::try_new(
+                schema.arrow_schema(),
+                vec![
+                    Arc::new(Int32Array::from(time)),
+                    Arc::new(StringArray::from(status)),
+                    Arc::new(Int32Array::from(size)),
+                ],
+            )
+            .unwrap()
+        };
+
+        let stats = BlockStats::from_batches(
+            &schema,
+            &[batch(vec![10, 5], vec![100, 7]), batch(vec![42], vec![-1])],
+        );
+        assert_eq!(stats.rows, 3);
+        assert_eq!(stats.column("time"), Some(&ColumnStats { min: 5, max: 42 }));
+        assert_eq!(
+            stats.column("size"),
+            Some(&ColumnStats { min: -1, max: 100 })
+        );
+        assert_eq!(stats.column("status"), None);
+
+        assert_eq!(
+            BlockStats::from_metadata(&stats.to_metadata()).unwrap(),
+            stats
+        );
+
+        let time = stats.column("time").unwrap();
+        assert!(time.overlaps(0, 6));
+        assert!(time.overlaps(42, 50));
+        assert!(!time.overlaps(0, 5));
+        assert!(!time.overlaps(43, 50));
+    }
+}
@@ -1,19 +1,631 @@
+//! Tables are a list of immutable blocks
+//!
+//! A block is a plain arrow ipc file (`ARROW1` magic, schema, record
+//! batches, footer) stored through `Store` under `<table>/<name>.arrow`, so
+//! any arrow reader can open it. Batches are read back one at a time, only
+//! keeping the projected columns.
+//!
+//! The schema in the file header also carries the `BlockStats` of the block,
+//! which are used to skip blocks that can't match a time range.
+//!
+//! Which blocks make up a table is tracked by its manifest, see `manifest`.
+//! Tables with a retention drop whole blocks once all their rows are older
+//! than it, see `Table::expire`.
+//! Blocks written but never committed (crash before the commit) are not
+//! part of any version and are simply ignored.
+
 #![allow(dead_code)]
 
-use crate::schema::Schema;
-use std::path::PathBuf;
+use crate::manifest::{Manifest, Snapshot, TableEdit};
+use crate::schema::{Schema, TIME_COL_NAME};
+use crate::stats::BlockStats;
+use crate::Store;
+use anyhow::{anyhow, Result};
+use arrow::datatypes::Schema as ArrowSchema;
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+const BLOCK_EXT: &str = "arrow";
+
+pub struct Table {
+    name: String,
+    schema: Schema,
+    current: RwLock<Arc<Snapshot>>,
+    /// serializes commits, readers never take it
+    commit_lock: Mutex<()>,
+    /// blocks removed from the table but maybe still used by a snapshot
+    obsolete: Mutex<Vec<Arc<TableBlock>>>,
+    /// how long rows are kept, forever if `None`
+    retention: Option<Duration>,
+}
+
+impl Table {
+    /// Empty table, nothing is persisted until the first commit
+    pub fn new(name: impl Into<String>, schema: Schema) -> Self {
+        Self::with_snapshot(name.into(), schema, Snapshot::default())
+    }
+
+    /// Loads the current version of a table from its manifest
+    pub fn open<S: Store>(store: &S, name: &str) -> Result<Self> {
+        let (schema, snapshot) = Manifest::read(store, name)?
+            .ok_or_else(|| anyhow!("Table {} has no manifest", name))?;
+        Ok(Self::with_snapshot(name.to_owned(), schema, snapshot))
+    }
+
+    /// Opens the table if it has a manifest, else creates an empty one
+    pub fn open_or_create<S: Store>(store: &S, name: &str, schema: Schema) -> Result<Self> {
+        match Manifest::read(store, name)? {
+            Some((existing, snapshot)) => {
+                if existing != schema {
+                    return Err(anyhow!(
+                        "Schema of table {} doesn't match its manifest",
+                        name
+                    ));
+                }
+                Ok(Self::with_snapshot(name.to_owned(), existing, snapshot))
+            }
+            None => Ok(Self::new(name, schema)),
+        }
+    }
+
+    fn with_snapshot(name: String, schema: Schema, snapshot: Snapshot) -> Self {
+        Self {
+            name,
+            schema,
+            current: RwLock::new(Arc::new(snapshot)),
+            commit_lock: Mutex::new(()),
+            obsolete: Mutex::new(vec![]),
+            retention: None,
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Current version of the table, unaffected by later commits
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Persists `edit` as the next version & makes it visible to readers
+    pub fn commit<S: Store>(&self, store: &S, edit: TableEdit) -> Result<Arc<Snapshot>> {
+        let _guard = self.commit_lock.lock().unwrap();
+
+        let (next, removed) = self.snapshot().apply(edit)?;
+        Manifest::write(store, &self.name, &self.schema, &next)?;
+
+        let next = Arc::new(next);
+        *self.current.write().unwrap() = next.clone();
+        self.obsolete.lock().unwrap().extend(removed);
+        self.collect_garbage(store)?;
+        Ok(next)
+    }
+
+    /// Drops the blocks whose rows are all older than the retention, `now`
+    /// being in the unit of the time column (seconds). Returns the number of
+    /// dropped blocks, their files go away with `collect_garbage`.
+    pub fn expire<S: Store>(&self, store: &S, now: i32) -> Result<usize> {
+        let retention = match self.retention {
+            Some(retention) => retention.as_secs().min(i32::MAX as u64) as i32,
+            None => return Ok(0),
+        };
+        let cutoff = now.saturating_sub(retention);
 
-struct Table {
-    blocks: Vec<TableBlock>,
+        let expired = self
+            .snapshot()
+            .blocks()
+            .iter()
+            .filter(|block| match block.stats().column(TIME_COL_NAME) {
+                Some(time) => time.max < cutoff,
+                None => false,
+            })
+            .map(|block| block.name().to_owned())
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let edit = expired.iter().fold(TableEdit::new(), |edit, name| {
+            edit.remove_block(name.as_str())
+        });
+        self.commit(store, edit)?;
+        Ok(expired.len())
+    }
+
+    /// Deletes removed blocks that no snapshot uses anymore, returns how
+    /// many were deleted
+    pub fn collect_garbage<S: Store>(&self, store: &S) -> Result<usize> {
+        let mut obsolete = self.obsolete.lock().unwrap();
+        let (unused, used): (Vec<_>, Vec<_>) = obsolete
+            .drain(..)
+            .partition(|block| Arc::strong_count(block) == 1);
+        *obsolete = used;
+
+        for block in &unused {
+            for path in block.indexes().iter().chain(Some(block.path())) {
+                let path = path.to_str().unwrap();
+                if store.exist(path) {
+                    store.delete(path)?;
+                }
+            }
+        }
+        Ok(unused.len())
+    }
 }
 
-struct TableBlock {
+/// Immutable chunk of a table
+pub struct TableBlock {
     name: String,
     schema: Schema,
     path: PathBuf,
+    stats: BlockStats,
+    /// side files built from the block rows, e.g. term dictionaries
+    indexes: Vec<PathBuf>,
 }
 
 impl TableBlock {
-    fn scan(&self) {}
-    fn read(&self) {}
+    /// Writes `batches` as a new block of `table`
+    pub fn write<S: Store>(
+        store: &S,
+        table: &str,
+        name: &str,
+        schema: Schema,
+        batches: &[RecordBatch],
+    ) -> Result<Self> {
+        let stats = BlockStats::from_batches(&schema, batches);
+        let header = Arc::new(ArrowSchema::new_with_metadata(
+            schema.arrow_schema().fields().clone(),
+            stats.to_metadata(),
+        ));
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = FileWriter::try_new(&mut buf, &header)?;
+            for batch in batches {
+                let batch = RecordBatch::try_new(header.clone(), batch.columns().to_vec())?;
+                writer.write(&batch)?;
+            }
+            writer.finish()?;
+        }
+
+        let path = Self::block_path(table, name);
+        store.put(path.to_str().unwrap(), buf)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            schema,
+            path,
+            stats,
+            indexes: vec![],
+        })
+    }
+
+    /// Opens a block written earlier, schema & stats come from its header
+    pub fn open<S: Store>(store: &S, table: &str, name: &str) -> Result<Self> {
+        let path = Self::block_path(table, name);
+        let header = reader(store, &path)?.schema();
+
+        Ok(Self {
+            name: name.to_owned(),
+            schema: Schema::try_from(ArrowSchema::new(header.fields().clone()))?,
+            path,
+            stats: BlockStats::from_metadata(header.metadata())?,
+            indexes: vec![],
+        })
+    }
+
+    pub(crate) fn from_parts(
+        name: String,
+        schema: Schema,
+        path: PathBuf,
+        stats: BlockStats,
+        indexes: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            name,
+            schema,
+            path,
+            stats,
+            indexes,
+        }
+    }
+
+    fn block_path(table: &str, name: &str) -> PathBuf {
+        PathBuf::from(table).join(format!("{}.{}", name, BLOCK_EXT))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn rows(&self) -> usize {
+        self.stats.rows
+    }
+
+    pub fn stats(&self) -> &BlockStats {
+        &self.stats
+    }
+
+    pub fn indexes(&self) -> &[PathBuf] {
+        &self.indexes
+    }
+
+    /// Records a side file of the block, it is deleted along with the block
+    pub fn add_index(&mut self, path: PathBuf) {
+        self.indexes.push(path);
+    }
+
+    /// false only if no value of `column` can be in `[start, end)`.
+    /// Non numeric or unknown columns can't be pruned.
+    pub fn overlaps(&self, column: &str, start: i32, end: i32) -> bool {
+        if self.stats.rows == 0 {
+            return false;
+        }
+        match self.stats.column(column) {
+            Some(stats) => stats.overlaps(start, end),
+            None => !self.has_numeric_column(column),
+        }
+    }
+
+    /// a numeric column without stats only holds nulls
+    fn has_numeric_column(&self, column: &str) -> bool {
+        self.schema
+            .iter()
+            .any(|f| f.name() == column && f.data_type() == &arrow::datatypes::DataType::Int32)
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Streams the batches of the block, `projection` being the indices of
+    /// the columns to keep, in the order they should come back. Batches are
+    /// read from the file one at a time, all their columns being decoded.
+    pub fn scan<S: Store>(&self, store: &S, projection: Option<Vec<usize>>) -> Result<BlockScan> {
+        if let Some(projection) = projection.as_ref() {
+            let columns = self.schema.iter().count();
+            if projection.is_empty() || projection.iter().any(|&i| i >= columns) {
+                return Err(anyhow!(
+                    "Invalid projection {:?} for block {} with {} columns",
+                    projection,
+                    self.name,
+                    columns
+                ));
+            }
+        }
+
+        let reader = reader(store, &self.path)?;
+        let columns = |schema: &ArrowSchema| {
+            schema
+                .fields()
+                .iter()
+                .map(|f| (f.name().clone(), f.data_type().clone()))
+                .collect::<Vec<_>>()
+        };
+        if columns(&reader.schema()) != columns(&self.schema.arrow_schema()) {
+            return Err(anyhow!(
+                "Block {} doesn't match its table schema",
+                self.path.display()
+            ));
+        }
+
+        Ok(BlockScan { reader, projection })
+    }
+
+    /// Reads all batches of the block at once
+    pub fn read<S: Store>(
+        &self,
+        store: &S,
+        projection: Option<Vec<usize>>,
+    ) -> Result<Vec<RecordBatch>> {
+        self.scan(store, projection)?.collect()
+    }
+}
+
+/// Iterator over the batches of a block, see `TableBlock::scan`
+pub struct BlockScan {
+    reader: FileReader<BufReader<File>>,
+    projection: Option<Vec<usize>>,
+}
+
+impl Iterator for BlockScan {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = match self.reader.next()? {
+            Ok(batch) => batch,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        Some(match self.projection.as_ref() {
+            Some(projection) => project(&batch, projection),
+            None => Ok(batch),
+        })
+    }
+}
+
+/// Reader of the block file at `path`, reading batches as they are needed
+fn reader<S: Store>(store: &S, path: &Path) -> Result<FileReader<BufReader<File>>> {
+    let file = File::open(store.root().join(path))?;
+    Ok(FileReader::try_new(BufReader::new(file))?)
+}
+
+fn project(batch: &RecordBatch, projection: &[usize]) -> Result<RecordBatch> {
+    let schema = batch.schema();
+    let fields = projection
+        .iter()
+        .map(|&i| schema.field(i).clone())
+        .collect();
+    let columns = projection
+        .iter()
+        .map(|&i| batch.column(i).clone())
+        .collect();
+    Ok(RecordBatch::try_new(
+        Arc::new(ArrowSchema::new(fields)),
+        columns,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::SchemaBuilder;
+    use crate::schema::FieldType;
+    use crate::FSBlobStore;
+    use arrow::array::{Int32Array, StringArray};
+    use pretty_assertions::assert_eq;
+
+    fn batch(schema: &Schema, from: i32, status: &str) -> RecordBatch {
+        RecordBatch::try_new(
+            schema.arrow_schema(),
+            vec![
+                Arc::new(Int32Array::from(vec![from, from + 1, from + 2])),
+                Arc::new(StringArray::from(vec![status, status, status])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn block_write_and_scan_test() {
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .field("status", FieldType::Str)
+            .build()
+            .unwrap();
+
+        let block = TableBlock::write(
+            &store,
+            "scan_table",
+            "block_write_and_scan_test",
+            schema.clone(),
+            &[batch(&schema, 0, "200"), batch(&schema, 3, "500")],
+        )
+        .unwrap();
+        assert_eq!(block.rows(), 6);
+
+        let batches = block.read(&store, None).unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[1].num_columns(), 2);
+        let time = batches[1]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert_eq!(time.values(), &[3, 4, 5]);
+
+        let mut scan = block.scan(&store, Some(vec![1])).unwrap();
+        let first = scan.next().unwrap().unwrap();
+        assert_eq!(first.num_columns(), 1);
+        assert_eq!(first.schema().field(0).name(), "status");
+        let status = first
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(status.value(0), "200");
+        assert_eq!(scan.count(), 1);
+
+        assert!(block.scan(&store, Some(vec![2])).is_err());
+        assert!(block.scan(&store, Some(vec![])).is_err());
+        store.delete(block.path().to_str().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn blocks_in_range_test() {
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        store.clean("range_table").unwrap();
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .field("status", FieldType::Str)
+            .build()
+            .unwrap();
+
+        let table = Table::new("range_table", schema.clone());
+        for from in &[0, 100, 200] {
+            let name = format!("block_{}", from);
+            let batches = [batch(&schema, *from, "200")];
+            TableBlock::write(&store, table.name(), &name, schema.clone(), &batches).unwrap();
+            // stats survive a reopen
+            let block = TableBlock::open(&store, table.name(), &name).unwrap();
+            table
+                .commit(&store, TableEdit::new().add_block(block))
+                .unwrap();
+        }
+
+        let names = |blocks: Vec<&TableBlock>| {
+            blocks
+                .iter()
+                .map(|b| b.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let snapshot = table.snapshot();
+        assert_eq!(names(snapshot.blocks_in_range(0, 1000)).len(), 3);
+        assert_eq!(
+            names(snapshot.blocks_in_range(101, 201)),
+            vec!["block_100", "block_200"]
+        );
+        assert_eq!(
+            names(snapshot.blocks_in_range(3, 100)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            snapshot.blocks()[2].stats().column("time").unwrap().max,
+            202
+        );
+
+        store.clean("range_table").unwrap();
+    }
+
+    #[test]
+    fn manifest_snapshot_test() {
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        store.clean("manifest_table").unwrap();
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .indexed_field("status", FieldType::Str)
+            .build()
+            .unwrap();
+        let write = |name: &str, from: i32| {
+            let batches = [batch(&schema, from, "200")];
+            TableBlock::write(&store, "manifest_table", name, schema.clone(), &batches).unwrap()
+        };
+
+        let table = Table::open_or_create(&store, "manifest_table", schema.clone()).unwrap();
+        assert_eq!(table.snapshot().version(), 0);
+        table
+            .commit(
+                &store,
+                TableEdit::new()
+                    .add_block(write("a", 0))
+                    .add_block(write("b", 3))
+                    .flushed_lsn(6),
+            )
+            .unwrap();
+
+        // a reader keeps its version while blocks are swapped under it
+        let reader = table.snapshot();
+        let merged = write("ab", 0);
+        let next = table
+            .commit(
+                &store,
+                TableEdit::new()
+                    .remove_block("a")
+                    .remove_block("b")
+                    .add_block(merged),
+            )
+            .unwrap();
+        assert_eq!(reader.version(), 1);
+        assert_eq!(reader.blocks().len(), 2);
+        assert_eq!(reader.blocks()[0].read(&store, None).unwrap().len(), 1);
+        assert_eq!(next.version(), 2);
+        assert_eq!(next.flushed_lsn(), 6);
+        assert!(store.exist("manifest_table/a.arrow"));
+
+        drop(reader);
+        assert_eq!(table.collect_garbage(&store).unwrap(), 2);
+        assert!(!store.exist("manifest_table/a.arrow"));
+        assert!(!store.exist("manifest_table/MANIFEST-00000000000000000001"));
+
+        let reopened = Table::open(&store, "manifest_table").unwrap();
+        let snapshot = reopened.snapshot();
+        assert_eq!(snapshot.version(), 2);
+        assert_eq!(snapshot.flushed_lsn(), 6);
+        assert_eq!(snapshot.blocks().len(), 1);
+        assert_eq!(snapshot.blocks()[0].name(), "ab");
+        assert_eq!(snapshot.blocks()[0].stats(), next.blocks()[0].stats());
+        assert_eq!(snapshot.blocks()[0].schema(), &schema);
+        assert!(reopened.schema().is_indexed("status"));
+
+        assert!(table
+            .commit(&store, TableEdit::new().remove_block("a"))
+            .is_err());
+        assert!(Table::open_or_create(
+            &store,
+            "manifest_table",
+            SchemaBuilder::new().timestamp().build().unwrap()
+        )
+        .is_err());
+
+        store.clean("manifest_table").unwrap();
+    }
+
+    #[test]
+    fn retention_test() {
+        let store = FSBlobStore {
+            root: "./root".into(),
+            blobs: vec![],
+        };
+        store.clean("retention_table").unwrap();
+        let schema = SchemaBuilder::new()
+            .timestamp()
+            .field("status", FieldType::Str)
+            .build()
+            .unwrap();
+
+        let table =
+            Table::new("retention_table", schema.clone()).with_retention(Duration::from_secs(50));
+        for from in &[0, 100, 200] {
+            let name = format!("block_{}", from);
+            let batches = [batch(&schema, *from, "200")];
+            let mut block =
+                TableBlock::write(&store, table.name(), &name, schema.clone(), &batches).unwrap();
+            let term = format!("retention_table/{}.term", name);
+            store.put(&term, vec![0]).unwrap();
+            block.add_index(term.into());
+            table
+                .commit(&store, TableEdit::new().add_block(block))
+                .unwrap();
+        }
+
+        // anything before 110 is expired, block_100 ends at 102
+        assert_eq!(table.expire(&store, 160).unwrap(), 2);
+        assert_eq!(table.expire(&store, 160).unwrap(), 0);
+        let snapshot = table.snapshot();
+        assert_eq!(snapshot.blocks().len(), 1);
+        assert_eq!(snapshot.blocks()[0].name(), "block_200");
+        assert!(!store.exist("retention_table/block_100.arrow"));
+        assert!(!store.exist("retention_table/block_100.term"));
+        assert!(store.exist("retention_table/block_200.term"));
+
+        let forever = Table::open(&store, "retention_table").unwrap();
+        assert_eq!(forever.expire(&store, i32::MAX).unwrap(), 0);
+
+        store.clean("retention_table").unwrap();
+    }
 }
@@ -3,21 +3,46 @@
 //! WAL is a apend only store to keep current going writes
 //! we write the files here temporarily, which will be moved to
 //! columnar store once we are done
+//!
+//! Records are framed as `len (u32) | crc32 (u32) | lsn (u64) | data` and
+//! appended to segment files under the store root. Every record gets a
+//! monotonically increasing log sequence number (lsn), and each segment is
+//! named after the lsn of its first record (`00000000000000000001.wal`, ...).
+//! A segment is rolled once it grows past `max_segment_size`.
+//!
+//! Once records are persisted somewhere else, `Wal::checkpoint(lsn)` marks
+//! them as done: replay starts right after the checkpoint and segments that
+//! only hold records up to it are deleted.
+//!
+//! When records hit the disk is decided by `Durability`. `Wal` is meant for
+//! a single writer, `Wal::shared` turns it into a `SharedWal` that many
+//! threads can append to, batching their fsyncs for group commit.
 
 #![allow(dead_code)]
 
-use anyhow::Result;
+mod shared;
+mod store;
+
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use fs2::FileExt;
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+pub use shared::SharedWal;
+pub use store::{BlobWriter, FSBlobStore, MemStore, Store};
+
+const HEADER_SIZE: u64 = 16;
+const SEGMENT_EXT: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
 
 /// WriteRecord is a unit entry in Wal
+#[derive(Debug, PartialEq)]
 pub struct WriteRecord {
     crc: u32,
     len: u32,
+    lsn: u64,
     data: Vec<u8>,
 }
 
@@ -25,175 +50,584 @@ impl WriteRecord {
     pub fn new(data: Vec<u8>) -> Result<Self> {
         let len = data.len().try_into()?;
         Ok(Self {
-            crc: 0xdddd,
+            crc: checksum(0, &data),
             len,
+            lsn: 0,
             data,
         })
     }
-}
 
-pub trait Store {
-    fn open_file_for_read(&self, path: &str) -> Result<File>;
-    fn open_file_for_append(&self, path: &str) -> Result<File>;
-    fn root(&self) -> Option<&PathBuf>;
+    /// sequence number assigned by the wal, 0 until the record is appended
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.len as usize == self.data.len() && self.crc == checksum(self.lsn, &self.data)
+    }
+
+    /// size of the record on disk, header included
+    fn frame_len(&self) -> u64 {
+        HEADER_SIZE + self.len as u64
+    }
+
+    /// header & payload are written in one go, so a crash leaves at most
+    /// one partially written record at the tail of a segment
+    fn encode(&self, lsn: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.frame_len() as usize);
+        buf.write_u32::<LittleEndian>(self.len)?;
+        buf.write_u32::<LittleEndian>(checksum(lsn, &self.data))?;
+        buf.write_u64::<LittleEndian>(lsn)?;
+        buf.extend_from_slice(&self.data);
+        Ok(buf)
+    }
 }
 
-enum StoreType {
-    FSBlobStore(FSBlobStore),
-    MemStore(),
+/// crc covers the lsn too, so a record can't be replayed under another lsn
+fn checksum(lsn: u64, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&lsn.to_le_bytes());
+    hasher.update(data);
+    hasher.finalize()
 }
 
-type FSBlob = File;
-pub struct FSBlobStore {
-    pub root: Option<PathBuf>,
-    pub blobs: Vec<FSBlob>,
+/// Outcome of reading one frame from a segment
+enum Frame {
+    Record(WriteRecord),
+    /// clean end of segment, no bytes left
+    Eof,
+    /// partially written or corrupted record, nothing after it can be trusted
+    Torn,
 }
 
-impl Store for FSBlobStore {
-    fn open_file_for_read(&self, path: &str) -> Result<File> {
-        Ok(std::fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .open(path)?)
+fn read_frame<R: Read>(reader: &mut R) -> std::io::Result<Frame> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Frame::Eof),
+        n if n < header.len() => return Ok(Frame::Torn),
+        _ => (),
     }
 
-    fn open_file_for_append(&self, path: &str) -> Result<File> {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .write(true)
-            .open(self.root.as_ref().unwrap().join(path))?;
-        let thread = std::thread::current();
-        let tname = thread.name().unwrap();
-        // println!("{:?} :: opening file {}", tname, path);
-        // println!("{:?} :: waiting for lock for append", tname);
-        // file.lock_exclusive()?;
-        // println!("{:?} :: lock aquired for append", tname);
-        Ok(file)
+    let mut header = &header[..];
+    let len = header.read_u32::<LittleEndian>()?;
+    let crc = header.read_u32::<LittleEndian>()?;
+    let lsn = header.read_u64::<LittleEndian>()?;
+
+    // don't trust `len` for allocation, it may be garbage
+    let mut data = Vec::new();
+    reader.take(len as u64).read_to_end(&mut data)?;
+
+    let record = WriteRecord {
+        crc,
+        len,
+        lsn,
+        data,
+    };
+    if record.is_valid() {
+        Ok(Frame::Record(record))
+    } else {
+        Ok(Frame::Torn)
     }
+}
 
-    fn root(&self) -> Option<&PathBuf> {
-        self.root.as_ref()
+/// like `read_exact` but returns the number of bytes read before EOF
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
     }
+    Ok(read)
+}
+
+/// segments are named after the lsn of their first record
+fn segment_name(first_lsn: u64) -> String {
+    format!("{:020}.{}", first_lsn, SEGMENT_EXT)
 }
 
-// impl Iterator for FSBlobStore {
-//     type Item = FSBlob;
-//     fn next(&mut self) -> Option<Self::Item> {
-//         let current_file = self
-//             .root
-//             .read_dir()
-//             .unwrap()
-//             .filter(|f| {
-//                 let path = f.unwrap().path();
-//                 path.is_file() && path.extension().unwrap() == ""
-//             })
-//             .next();
-
-//     }
-// }
+fn parse_segment_name(name: &str) -> Option<u64> {
+    let (id, ext) = name.split_at(name.find('.')?);
+    if &ext[1..] != SEGMENT_EXT {
+        return None;
+    }
+    id.parse().ok()
+}
+
+/// Decides when appended records are fsynced
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability {
+    /// fsync before every append returns
+    #[default]
+    Always,
+    /// Appends from concurrent writers share one fsync. The first writer to
+    /// need a sync waits up to `max_latency` for in flight appends to join it.
+    /// With a single writer this is the same as `Always`.
+    GroupCommit { max_latency: Duration },
+    /// fsync every `interval`, appends return as soon as they are written.
+    /// A crash loses at most `interval` worth of records.
+    Periodic { interval: Duration },
+}
 
 pub struct Wal<T: Store> {
     pub store: T,
-    pub active_file: Option<File>,
+    pub active_file: Option<T::Writer>,
+    /// lsn of the first record in the active segment
+    segment_id: u64,
+    segment_size: u64,
+    max_segment_size: u64,
+    next_lsn: u64,
+    checkpoint_lsn: u64,
+    durability: Durability,
+    last_sync: Instant,
 }
 
 impl<T: Store> Wal<T> {
-    pub fn append(&mut self, payload: &WriteRecord) -> Result<u32> {
-        let mut f = match self.active_file.take() {
-            Some(f) => f,
-            None => self.store.open_file_for_append("file.db")?,
-        };
-        //std::thread::sleep_ms(500);
+    /// Appends the record, syncing it as per `Durability`, and returns the
+    /// lsn assigned to it
+    pub fn append(&mut self, payload: &WriteRecord) -> Result<u64> {
+        let lsn = self.write(payload)?;
+        match self.durability {
+            Durability::Always | Durability::GroupCommit { .. } => self.fsync()?,
+            Durability::Periodic { interval } => {
+                if self.last_sync.elapsed() >= interval {
+                    self.fsync()?;
+                }
+            }
+        }
+        Ok(lsn)
+    }
+
+    /// Writes the record to the active segment without syncing it
+    fn write(&mut self, payload: &WriteRecord) -> Result<u64> {
+        self.open()?;
 
-        // std::thread::sleep_ms(10000);
-        f.write_u32::<LittleEndian>(payload.len)
-            // .and_then(|_| f.write_u16::<LittleEndian>(payload.crc))
-            .and_then(|_| f.write_all(&payload.data))?;
-        drop(payload);
-        std::fs::remove_file(self.store.root().unwrap().join("file.db"))?;
-        // self.active_file = Some(f);
-        Ok(0)
+        let lsn = self.next_lsn;
+        let frame = payload.encode(lsn)?;
+        let frame_len = frame.len() as u64;
+
+        if self.segment_size > 0 && self.segment_size + frame_len > self.max_segment_size {
+            self.roll()?;
+        }
+
+        let f = self.active_file.as_mut().unwrap();
+        f.write_all(&frame)?;
+        self.segment_size += frame_len;
+        self.next_lsn += 1;
+        Ok(lsn)
     }
 
-    pub fn fsync(&mut self) -> Result<()> {
-        let f = self.active_file.take();
+    /// Marks every record up to & including `lsn` as persisted elsewhere.
+    /// Replay starts after it and segments fully below it are deleted.
+    pub fn checkpoint(&mut self, lsn: u64) -> Result<()> {
+        self.open()?;
 
-        if let Some(f) = f {
-            f.sync_all()?;
+        if lsn >= self.next_lsn {
+            return Err(anyhow!(
+                "Can't checkpoint lsn {}, last appended lsn is {}",
+                lsn,
+                self.next_lsn - 1
+            ));
         }
+        if lsn <= self.checkpoint_lsn {
+            return Ok(());
+        }
+
+        // make sure the checkpoint never points past what is on disk
+        self.fsync()?;
+        self.write_checkpoint(lsn)?;
+        self.checkpoint_lsn = lsn;
+        self.truncate()
+    }
+
+    /// lsn of the last checkpoint, 0 if there is none
+    pub fn checkpoint_lsn(&mut self) -> Result<u64> {
+        self.open()?;
+        Ok(self.checkpoint_lsn)
+    }
+
+    /// lsn the next appended record will get
+    pub fn next_lsn(&mut self) -> Result<u64> {
+        self.open()?;
+        Ok(self.next_lsn)
+    }
+
+    pub fn fsync(&mut self) -> Result<()> {
+        if let Some(f) = self.active_file.as_ref() {
+            f.sync()?;
+        }
+        self.last_sync = Instant::now();
         Ok(())
     }
 
+    /// Iterates over every intact record after the last checkpoint, in lsn
+    /// order. Iteration ends at the first torn or corrupted record.
+    pub fn replay(&self) -> Result<Replay<'_, T>> {
+        Ok(Replay {
+            store: &self.store,
+            segments: self.segments()?.into_iter(),
+            reader: None,
+            from_lsn: self.read_checkpoint()? + 1,
+            done: false,
+        })
+    }
+
     pub fn new(t: T) -> Self {
         Wal {
             active_file: None,
             store: t,
+            segment_id: 0,
+            segment_size: 0,
+            max_segment_size: DEFAULT_SEGMENT_SIZE,
+            next_lsn: 1,
+            checkpoint_lsn: 0,
+            durability: Durability::default(),
+            last_sync: Instant::now(),
         }
     }
+
+    pub fn max_segment_size(mut self, max_segment_size: u64) -> Self {
+        self.max_segment_size = max_segment_size;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// first lsn of all segments in the store, oldest first
+    fn segments(&self) -> Result<Vec<u64>> {
+        let mut ids = self
+            .store
+            .list()?
+            .iter()
+            .filter_map(|name| parse_segment_name(name))
+            .collect::<Vec<u64>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn open(&mut self) -> Result<()> {
+        if self.active_file.is_none() {
+            self.active_file = Some(self.recover_active_segment()?);
+        }
+        Ok(())
+    }
+
+    /// Opens the newest segment for append, cutting off a torn tail left
+    /// behind by a crash so new records don't land after garbage.
+    fn recover_active_segment(&mut self) -> Result<T::Writer> {
+        self.checkpoint_lsn = self.read_checkpoint()?;
+
+        let last = self.segments()?.last().copied();
+        let id = last.unwrap_or(self.checkpoint_lsn + 1);
+        let name = segment_name(id);
+
+        let mut valid_len = 0;
+        let mut next_lsn = id;
+        if last.is_some() {
+            let mut reader = BufReader::new(self.store.open_file_for_read(&name)?);
+            while let Frame::Record(record) = read_frame(&mut reader)? {
+                valid_len += record.frame_len();
+                next_lsn = record.lsn + 1;
+            }
+        }
+
+        let file = self.store.open_file_for_append(&name)?;
+        if file.size()? > valid_len {
+            file.truncate(valid_len)?;
+            file.sync()?;
+        }
+
+        self.segment_id = id;
+        self.segment_size = valid_len;
+        self.next_lsn = next_lsn.max(self.checkpoint_lsn + 1);
+        Ok(file)
+    }
+
+    fn roll(&mut self) -> Result<()> {
+        self.fsync()?;
+        self.segment_id = self.next_lsn;
+        self.segment_size = 0;
+        self.active_file = Some(
+            self.store
+                .open_file_for_append(&segment_name(self.segment_id))?,
+        );
+        Ok(())
+    }
+
+    /// Deletes segments whose records are all covered by the checkpoint.
+    /// The active segment is always kept.
+    fn truncate(&mut self) -> Result<()> {
+        let segments = self.segments()?;
+        for pair in segments.windows(2) {
+            // every record in pair[0] has an lsn below pair[1]
+            if pair[1] > self.checkpoint_lsn + 1 {
+                break;
+            }
+            self.store.remove(&segment_name(pair[0]))?;
+        }
+        Ok(())
+    }
+
+    /// Written to a temp file & renamed over the old one, so a crash leaves
+    /// either the old or the new checkpoint behind.
+    fn write_checkpoint(&self, lsn: u64) -> Result<()> {
+        let tmp = format!("{}.tmp", CHECKPOINT_FILE);
+        let mut f = self.store.open_file_for_write(&tmp)?;
+        f.write_all(&WriteRecord::new(vec![])?.encode(lsn)?)?;
+        f.sync()?;
+        self.store.rename(&tmp, CHECKPOINT_FILE)
+    }
+
+    fn read_checkpoint(&self) -> Result<u64> {
+        if !self
+            .store
+            .list()?
+            .iter()
+            .any(|name| name == CHECKPOINT_FILE)
+        {
+            return Ok(0);
+        }
+
+        let mut f = self.store.open_file_for_read(CHECKPOINT_FILE)?;
+        match read_frame(&mut f)? {
+            Frame::Record(record) => Ok(record.lsn),
+            _ => Err(anyhow!("Corrupted wal checkpoint")),
+        }
+    }
+}
+
+/// Iterator returned by `Wal::replay`
+pub struct Replay<'a, T: Store> {
+    store: &'a T,
+    segments: std::vec::IntoIter<u64>,
+    reader: Option<BufReader<T::Reader>>,
+    from_lsn: u64,
+    done: bool,
+}
+
+impl<'a, T: Store> Iterator for Replay<'a, T> {
+    type Item = Result<WriteRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.reader.is_none() {
+                let id = self.segments.next()?;
+                match self.store.open_file_for_read(&segment_name(id)) {
+                    Ok(f) => self.reader = Some(BufReader::new(f)),
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+
+            let reader = self.reader.as_mut().unwrap();
+            match read_frame(reader) {
+                Ok(Frame::Record(record)) if record.lsn < self.from_lsn => (),
+                Ok(Frame::Record(record)) => return Some(Ok(record)),
+                Ok(Frame::Eof) => self.reader = None,
+                Ok(Frame::Torn) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    // #[test]
-    // fn wal_write_test() {
-    //     let mut wal = Wal::new(FSBlobStore { blobs: vec![] });
-    //     let record = WriteRecord::new(Vec::from("Hello world!")).unwrap();
-    //     for i in 0..100 {
-    //         wal.append(&record).unwrap();
-    //     }
+    #[test]
+    fn wal_write_test() {
+        let mut wal = Wal::new(MemStore::new());
+        let record = WriteRecord::new(Vec::from("Hello world!")).unwrap();
+        for _ in 0..100 {
+            wal.append(&record).unwrap();
+        }
+
+        wal.fsync().unwrap();
 
-    //     wal.fsync().unwrap();
-    // }
+        let records = wal.replay().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 100);
+        assert!(records.iter().all(|r| r.data() == b"Hello world!"));
+        assert_eq!(
+            records.iter().map(|r| r.lsn()).collect::<Vec<u64>>(),
+            (1..=100).collect::<Vec<u64>>()
+        );
+    }
 
     #[test]
-    fn wal_write_concurrent_test() {
-        std::thread::sleep_ms(20000);
-        println!(
-            "cpu count:: logical: {}, phys: {}",
-            num_cpus::get(),
-            num_cpus::get_physical()
+    fn wal_segment_roll_test() {
+        let mut wal = Wal::new(MemStore::new()).max_segment_size(80);
+        for i in 0..10u8 {
+            wal.append(&WriteRecord::new(vec![i; 20]).unwrap()).unwrap();
+        }
+        wal.fsync().unwrap();
+
+        assert_eq!(wal.segments().unwrap(), vec![1, 3, 5, 7, 9]);
+        let records = wal.replay().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            records.into_iter().map(|r| r.data[0]).collect::<Vec<u8>>(),
+            (0..10).collect::<Vec<u8>>()
         );
-        let hndl = std::thread::Builder::new()
-            .name("THREAD-1".into())
-            .spawn(|| {
-                println!("thread1 started");
-                let mut wal = Wal::new(FSBlobStore {
-                    root: Some("../db".into()),
-                    blobs: vec![],
-                });
-                let record =
-                    WriteRecord::new(vec![1u8; (52428800 * 2).try_into().unwrap()]).unwrap();
-                for _ in 0..5000000 {
-                    // println!("writing in thread1");
-                    wal.append(&record).unwrap();
-                }
-                wal.fsync().unwrap();
-            })
+    }
+
+    #[test]
+    fn wal_torn_tail_test() {
+        let store = MemStore::new();
+        let mut wal = Wal::new(store.clone());
+        for i in 0..3u8 {
+            wal.append(&WriteRecord::new(vec![i; 10]).unwrap()).unwrap();
+        }
+        drop(wal);
+
+        // simulate a crash in the middle of writing a record
+        let mut f = store.open_file_for_append(&segment_name(1)).unwrap();
+        f.write_all(&[12, 0, 0, 0, 1, 2]).unwrap();
+
+        let mut wal = Wal::new(store);
+        assert_eq!(wal.replay().unwrap().count(), 3);
+
+        // appending after recovery drops the torn tail
+        let lsn = wal.append(&WriteRecord::new(vec![3; 10]).unwrap()).unwrap();
+        assert_eq!(lsn, 4);
+        let records = wal.replay().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3].data(), &[3; 10]);
+    }
+
+    #[test]
+    fn wal_checkpoint_test() {
+        let store = MemStore::new();
+        let mut wal = Wal::new(store.clone()).max_segment_size(80);
+        for i in 0..10u8 {
+            wal.append(&WriteRecord::new(vec![i; 20]).unwrap()).unwrap();
+        }
+
+        assert!(wal.checkpoint(11).is_err());
+        wal.checkpoint(6).unwrap();
+
+        // segment 5 holds lsn 5 & 6, segment 7 still has records to replay
+        assert_eq!(wal.segments().unwrap(), vec![7, 9]);
+        let records = wal.replay().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.lsn()).collect::<Vec<u64>>(),
+            (7..=10).collect::<Vec<u64>>()
+        );
+
+        // checkpoint & lsn survive a restart
+        drop(wal);
+        let mut wal = Wal::new(store);
+        assert_eq!(wal.checkpoint_lsn().unwrap(), 6);
+        assert_eq!(wal.next_lsn().unwrap(), 11);
+
+        // checkpointing everything keeps only the active segment
+        wal.checkpoint(10).unwrap();
+        assert_eq!(wal.segments().unwrap(), vec![9]);
+        assert_eq!(wal.replay().unwrap().count(), 0);
+        assert_eq!(
+            wal.append(&WriteRecord::new(vec![10; 20]).unwrap())
+                .unwrap(),
+            11
+        );
+    }
+
+    #[test]
+    fn wal_fs_store_test() {
+        let root = std::env::temp_dir()
+            .join("akiradb-wal")
+            .join("wal_fs_store_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let fs_store = || FSBlobStore {
+            root: Some(root.clone()),
+            blobs: vec![],
+        };
+
+        let mut wal = Wal::new(fs_store()).max_segment_size(80);
+        for i in 0..10u8 {
+            wal.append(&WriteRecord::new(vec![i; 20]).unwrap()).unwrap();
+        }
+        wal.checkpoint(4).unwrap();
+        drop(wal);
+
+        // simulate a crash in the middle of writing a record
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(root.join(segment_name(9)))
             .unwrap();
+        f.write_all(&[12, 0, 0, 0, 1, 2]).unwrap();
+        drop(f);
 
-        /*
-        let hndl2 = std::thread::Builder::new()
-            .name("THREAD-2".into())
-            .spawn(|| {
-                println!("thread2 started");
-                let mut wal2 = Wal::new(FSBlobStore {
-                    root: "../db".into(),
-                    blobs: vec![],
-                });
-                let record = WriteRecord::new(Vec::from("Secon Writer")).unwrap();
-                for _ in 0..100 {
-                    // println!("writing in thread2");
-                    wal2.append(&record).unwrap();
-                }
-                wal2.fsync().unwrap();
+        let mut wal = Wal::new(fs_store());
+        assert_eq!(wal.next_lsn().unwrap(), 11);
+        assert_eq!(
+            wal.replay()
+                .unwrap()
+                .map(|r| r.unwrap().lsn())
+                .collect::<Vec<u64>>(),
+            (5..=10).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn wal_corrupt_record_test() {
+        let record = WriteRecord::new(Vec::from("Hello world!")).unwrap();
+        let mut frame = record.encode(1).unwrap();
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+
+        assert!(matches!(read_frame(&mut &frame[..]).unwrap(), Frame::Torn));
+    }
+
+    #[test]
+    fn wal_write_concurrent_test() {
+        let wal = Wal::new(MemStore::new())
+            .max_segment_size(64 * 1024)
+            .durability(Durability::GroupCommit {
+                max_latency: Duration::from_millis(1),
             })
+            .shared()
             .unwrap();
-        */
-        dbg!("waiting for both thread!");
-        hndl.join().unwrap();
-        //hndl2.join().unwrap();
+
+        let hndls = (1..=num_cpus::get().max(2))
+            .map(|i| {
+                let wal = wal.clone();
+                std::thread::Builder::new()
+                    .name(format!("THREAD-{}", i))
+                    .spawn(move || {
+                        let record = WriteRecord::new(vec![i as u8; 1024]).unwrap();
+                        (0..100)
+                            .map(|_| wal.append(&record).unwrap())
+                            .collect::<Vec<u64>>()
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let mut lsns = hndls
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect::<Vec<u64>>();
+        lsns.sort_unstable();
+        lsns.dedup();
+        assert_eq!(lsns.len(), num_cpus::get().max(2) * 100);
     }
 }
@@ -0,0 +1,280 @@
+//! Wal handle shared between writer threads
+//!
+//! Writers take turns writing their record under one lock, and then sync as
+//! per `Durability`. For group commit the first writer that needs a sync
+//! becomes the leader: it waits for in flight appends, fsyncs once for all
+//! of them and wakes up everyone whose record is now on disk.
+
+use crate::{BlobWriter, Durability, Store, Wal, WriteRecord};
+use anyhow::{anyhow, Result};
+use log::error;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Instant;
+
+struct Inner<T: Store> {
+    wal: Mutex<Wal<T>>,
+    state: Mutex<SyncState>,
+    /// signalled when a record is written or a sync finishes
+    changed: Condvar,
+    durability: Durability,
+}
+
+#[derive(Default)]
+struct SyncState {
+    /// appends that are waiting for or holding the wal lock
+    in_flight: usize,
+    /// every record up to this lsn is on disk
+    synced_lsn: u64,
+    /// a leader is currently syncing
+    syncing: bool,
+    /// a sync failed, we don't know what made it to disk anymore
+    poisoned: bool,
+}
+
+pub struct SharedWal<T: Store> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Store> Clone for SharedWal<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Wal<T>
+where
+    T: Store + Send + 'static,
+    T::Writer: Send,
+{
+    /// Wraps the wal so it can be appended to from many threads.
+    /// `Durability::Periodic` spawns a thread that syncs in the background.
+    pub fn shared(self) -> Result<SharedWal<T>> {
+        let durability = self.durability;
+        let inner = Arc::new(Inner {
+            wal: Mutex::new(self),
+            state: Mutex::new(SyncState::default()),
+            changed: Condvar::new(),
+            durability,
+        });
+
+        if let Durability::Periodic { interval } = durability {
+            let weak = Arc::downgrade(&inner);
+            std::thread::Builder::new()
+                .name("wal-sync".into())
+                .spawn(move || loop {
+                    std::thread::sleep(interval);
+                    if !periodic_sync(&weak) {
+                        break;
+                    }
+                })?;
+        }
+
+        Ok(SharedWal { inner })
+    }
+}
+
+/// returns false once the wal is dropped
+fn periodic_sync<T: Store>(weak: &Weak<Inner<T>>) -> bool {
+    let inner = match weak.upgrade() {
+        Some(inner) => inner,
+        None => return false,
+    };
+
+    if let Err(e) = inner.sync() {
+        error!("wal background sync failed: {}", e);
+    }
+    true
+}
+
+impl<T: Store> SharedWal<T> {
+    /// Appends the record and returns its lsn once it is as durable as the
+    /// configured `Durability` promises
+    pub fn append(&self, payload: &WriteRecord) -> Result<u64> {
+        let lsn = self.write(payload)?;
+        self.sync_to(lsn)?;
+        Ok(lsn)
+    }
+
+    /// Writes the record without waiting for it to be durable, callers that
+    /// need to do something in lsn order do it between `write` & `sync_to`
+    pub fn write(&self, payload: &WriteRecord) -> Result<u64> {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.poisoned {
+                return Err(anyhow!("Wal sync failed earlier, refusing to append"));
+            }
+            state.in_flight += 1;
+        }
+
+        let written = self.inner.wal.lock().unwrap().write(payload);
+
+        self.inner.state.lock().unwrap().in_flight -= 1;
+        self.inner.changed.notify_all();
+        written
+    }
+
+    /// Waits until `lsn` is as durable as the configured `Durability` promises
+    pub fn sync_to(&self, lsn: u64) -> Result<()> {
+        match self.inner.durability {
+            Durability::Always => self.inner.sync(),
+            Durability::GroupCommit { max_latency } => self.group_commit(lsn, max_latency),
+            Durability::Periodic { .. } => Ok(()),
+        }
+    }
+
+    pub fn fsync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    pub fn checkpoint(&self, lsn: u64) -> Result<()> {
+        self.inner.wal.lock().unwrap().checkpoint(lsn)
+    }
+
+    pub fn next_lsn(&self) -> Result<u64> {
+        self.inner.wal.lock().unwrap().next_lsn()
+    }
+
+    fn group_commit(&self, lsn: u64, max_latency: std::time::Duration) -> Result<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if state.poisoned {
+                return Err(anyhow!("Wal sync failed, record {} may be lost", lsn));
+            }
+            if state.synced_lsn >= lsn {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.inner.changed.wait(state).unwrap();
+        }
+
+        // we are the leader, give concurrent appends a chance to join us
+        state.syncing = true;
+        let deadline = Instant::now() + max_latency;
+        while state.in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self
+                .inner
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        drop(state);
+
+        let synced = self.inner.sync_handle().and_then(|(file, lsn)| {
+            if let Some(file) = file {
+                file.sync()?;
+            }
+            Ok(lsn)
+        });
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.syncing = false;
+        let result = match synced {
+            Ok(synced_lsn) => {
+                state.synced_lsn = state.synced_lsn.max(synced_lsn);
+                Ok(())
+            }
+            Err(e) => {
+                state.poisoned = true;
+                Err(e)
+            }
+        };
+        drop(state);
+        self.inner.changed.notify_all();
+        result
+    }
+}
+
+impl<T: Store> Inner<T> {
+    fn sync(&self) -> Result<()> {
+        let result = self.wal.lock().unwrap().fsync();
+        if result.is_err() {
+            self.state.lock().unwrap().poisoned = true;
+        }
+        result
+    }
+
+    /// Handle to the active segment & the last lsn written to it, so the
+    /// fsync itself can run without holding the wal lock. Segments that got
+    /// rolled in between were already synced by the roll.
+    fn sync_handle(&self) -> Result<(Option<T::Writer>, u64)> {
+        let wal = self.wal.lock().unwrap();
+        let file = match wal.active_file.as_ref() {
+            Some(f) => Some(f.try_clone()?),
+            None => None,
+        };
+        Ok((file, wal.next_lsn - 1))
+    }
+}
+
+impl<T: Store> Drop for Inner<T> {
+    fn drop(&mut self) {
+        if let Ok(wal) = self.wal.get_mut() {
+            let _ = wal.fsync();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemStore;
+    use std::time::Duration;
+
+    fn write_from_threads(wal: &SharedWal<MemStore>, threads: usize, records: usize) {
+        let handles = (0..threads)
+            .map(|t| {
+                let wal = wal.clone();
+                std::thread::spawn(move || {
+                    let record = WriteRecord::new(vec![t as u8; 128]).unwrap();
+                    for _ in 0..records {
+                        wal.append(&record).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn group_commit_test() {
+        let wal = Wal::new(MemStore::new())
+            .durability(Durability::GroupCommit {
+                max_latency: Duration::from_millis(2),
+            })
+            .shared()
+            .unwrap();
+
+        write_from_threads(&wal, 8, 50);
+        assert_eq!(wal.next_lsn().unwrap(), 401);
+        assert_eq!(wal.inner.state.lock().unwrap().synced_lsn, 400);
+    }
+
+    #[test]
+    fn periodic_sync_test() {
+        let store = MemStore::new();
+        let wal = Wal::new(store.clone())
+            .durability(Durability::Periodic {
+                interval: Duration::from_millis(5),
+            })
+            .shared()
+            .unwrap();
+
+        write_from_threads(&wal, 4, 25);
+        drop(wal);
+
+        let wal = Wal::new(store);
+        assert_eq!(wal.replay().unwrap().count(), 100);
+    }
+}
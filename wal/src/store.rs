@@ -0,0 +1,309 @@
+//! Where wal segments live
+//!
+//! `FSBlobStore` keeps them as files under `root`, `MemStore` keeps them in
+//! memory for tests & nodes that don't need to survive a restart.
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Write side of a blob, what the wal needs on top of `Write`
+pub trait BlobWriter: Write + Sized {
+    /// make everything written so far durable
+    fn sync(&self) -> Result<()>;
+    /// current size in bytes
+    fn size(&self) -> Result<u64>;
+    /// cut the blob down to `size` bytes
+    fn truncate(&self, size: u64) -> Result<()>;
+    /// another handle to the same blob, used to sync without the wal lock
+    fn try_clone(&self) -> Result<Self>;
+}
+
+pub trait Store {
+    type Reader: Read;
+    type Writer: BlobWriter;
+
+    fn open_file_for_read(&self, path: &str) -> Result<Self::Reader>;
+    fn open_file_for_append(&self, path: &str) -> Result<Self::Writer>;
+    /// creates the file, or truncates it if it already exists
+    fn open_file_for_write(&self, path: &str) -> Result<Self::Writer>;
+    fn root(&self) -> Option<&PathBuf>;
+    /// names of all files directly under root
+    fn list(&self) -> Result<Vec<String>>;
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    fn remove(&self, path: &str) -> Result<()>;
+}
+
+enum StoreType {
+    FSBlobStore(FSBlobStore),
+    MemStore(MemStore),
+}
+
+impl BlobWriter for File {
+    fn sync(&self) -> Result<()> {
+        Ok(self.sync_all()?)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        Ok(self.set_len(size)?)
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(File::try_clone(self)?)
+    }
+}
+
+type FSBlob = File;
+pub struct FSBlobStore {
+    pub root: Option<PathBuf>,
+    pub blobs: Vec<FSBlob>,
+}
+
+impl FSBlobStore {
+    fn path(&self, path: &str) -> PathBuf {
+        match self.root.as_ref() {
+            Some(root) => root.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+impl Store for FSBlobStore {
+    type Reader = File;
+    type Writer = File;
+
+    fn open_file_for_read(&self, path: &str) -> Result<File> {
+        Ok(std::fs::OpenOptions::new()
+            .read(true)
+            .open(self.path(path))?)
+    }
+
+    fn open_file_for_append(&self, path: &str) -> Result<File> {
+        if let Some(root) = self.root.as_ref() {
+            std::fs::create_dir_all(root)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(path))?;
+        Ok(file)
+    }
+
+    fn open_file_for_write(&self, path: &str) -> Result<File> {
+        if let Some(root) = self.root.as_ref() {
+            std::fs::create_dir_all(root)?;
+        }
+        Ok(std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.path(path))?)
+    }
+
+    fn root(&self) -> Option<&PathBuf> {
+        self.root.as_ref()
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let root = self.root.clone().unwrap_or_else(|| PathBuf::from("."));
+        let dir = match std::fs::read_dir(root) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut names = vec![];
+        for entry in dir {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        std::fs::rename(self.path(from), self.path(to))?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        std::fs::remove_file(self.path(path))?;
+        Ok(())
+    }
+}
+
+type MemBlob = Arc<RwLock<Vec<u8>>>;
+
+/// Keeps every blob in memory, clones share the same blobs
+#[derive(Clone, Default)]
+pub struct MemStore {
+    blobs: Arc<Mutex<BTreeMap<String, MemBlob>>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn blob(&self, path: &str) -> Result<MemBlob> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| anyhow!("No such blob: {}", path))
+    }
+}
+
+/// Appends to a blob of a `MemStore`
+pub struct MemWriter {
+    blob: MemBlob,
+}
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.blob.write().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BlobWriter for MemWriter {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.blob.read().unwrap().len() as u64)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.blob.write().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            blob: self.blob.clone(),
+        })
+    }
+}
+
+/// Reads a blob of a `MemStore`, sees bytes appended after it was opened
+pub struct MemReader {
+    blob: MemBlob,
+    pos: usize,
+}
+
+impl Read for MemReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.blob.read().unwrap();
+        // truncated below the reader
+        if self.pos >= data.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(data.len() - self.pos);
+        buf[..n].copy_from_slice(&data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Store for MemStore {
+    type Reader = MemReader;
+    type Writer = MemWriter;
+
+    fn open_file_for_read(&self, path: &str) -> Result<MemReader> {
+        Ok(MemReader {
+            blob: self.blob(path)?,
+            pos: 0,
+        })
+    }
+
+    fn open_file_for_append(&self, path: &str) -> Result<MemWriter> {
+        let blob = self
+            .blobs
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .clone();
+        Ok(MemWriter { blob })
+    }
+
+    fn open_file_for_write(&self, path: &str) -> Result<MemWriter> {
+        let writer = self.open_file_for_append(path)?;
+        writer.blob.write().unwrap().clear();
+        Ok(writer)
+    }
+
+    fn root(&self) -> Option<&PathBuf> {
+        None
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self.blobs.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        let blob = blobs
+            .remove(from)
+            .ok_or_else(|| anyhow!("No such blob: {}", from))?;
+        blobs.insert(to.to_owned(), blob);
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("No such blob: {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_store_test() {
+        let store = MemStore::new();
+        let mut w = store.open_file_for_append("a").unwrap();
+        w.write_all(b"Hello").unwrap();
+
+        let mut r = store.open_file_for_read("a").unwrap();
+        w.write_all(b" world").unwrap();
+        let mut buf = String::new();
+        r.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "Hello world");
+
+        w.truncate(5).unwrap();
+        assert_eq!(w.size().unwrap(), 5);
+        assert_eq!(r.read(&mut [0; 8]).unwrap(), 0);
+
+        store.rename("a", "b").unwrap();
+        assert_eq!(store.list().unwrap(), vec!["b".to_owned()]);
+        assert!(store.open_file_for_read("a").is_err());
+
+        let w = store.open_file_for_write("b").unwrap();
+        assert_eq!(w.size().unwrap(), 0);
+        store.remove("b").unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+}